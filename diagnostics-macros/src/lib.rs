@@ -454,3 +454,232 @@ fn is_source_span(ty: &syn::Type) -> bool {
         _ => false,
     }
 }
+
+/// Derives `ToDiagnostic` for a struct or enum.
+///
+/// The struct, or each variant of the enum, must have a `#[diag(severity, "message")]`
+/// attribute, where `severity` is one of `bug`, `error`, `warning`, `note`, or `help`.
+/// Fields which implement `Spanned` may be given a `#[label(style)]` or
+/// `#[label(style, "message")]` attribute, where `style` is `primary` or `secondary`.
+///
+/// Messages are format strings, in which fields can be referenced by name, or as `_0`,
+/// `_1`, etc. for unnamed fields, e.g. `#[diag(error, "undefined variable {name}")]`.
+#[proc_macro_derive(ToDiagnostic, attributes(diag, label))]
+pub fn derive_to_diagnostic(input: TokenStream) -> TokenStream {
+    // Parse into syntax tree
+    let derive = parse_macro_input!(input as DeriveInput);
+    // Structure name
+    let name = derive.ident;
+    let result = match derive.data {
+        Data::Struct(data) => {
+            derive_to_diagnostic_struct(name, derive.attrs, data, derive.generics)
+        }
+        Data::Enum(data) => derive_to_diagnostic_enum(name, data, derive.generics),
+        Data::Union(_) => Err(Error::new(
+            name.span(),
+            "deriving ToDiagnostic on unions is not currently supported",
+        )),
+    };
+    match result {
+        Ok(ts) => ts,
+        Err(err) => err.into_compile_error().into(),
+    }
+}
+
+fn derive_to_diagnostic_struct(
+    name: Ident,
+    attrs: Vec<Attribute>,
+    data: syn::DataStruct,
+    generics: syn::Generics,
+) -> Result<TokenStream, Error> {
+    let pattern = make_fields_pattern(make_path(&[Ident::new("Self", name.span())]), &data.fields);
+    let body = make_diagnostic_expr(name.span(), &attrs, &data.fields)?;
+
+    let (impl_gen, ty_gen, where_clause) = generics.split_for_impl();
+    let quoted = quote! {
+        impl #impl_gen ::miden_diagnostics::ToDiagnostic for #name #ty_gen #where_clause {
+            #[allow(unused_variables)]
+            fn to_diagnostic(self) -> ::miden_diagnostics::Diagnostic {
+                let #pattern = self;
+                #body
+            }
+        }
+    };
+
+    Ok(TokenStream::from(quoted))
+}
+
+fn derive_to_diagnostic_enum(
+    name: Ident,
+    data: syn::DataEnum,
+    generics: syn::Generics,
+) -> Result<TokenStream, Error> {
+    let mut arms = Vec::with_capacity(data.variants.len());
+    for variant in data.variants.iter() {
+        let span = variant.span();
+        let path = make_path(&[Ident::new("Self", span), variant.ident.clone()]);
+        let pattern = make_fields_pattern(path, &variant.fields);
+        let body = make_diagnostic_expr(span, &variant.attrs, &variant.fields)?;
+        arms.push(quote! {
+            #pattern => { #body }
+        });
+    }
+
+    let (impl_gen, ty_gen, where_clause) = generics.split_for_impl();
+    let quoted = quote! {
+        impl #impl_gen ::miden_diagnostics::ToDiagnostic for #name #ty_gen #where_clause {
+            #[allow(unused_variables)]
+            fn to_diagnostic(self) -> ::miden_diagnostics::Diagnostic {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+    };
+
+    Ok(TokenStream::from(quoted))
+}
+
+/// Generates a pattern which binds every field of a struct or variant by name.
+///
+/// Unnamed fields are bound as `_0`, `_1`, etc.
+fn make_fields_pattern(path: Path, fields: &syn::Fields) -> proc_macro2::TokenStream {
+    let bindings = field_bindings(fields);
+    match fields {
+        syn::Fields::Named(_) => quote! { #path { #(#bindings),* } },
+        syn::Fields::Unnamed(_) => quote! { #path ( #(#bindings),* ) },
+        syn::Fields::Unit => quote! { #path },
+    }
+}
+
+fn field_bindings(fields: &syn::Fields) -> Vec<Ident> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, f)| match f.ident.as_ref() {
+            Some(ident) => ident.clone(),
+            None => Ident::new(&format!("_{}", i), f.span()),
+        })
+        .collect()
+}
+
+/// Generates an expression which constructs the `Diagnostic` for a struct or variant, from
+/// its `#[diag]` attribute, and the `#[label]` attributes on its fields.
+fn make_diagnostic_expr(
+    span: Span,
+    attrs: &[Attribute],
+    fields: &syn::Fields,
+) -> Result<proc_macro2::TokenStream, Error> {
+    let mut diags = attrs.iter().filter(|attr| attr.path.is_ident("diag"));
+    let diag = match (diags.next(), diags.next()) {
+        (Some(diag), None) => diag.parse_args::<DiagAttr>()?,
+        (None, _) => {
+            return Err(Error::new(
+                span,
+                "ToDiagnostic requires a #[diag(severity, \"message\")] attribute",
+            ))
+        }
+        (Some(_), Some(extra)) => {
+            return Err(Error::new(
+                extra.span(),
+                "ToDiagnostic requires one #[diag] attribute, but multiple were found",
+            ))
+        }
+    };
+    let severity = diag.severity;
+    let message = diag.message;
+
+    let mut labels = vec![];
+    for (field, binding) in fields.iter().zip(field_bindings(fields)) {
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path.is_ident("label"))
+        {
+            let label = attr.parse_args::<LabelAttr>()?;
+            let style = label.style;
+            let message = match label.message {
+                Some(message) => quote! { format!(#message) },
+                None => quote! { String::new() },
+            };
+            labels.push(quote! {
+                {
+                    let span = ::miden_diagnostics::Spanned::span(&#binding);
+                    ::miden_diagnostics::Label::new(
+                        ::miden_diagnostics::LabelStyle::#style,
+                        span.source_id(),
+                        span,
+                    )
+                    .with_message(#message)
+                }
+            });
+        }
+    }
+
+    Ok(quote! {
+        ::miden_diagnostics::Diagnostic::new(::miden_diagnostics::Severity::#severity)
+            .with_message(format!(#message))
+            .with_labels(vec![#(#labels),*])
+    })
+}
+
+/// Represents `#[diag(severity, "message")]`
+struct DiagAttr {
+    severity: Ident,
+    message: syn::LitStr,
+}
+impl syn::parse::Parse for DiagAttr {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let ident = input.parse::<Ident>()?;
+        let severity = match ident.to_string().as_str() {
+            "bug" => "Bug",
+            "error" => "Error",
+            "warning" => "Warning",
+            "note" => "Note",
+            "help" => "Help",
+            _ => {
+                return Err(Error::new(
+                    ident.span(),
+                    "expected one of: bug, error, warning, note, help",
+                ))
+            }
+        };
+        input.parse::<Token![,]>()?;
+        let message = input.parse()?;
+        Ok(Self {
+            severity: Ident::new(severity, ident.span()),
+            message,
+        })
+    }
+}
+
+/// Represents `#[label(style)]` or `#[label(style, "message")]`
+struct LabelAttr {
+    style: Ident,
+    message: Option<syn::LitStr>,
+}
+impl syn::parse::Parse for LabelAttr {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let ident = input.parse::<Ident>()?;
+        let style = match ident.to_string().as_str() {
+            "primary" => "Primary",
+            "secondary" => "Secondary",
+            _ => {
+                return Err(Error::new(
+                    ident.span(),
+                    "expected one of: primary, secondary",
+                ))
+            }
+        };
+        let message = if input.is_empty() {
+            None
+        } else {
+            input.parse::<Token![,]>()?;
+            Some(input.parse()?)
+        };
+        Ok(Self {
+            style: Ident::new(style, ident.span()),
+            message,
+        })
+    }
+}
@@ -0,0 +1,43 @@
+//! Compares sequential lookups of locations in a [SourceFile], which are answered from the
+//! cached last line, with lookups of the same offsets in a scattered order, which are not.
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use miden_diagnostics::{ByteIndex, CodeMap};
+
+const LINES: usize = 10_000;
+
+fn location(c: &mut Criterion) {
+    let codemap = CodeMap::new();
+    let source = (0..LINES)
+        .map(|i| format!("    push.{}\n", i))
+        .collect::<String>();
+    let id = codemap.add("bench.masm", source);
+    let file = codemap.get(id).unwrap();
+
+    let len = file.source().len() as u32;
+    let sequential = (0..len).step_by(3).map(ByteIndex).collect::<Vec<_>>();
+    // Visits the same offsets, but jumps between distant lines on each lookup
+    let scattered = (0..sequential.len())
+        .map(|i| sequential[i * 7_919 % sequential.len()])
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("location");
+    group.throughput(Throughput::Elements(sequential.len() as u64));
+    group.bench_function("sequential", |b| {
+        b.iter(|| {
+            for index in sequential.iter() {
+                black_box(file.location(*index).unwrap());
+            }
+        })
+    });
+    group.bench_function("scattered", |b| {
+        b.iter(|| {
+            for index in scattered.iter() {
+                black_box(file.location(*index).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, location);
+criterion_main!(benches);
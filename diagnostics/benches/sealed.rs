@@ -0,0 +1,42 @@
+//! Compares [CodeMap::get], which acquires a `flurry` guard for each lookup, with
+//! [SealedCodeMap::get], which indexes an immutable `Vec` without one.
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use miden_diagnostics::{CodeMap, SourceId};
+
+const FILES: usize = 1_000;
+
+fn codemap() -> (CodeMap, Vec<SourceId>) {
+    let codemap = CodeMap::new();
+    let ids = (0..FILES)
+        .map(|i| codemap.add(format!("{}.masm", i), format!("proc.p{}\nend\n", i)))
+        .collect();
+    (codemap, ids)
+}
+
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+
+    let (codemap, ids) = codemap();
+    group.bench_function("CodeMap", |b| {
+        b.iter(|| {
+            for id in ids.iter() {
+                black_box(codemap.get(black_box(*id)).unwrap());
+            }
+        })
+    });
+
+    let (codemap, ids) = self::codemap();
+    let sealed = codemap.seal();
+    group.bench_function("SealedCodeMap", |b| {
+        b.iter(|| {
+            for id in ids.iter() {
+                black_box(sealed.get(black_box(*id)).unwrap());
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, get);
+criterion_main!(benches);
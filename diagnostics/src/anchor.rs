@@ -0,0 +1,137 @@
+use crate::*;
+
+/// An [Anchor] tracks the location of a [SourceSpan] across edits to its source file,
+/// e.g. so that diagnostics produced for an earlier version of a file in an editor can
+/// still be placed correctly after the user has made changes to it.
+///
+/// Use [CodeMap::anchor] to construct one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Anchor {
+    span: SourceSpan,
+}
+
+/// Describes an edit to a source file, in which the content of `removed` is replaced with
+/// `inserted_len` bytes of new content.
+///
+/// A pure insertion is represented with an empty `removed` span at the insertion point.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SourceEdit {
+    pub removed: SourceSpan,
+    pub inserted_len: usize,
+}
+
+impl Anchor {
+    /// Returns the span this anchor currently refers to
+    pub fn span(&self) -> SourceSpan {
+        self.span
+    }
+
+    /// Returns the span this anchor refers to after applying `edit`
+    ///
+    /// * If the edit is in a different file, or entirely after the span, the span is unchanged
+    /// * If the edit is entirely before the span, the span is shifted accordingly
+    /// * If the edit overlaps the span, the span is invalidated, and `None` is returned
+    ///
+    /// An insertion exactly at the start of the span is considered to be before it, and one
+    /// exactly at the end is considered to be after it.
+    pub fn resolve_after_edit(&self, edit: &SourceEdit) -> Option<SourceSpan> {
+        let span = self.span;
+        let removed = edit.removed;
+        if removed.source_id() != span.source_id() {
+            return Some(span);
+        }
+        if removed.end_index() <= span.start_index() {
+            let removed_len = removed.end_index().to_usize() - removed.start_index().to_usize();
+            let delta = ByteOffset(edit.inserted_len as i64 - removed_len as i64);
+            return Some(SourceSpan::new(
+                SourceIndex::new(span.source_id(), span.start_index() + delta),
+                SourceIndex::new(span.source_id(), span.end_index() + delta),
+            ));
+        }
+        if removed.start_index() >= span.end_index() {
+            return Some(span);
+        }
+        None
+    }
+
+    /// Applies `edit` to this anchor in place, see `resolve_after_edit`
+    ///
+    /// Returns false if the anchor was invalidated by the edit, in which case it is unchanged.
+    pub fn apply_edit(&mut self, edit: &SourceEdit) -> bool {
+        match self.resolve_after_edit(edit) {
+            Some(span) => {
+                self.span = span;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl CodeMap {
+    /// Returns an [Anchor] for `span`, which can be used to track its location across edits
+    /// to the underlying source file.
+    pub fn anchor(&self, span: SourceSpan) -> Anchor {
+        Anchor { span }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(id: SourceId, start: u32, end: u32) -> SourceSpan {
+        SourceSpan::new(
+            SourceIndex::new(id, ByteIndex(start)),
+            SourceIndex::new(id, ByteIndex(end)),
+        )
+    }
+
+    fn edit(id: SourceId, start: u32, end: u32, inserted_len: usize) -> SourceEdit {
+        SourceEdit {
+            removed: span(id, start, end),
+            inserted_len,
+        }
+    }
+
+    #[test]
+    fn resolve_after_edit() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("a", "let x = foo;\n".to_string());
+        let other = codemap.add("b", "".to_string());
+        let anchor = codemap.anchor(span(id, 8, 11));
+
+        // Insertions before, or at the start of the span, shift it
+        assert_eq!(
+            anchor.resolve_after_edit(&edit(id, 0, 0, 4)),
+            Some(span(id, 12, 15))
+        );
+        assert_eq!(
+            anchor.resolve_after_edit(&edit(id, 8, 8, 1)),
+            Some(span(id, 9, 12))
+        );
+        // Removals before the span shift it back
+        assert_eq!(
+            anchor.resolve_after_edit(&edit(id, 0, 4, 0)),
+            Some(span(id, 4, 7))
+        );
+        // Edits after the span, or in another file, leave it unchanged
+        assert_eq!(
+            anchor.resolve_after_edit(&edit(id, 11, 11, 2)),
+            Some(span(id, 8, 11))
+        );
+        assert_eq!(
+            anchor.resolve_after_edit(&edit(other, 0, 0, 2)),
+            Some(span(id, 8, 11))
+        );
+        // Overlapping edits invalidate it
+        assert_eq!(anchor.resolve_after_edit(&edit(id, 9, 9, 1)), None);
+        assert_eq!(anchor.resolve_after_edit(&edit(id, 6, 9, 1)), None);
+
+        let mut anchor = anchor;
+        assert!(anchor.apply_edit(&edit(id, 0, 0, 4)));
+        assert_eq!(anchor.span(), span(id, 12, 15));
+        assert!(!anchor.apply_edit(&edit(id, 13, 14, 0)));
+        assert_eq!(anchor.span(), span(id, 12, 15));
+    }
+}
@@ -0,0 +1,180 @@
+use core::fmt::Write;
+
+use crate::codemap::expand_tabs;
+use crate::*;
+
+/// Renders the full content of `file_id`, with the labels of `diagnostics` which refer to it
+/// overlaid in place, as a plain string without color, e.g. for a code review view:
+///
+/// ```text
+/// 1 │   let x = foo(1);
+///   │           ^^^ error: unknown function `foo`
+/// 2 │ │ let y = {
+/// 3 │ │     x
+/// 4 │ │ };
+///   │ ╰ ^^ warning: unused variable `y`
+/// ```
+///
+/// Each label on a line is underlined on a row of its own beneath it, ordered by column, so
+/// that overlapping labels remain legible. Labels spanning multiple lines are marked in the
+/// margin, and underlined from the start of their last line. Each label is followed by the
+/// severity of its diagnostic, as given by `labels`, and its message, or the message of the
+/// diagnostic if it has none. Labels in other files are ignored.
+///
+/// Returns `Err` if `file_id` is not in `codemap`.
+pub fn render_annotated_file(
+    codemap: &CodeMap,
+    file_id: SourceId,
+    diagnostics: &[Diagnostic],
+    config: &crate::term::Config,
+    labels: &SeverityLabels,
+) -> Result<String, Error> {
+    let file = codemap.get(file_id)?;
+    let file = file.as_ref();
+    let mut annotations = diagnostics
+        .iter()
+        .flat_map(|diagnostic| {
+            diagnostic
+                .labels
+                .iter()
+                .filter(|label| label.file_id == file_id)
+                .map(move |label| {
+                    let message = if label.message.is_empty() {
+                        &diagnostic.message
+                    } else {
+                        &label.message
+                    };
+                    let start = label.range.start.min(file.source().len());
+                    let end = label.range.end.clamp(start, file.source().len());
+                    Annotation {
+                        start_line: file.line_index(ByteIndex(start as u32)).to_usize(),
+                        end_line: file.line_index(ByteIndex(end as u32)).to_usize(),
+                        start,
+                        end,
+                        caret: match label.style {
+                            LabelStyle::Primary => config.chars.single_primary_caret,
+                            LabelStyle::Secondary => config.chars.single_secondary_caret,
+                        },
+                        message: format!("{}: {}", labels.get(diagnostic.severity), message),
+                    }
+                })
+        })
+        .collect::<Vec<_>>();
+    annotations.sort_by_key(|annotation| annotation.start);
+
+    let chars = &config.chars;
+    let width = file.line_count().to_string().len();
+    let mut out = String::new();
+    for (line_index, line_span, line) in file.lines() {
+        let line_index = line_index.to_usize();
+        let line_start = line_span.start_index().to_usize();
+        let multiline = |a: &&Annotation| a.start_line != a.end_line;
+        let in_multiline = annotations
+            .iter()
+            .filter(multiline)
+            .any(|a| a.start_line <= line_index && line_index <= a.end_line);
+        let margin = if in_multiline { chars.multi_left } else { ' ' };
+        let (expanded, columns) = expand_tabs(line, config.tab_width);
+        writeln!(
+            out,
+            "{:>width$} {} {} {}",
+            line_index + 1,
+            chars.source_border_left,
+            margin,
+            expanded,
+            width = width
+        )
+        .unwrap();
+
+        for annotation in annotations.iter().filter(|a| a.end_line == line_index) {
+            let (margin, start) = if multiline(&annotation) {
+                (chars.multi_bottom_left, 0)
+            } else {
+                (
+                    margin,
+                    columns[(annotation.start - line_start).min(line.len())],
+                )
+            };
+            let end = columns[(annotation.end - line_start).min(line.len())];
+            writeln!(
+                out,
+                "{:width$} {} {}{:indent$}{} {}",
+                "",
+                chars.source_border_left,
+                margin,
+                "",
+                annotation
+                    .caret
+                    .to_string()
+                    .repeat(end.saturating_sub(start).max(1)),
+                annotation.message,
+                width = width,
+                indent = start + 1,
+            )
+            .unwrap();
+        }
+    }
+    Ok(out)
+}
+
+struct Annotation {
+    start_line: usize,
+    end_line: usize,
+    start: usize,
+    end: usize,
+    caret: char,
+    message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(id: SourceId, start: u32, end: u32) -> SourceSpan {
+        SourceSpan::new(
+            SourceIndex::new(id, ByteIndex(start)),
+            SourceIndex::new(id, ByteIndex(end)),
+        )
+    }
+
+    #[test]
+    fn renders_whole_file_with_labels() {
+        let codemap = CodeMap::new();
+        let id = codemap.add(
+            "test.masm",
+            "begin\n  push.1\n  exec.foo\n  drop\nend\n".to_string(),
+        );
+        let other = codemap.add("other.masm", "drop\n".to_string());
+        let diagnostics = vec![
+            Diagnostic::error()
+                .with_message("unknown procedure `foo`")
+                .with_labels(vec![Label::primary(id, span(id, 22, 25))]),
+            Diagnostic::warning()
+                .with_message("unused value")
+                .with_labels(vec![
+                    Label::primary(id, span(id, 8, 14)).with_message("pushed here"),
+                    Label::secondary(other, span(other, 0, 4)),
+                ]),
+        ];
+        let rendered = render_annotated_file(
+            &codemap,
+            id,
+            &diagnostics,
+            &crate::term::Config::default(),
+            &SeverityLabels::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            rendered,
+            "1 │   begin\n2 │     push.1\n  │     ^^^^^^ warning: pushed here\n3 │     exec.foo\n  │          ^^^ error: unknown procedure `foo`\n4 │     drop\n5 │   end\n"
+        );
+        assert!(render_annotated_file(
+            &codemap,
+            SourceId::new(42),
+            &diagnostics,
+            &crate::term::Config::default(),
+            &SeverityLabels::default(),
+        )
+        .is_err());
+    }
+}
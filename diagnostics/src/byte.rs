@@ -0,0 +1,212 @@
+//! The byte index types used by [crate::SourceSpan] and [crate::SourceIndex].
+//!
+//! These mirror the types of the same name in `codespan`, which is `std`-only, so that they
+//! are the same whether or not the `std` feature is enabled. With `std` enabled, they convert
+//! to and from the `codespan` types, and implement its [codespan::Index] and [codespan::Offset]
+//! traits, so that spans interoperate with `codespan`.
+use core::fmt;
+use core::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+
+/// The raw, untyped index
+pub type RawIndex = u32;
+
+/// The raw, untyped offset
+pub type RawOffset = i64;
+
+/// A byte position in a source file
+#[derive(Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteIndex(pub RawIndex);
+impl ByteIndex {
+    /// Convert the position into a `usize`, for use in array indexing
+    #[inline]
+    pub const fn to_usize(self) -> usize {
+        self.0 as usize
+    }
+}
+impl fmt::Debug for ByteIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ByteIndex({})", self.0)
+    }
+}
+impl fmt::Display for ByteIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+impl From<RawIndex> for ByteIndex {
+    #[inline]
+    fn from(index: RawIndex) -> Self {
+        Self(index)
+    }
+}
+impl From<ByteIndex> for RawIndex {
+    #[inline]
+    fn from(index: ByteIndex) -> RawIndex {
+        index.0
+    }
+}
+impl From<ByteIndex> for usize {
+    #[inline]
+    fn from(index: ByteIndex) -> usize {
+        index.to_usize()
+    }
+}
+impl Add<ByteOffset> for ByteIndex {
+    type Output = ByteIndex;
+
+    #[inline]
+    fn add(self, rhs: ByteOffset) -> ByteIndex {
+        ByteIndex((self.0 as RawOffset + rhs.0) as RawIndex)
+    }
+}
+impl AddAssign<ByteOffset> for ByteIndex {
+    #[inline]
+    fn add_assign(&mut self, rhs: ByteOffset) {
+        *self = *self + rhs;
+    }
+}
+impl Sub<ByteOffset> for ByteIndex {
+    type Output = ByteIndex;
+
+    #[inline]
+    fn sub(self, rhs: ByteOffset) -> ByteIndex {
+        ByteIndex((self.0 as RawOffset - rhs.0) as RawIndex)
+    }
+}
+impl SubAssign<ByteOffset> for ByteIndex {
+    #[inline]
+    fn sub_assign(&mut self, rhs: ByteOffset) {
+        *self = *self - rhs;
+    }
+}
+impl Sub for ByteIndex {
+    type Output = ByteOffset;
+
+    #[inline]
+    fn sub(self, rhs: ByteIndex) -> ByteOffset {
+        ByteOffset(self.0 as RawOffset - rhs.0 as RawOffset)
+    }
+}
+
+/// A byte offset in a source file
+#[derive(Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteOffset(pub RawOffset);
+impl ByteOffset {
+    /// Create a byte offset from the UTF-8 length of a character
+    #[inline]
+    pub fn from_char_len(ch: char) -> ByteOffset {
+        ByteOffset(ch.len_utf8() as RawOffset)
+    }
+
+    /// Create a byte offset from the UTF-8 length of a string
+    #[inline]
+    pub fn from_str_len(value: &str) -> ByteOffset {
+        ByteOffset(value.len() as RawOffset)
+    }
+
+    /// Convert the offset into a `usize`, for use in array indexing
+    #[inline]
+    pub const fn to_usize(self) -> usize {
+        self.0 as usize
+    }
+}
+impl fmt::Debug for ByteOffset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ByteOffset({})", self.0)
+    }
+}
+impl fmt::Display for ByteOffset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+impl From<RawOffset> for ByteOffset {
+    #[inline]
+    fn from(offset: RawOffset) -> Self {
+        Self(offset)
+    }
+}
+impl From<ByteOffset> for RawOffset {
+    #[inline]
+    fn from(offset: ByteOffset) -> RawOffset {
+        offset.0
+    }
+}
+impl From<ByteOffset> for usize {
+    #[inline]
+    fn from(offset: ByteOffset) -> usize {
+        offset.to_usize()
+    }
+}
+impl Neg for ByteOffset {
+    type Output = ByteOffset;
+
+    #[inline]
+    fn neg(self) -> ByteOffset {
+        ByteOffset(-self.0)
+    }
+}
+impl Add for ByteOffset {
+    type Output = ByteOffset;
+
+    #[inline]
+    fn add(self, rhs: ByteOffset) -> ByteOffset {
+        ByteOffset(self.0 + rhs.0)
+    }
+}
+impl AddAssign for ByteOffset {
+    #[inline]
+    fn add_assign(&mut self, rhs: ByteOffset) {
+        self.0 += rhs.0;
+    }
+}
+impl Sub for ByteOffset {
+    type Output = ByteOffset;
+
+    #[inline]
+    fn sub(self, rhs: ByteOffset) -> ByteOffset {
+        ByteOffset(self.0 - rhs.0)
+    }
+}
+impl SubAssign for ByteOffset {
+    #[inline]
+    fn sub_assign(&mut self, rhs: ByteOffset) {
+        self.0 -= rhs.0;
+    }
+}
+
+#[cfg(feature = "std")]
+mod codespan_interop {
+    use super::*;
+
+    impl From<codespan::ByteIndex> for ByteIndex {
+        #[inline]
+        fn from(index: codespan::ByteIndex) -> Self {
+            Self(index.0)
+        }
+    }
+    impl From<ByteIndex> for codespan::ByteIndex {
+        #[inline]
+        fn from(index: ByteIndex) -> Self {
+            Self(index.0)
+        }
+    }
+    impl From<codespan::ByteOffset> for ByteOffset {
+        #[inline]
+        fn from(offset: codespan::ByteOffset) -> Self {
+            Self(offset.0)
+        }
+    }
+    impl From<ByteOffset> for codespan::ByteOffset {
+        #[inline]
+        fn from(offset: ByteOffset) -> Self {
+            Self(offset.0)
+        }
+    }
+    impl codespan::Index for ByteIndex {
+        type Offset = ByteOffset;
+    }
+    impl codespan::Offset for ByteOffset {
+        const ZERO: ByteOffset = ByteOffset(0);
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::mpsc::Sender;
+
+use parking_lot::Mutex;
+
+use crate::term::termcolor::Buffer;
+use crate::*;
+
+/// An [EmittedDiagnostic] is a self-contained record of a diagnostic emitted via a
+/// [ChannelEmitter], with the locations of its labels resolved, so that it can be
+/// presented without access to the [CodeMap].
+#[derive(Debug, Clone)]
+pub struct EmittedDiagnostic {
+    /// The diagnostic which was emitted
+    pub diagnostic: Diagnostic,
+    /// The child diagnostics attached to `diagnostic`, each with its own children, if any
+    pub children: Vec<DiagnosticTree>,
+    /// The suggested fixes attached to `diagnostic`
+    pub suggestions: Vec<Suggestion>,
+    /// The resolved location of each label of `diagnostic`, in the same order as the labels,
+    /// or `None` if the label refers to a file which is not in the [CodeMap].
+    pub locations: Vec<Option<LabelLocation>>,
+}
+
+/// The resolved location of a label, see [EmittedDiagnostic]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelLocation {
+    /// The name of the file to which the label refers
+    pub file: FileName,
+    /// The location at which the label starts
+    pub start: Location,
+    /// The location at which the label ends
+    pub end: Location,
+}
+
+/// [ChannelEmitter] sends each emitted diagnostic, in structured form, over a channel.
+///
+/// This is intended for architectures where diagnostics are produced on one thread, e.g. a
+/// compiler worker, and presented on another, e.g. a UI thread, which receives them live.
+///
+/// Output which is not a diagnostic, e.g. from [DiagnosticsHandler::info], is discarded.
+/// If the receiving end of the channel has been dropped, diagnostics are discarded as well.
+pub struct ChannelEmitter {
+    sender: Mutex<Sender<EmittedDiagnostic>>,
+}
+impl ChannelEmitter {
+    /// Construct a new [ChannelEmitter] which sends diagnostics via `sender`
+    pub fn new(sender: Sender<EmittedDiagnostic>) -> Self {
+        Self {
+            sender: Mutex::new(sender),
+        }
+    }
+}
+impl Emitter for ChannelEmitter {
+    #[inline]
+    fn buffer(&self) -> Buffer {
+        Buffer::no_color()
+    }
+
+    #[inline]
+    fn print(&self, _buffer: Buffer) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn emit_structured(
+        &self,
+        codemap: &CodeMap,
+        record: &DiagnosticRecord<'_>,
+    ) -> std::io::Result<bool> {
+        let locations = record
+            .diagnostic
+            .labels
+            .iter()
+            .map(|label| {
+                let file = codemap.get(label.file_id).ok()?;
+                Some(LabelLocation {
+                    file: file.name().clone(),
+                    start: file.location(ByteIndex(label.range.start as u32)).ok()?,
+                    end: file.location(ByteIndex(label.range.end as u32)).ok()?,
+                })
+            })
+            .collect();
+        let emitted = EmittedDiagnostic {
+            diagnostic: record.diagnostic.clone(),
+            children: record.children.to_vec(),
+            suggestions: record.suggestions.to_vec(),
+            locations,
+        };
+        self.sender.lock().send(emitted).ok();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc};
+
+    use super::*;
+
+    #[test]
+    fn diagnostics_are_received_over_channel() {
+        let (sender, receiver) = mpsc::channel();
+        let codemap = Arc::new(CodeMap::new());
+        let id = codemap.add("test.masm", "push.1\nadd.x\n".to_string());
+        let emitter = Arc::new(ChannelEmitter::new(sender));
+        let handler = DiagnosticsHandler::new(DiagnosticsConfig::default(), codemap, emitter);
+
+        std::thread::spawn(move || {
+            handler
+                .diagnostic(Severity::Error)
+                .with_message("invalid immediate")
+                .with_primary_label(
+                    SourceSpan::new(
+                        SourceIndex::new(id, ByteIndex(11)),
+                        SourceIndex::new(id, ByteIndex(12)),
+                    ),
+                    "expected a number",
+                )
+                .emit();
+            handler.info("not a diagnostic");
+        })
+        .join()
+        .unwrap();
+
+        let emitted = receiver.recv().unwrap();
+        assert_eq!(emitted.diagnostic.severity, Severity::Error);
+        assert_eq!(emitted.diagnostic.message, "invalid immediate");
+        assert_eq!(emitted.diagnostic.labels[0].message, "expected a number");
+        assert_eq!(
+            emitted.locations,
+            vec![Some(LabelLocation {
+                file: FileName::from("test.masm"),
+                start: Location::new(1, 4),
+                end: Location::new(1, 5),
+            })]
+        );
+        assert!(receiver.try_recv().is_err());
+    }
+}
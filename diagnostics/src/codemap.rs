@@ -14,8 +14,9 @@ type HashMap<K, V> = flurry::HashMap<K, V, core::hash::BuildHasherDefault<FxHash
 ///
 /// The [CodeMap] maintains a set of [SourceFile] entries corresponding to the sources
 /// added to it, with various auxiliary structures for tracking the [FileName] under which
-/// each source was added, the [SourceId] assigned to it, and which files on disk have
-/// been read into memory and added to it.
+/// each source was added, the [SourceId] assigned to it, the logical key (if any) under
+/// which it was registered, and which files on disk have been read into memory and added
+/// to it.
 ///
 /// The [CodeMap] is designed to de-duplicate files and avoid reading from disk multiple
 /// times for the same [Path]. It is also designed to live for the entire lifetime of the
@@ -30,8 +31,10 @@ type HashMap<K, V> = flurry::HashMap<K, V, core::hash::BuildHasherDefault<FxHash
 pub struct CodeMap {
     files: HashMap<SourceId, Arc<SourceFile>>,
     names: HashMap<FileName, SourceId>,
+    keys: HashMap<String, SourceId>,
     seen: HashMap<PathBuf, SourceId>,
     next_file_id: AtomicU32,
+    deterministic: bool,
 }
 impl CodeMap {
     /// Creates an empty `CodeMap`.
@@ -39,8 +42,28 @@ impl CodeMap {
         Self {
             files: HashMap::default(),
             names: HashMap::default(),
+            keys: HashMap::default(),
             seen: HashMap::default(),
             next_file_id: AtomicU32::new(1),
+            deterministic: false,
+        }
+    }
+
+    /// Creates an empty `CodeMap` which assigns [SourceId]s deterministically.
+    ///
+    /// By default, ids are assigned in the order files are added, so when files are added
+    /// concurrently, the same file may be assigned different ids across runs. In this mode,
+    /// the id of a file is instead derived from a hash of its [FileName], so that it is
+    /// stable across runs regardless of the order in which files are added. This makes it
+    /// possible to cache or serialize spans between runs.
+    ///
+    /// The tradeoff is that distinct names may hash to the same id. When that happens, or
+    /// when the same name is added more than once, the next free id is used instead, which
+    /// means that the affected files are again dependent on the order in which they were added.
+    pub fn with_deterministic_ids() -> Self {
+        Self {
+            deterministic: true,
+            ..Self::new()
         }
     }
 
@@ -81,6 +104,9 @@ impl CodeMap {
 
     /// Adds a file to the map from the given `path`, if not already present.
     ///
+    /// The modification time of the file is recorded on a best-effort basis, see
+    /// [SourceFile::modified].
+    ///
     /// Returns `Ok` if successfully added, or `Err` if an error occurred
     /// while reading the file from disk.
     pub fn add_file<P: AsRef<Path>>(&self, path: P) -> std::io::Result<SourceId> {
@@ -90,8 +116,15 @@ impl CodeMap {
         match self.seen.get(path, &guard) {
             Some(id) => Ok(*id),
             None => {
+                // The modification time is read first, so that a change made while the file is
+                // being read results in a later modification time than the one recorded
+                let modified = std::fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok();
                 let source = std::fs::read_to_string(path)?;
-                let source_id = self.insert_file(name, source, None);
+                let mut file = SourceFile::new(SourceId::UNKNOWN, name, source, None);
+                file.set_modified(modified);
+                let source_id = self.insert_source_file(file);
                 match self.seen.try_insert(path.to_path_buf(), source_id, &guard) {
                     Ok(id) => Ok(*id),
                     Err(err) => Ok(*err.current),
@@ -100,6 +133,99 @@ impl CodeMap {
         }
     }
 
+    /// Adds the files at each of the given `paths` to the map, like `add_file`, reading them
+    /// from disk in parallel when the `rayon` feature is enabled.
+    ///
+    /// This is intended for loading a large set of files known up front, where reading them
+    /// one at a time would dominate startup. The result for each path is returned in the same
+    /// order as `paths`. A path which occurs more than once is only read once, and each of its
+    /// occurrences gets the same [SourceId]; if it could not be read, each occurrence gets the
+    /// error from its own attempt to read it.
+    pub fn add_files<I, P>(&self, paths: I) -> Vec<std::io::Result<SourceId>>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let paths = paths.into_iter().collect::<Vec<_>>();
+        // Each distinct path is only read once up front, as concurrent reads of the same path
+        // would race to register it, each inserting a copy of the file into the map
+        let mut seen = rustc_hash::FxHashSet::<&Path>::default();
+        let unique = paths
+            .iter()
+            .map(|path| path.as_ref())
+            .filter(|path| seen.insert(*path))
+            .collect::<Vec<_>>();
+
+        #[cfg(feature = "rayon")]
+        let results = {
+            use rayon::prelude::*;
+
+            unique
+                .par_iter()
+                .map(|path| self.add_file(path))
+                .collect::<Vec<_>>()
+        };
+        #[cfg(not(feature = "rayon"))]
+        let results = unique
+            .iter()
+            .map(|path| self.add_file(path))
+            .collect::<Vec<_>>();
+
+        let mut results = unique
+            .into_iter()
+            .zip(results)
+            .collect::<rustc_hash::FxHashMap<_, _>>();
+        // Later occurrences of a path are added again, which returns the id of the file already
+        // read, or, if it could not be read, the error from another attempt
+        paths
+            .iter()
+            .map(|path| {
+                results
+                    .remove(path.as_ref())
+                    .unwrap_or_else(|| self.add_file(path))
+            })
+            .collect()
+    }
+
+    /// Add a file to this [CodeMap] under the logical `key`, returning the [SourceId] assigned to it.
+    ///
+    /// A key is a stable logical identifier for a source, e.g. a module path such as
+    /// `std::collections`, which is independent of its [FileName]. The file can be found
+    /// by key later using `get_file_id_by_key`. If `key` was already registered, it is
+    /// updated to refer to the newly added file.
+    ///
+    /// NOTE: This always results in a new entry in the map.
+    pub fn add_with_key(
+        &self,
+        key: impl Into<String>,
+        name: impl Into<FileName>,
+        source: String,
+    ) -> SourceId {
+        let source_id = self.insert_file(name.into(), source, None);
+        let guard = self.keys.guard();
+        self.keys.insert(key.into(), source_id, &guard);
+        source_id
+    }
+
+    /// Add a file to the map whose content is produced by `loader` when it is first accessed,
+    /// returning the [SourceId] assigned to it.
+    ///
+    /// This is intended for sources which are expensive to produce, e.g. because they must be
+    /// decompressed or fetched remotely, and which may never be needed, e.g. if no diagnostic
+    /// refers to them. The file is loaded by `get`, or by any other method which accesses its
+    /// content. Loading is thread-safe: `loader` is called at most once, and concurrent
+    /// accesses wait for it to complete.
+    ///
+    /// If `loader` fails, the error is returned by the access which triggered it, and the file
+    /// is treated as missing from then on. Unlike `add`, files are not de-duplicated by name.
+    pub fn add_lazy(
+        &self,
+        name: impl Into<FileName>,
+        loader: Box<dyn FnOnce() -> std::io::Result<String> + Send>,
+    ) -> SourceId {
+        self.insert_source_file(SourceFile::lazy(SourceId::UNKNOWN, name.into(), loader))
+    }
+
     /// Add a file to the map with the given [SourceSpan] as a parent.
     ///
     /// This is intended for use cases such as a preprocessor which needs
@@ -119,31 +245,85 @@ impl CodeMap {
         self.insert_file(name.into(), source, Some(parent))
     }
 
+    /// Changes the [FileName] of `file_id` to `new_name`, e.g. when a file has been moved
+    ///
+    /// The [SourceId] of the file is unchanged, so existing spans remain valid, but future
+    /// lookups by name, and diagnostics, use the new name. Any outstanding `Arc<SourceFile>`
+    /// for the file retains the old name.
+    ///
+    /// Returns `Err` if `file_id` is not in this map.
+    pub fn rename_file(
+        &self,
+        file_id: SourceId,
+        new_name: impl Into<FileName>,
+    ) -> Result<(), Error> {
+        let new_name = new_name.into();
+        let file = self.get_unloaded(file_id)?;
+        let old_name = file.name().clone();
+        let mut renamed = SourceFile::clone(&file);
+        renamed.set_name(new_name.clone());
+        let files_guard = self.files.guard();
+        self.files.insert(file_id, Arc::new(renamed), &files_guard);
+
+        let names_guard = self.names.guard();
+        if self.names.get(&old_name, &names_guard) == Some(&file_id) {
+            self.names.remove(&old_name, &names_guard);
+        }
+        let seen_guard = self.seen.guard();
+        if let FileName::Real(ref path) = old_name {
+            if self.seen.get(path, &seen_guard) == Some(&file_id) {
+                self.seen.remove(path, &seen_guard);
+            }
+        }
+        if let FileName::Real(ref path) = new_name {
+            self.seen.insert(path.clone(), file_id, &seen_guard);
+        }
+        self.names.insert(new_name, file_id, &names_guard);
+        Ok(())
+    }
+
     fn insert_file(&self, name: FileName, source: String, parent: Option<SourceSpan>) -> SourceId {
-        let file_id = self.next_file_id();
-        let filename = name.clone();
+        self.insert_source_file(SourceFile::new(SourceId::UNKNOWN, name, source, parent))
+    }
+
+    fn insert_source_file(&self, mut file: SourceFile) -> SourceId {
+        let mut file_id = self.next_file_id(file.name());
+        let filename = file.name().clone();
+        file.set_id(file_id);
+        let mut file = Arc::new(file);
+        let file_guard = self.files.guard();
+        // An id can only be taken already when ids are derived from names, in which case
+        // we probe for the next free id
+        while let Err(err) = self.files.try_insert(file_id, file, &file_guard) {
+            file_id = SourceId::new(file_id.get() % (SourceId::UNKNOWN_SOURCE_ID - 1) + 1);
+            file = err.not_inserted;
+            Arc::get_mut(&mut file).unwrap().set_id(file_id);
+        }
         let name_guard = self.names.guard();
         self.names.insert(filename, file_id, &name_guard);
-        let file_guard = self.files.guard();
-        self.files.insert(
-            file_id,
-            Arc::new(SourceFile::new(file_id, name, source, parent)),
-            &file_guard,
-        );
         file_id
     }
 
     /// Get the [SourceFile] corresponding to the given [SourceId]
+    ///
+    /// If the file was added via `add_lazy`, and has not been loaded yet, it is loaded first.
+    /// Returns `Err` if loading fails.
     pub fn get(&self, file_id: SourceId) -> Result<Arc<SourceFile>, Error> {
+        let file = self.get_unloaded(file_id)?;
+        file.load()?;
+        Ok(file)
+    }
+
+    /// Like `get`, but without loading the file if it was added via `add_lazy`
+    fn get_unloaded(&self, file_id: SourceId) -> Result<Arc<SourceFile>, Error> {
         if file_id == SourceId::UNKNOWN {
-            Err(Error::FileMissing)
-        } else {
-            let guard = self.files.guard();
-            self.files
-                .get(&file_id, &guard)
-                .cloned()
-                .ok_or(Error::FileMissing)
+            return Err(Error::FileMissing);
         }
+        let guard = self.files.guard();
+        self.files
+            .get(&file_id, &guard)
+            .cloned()
+            .ok_or(Error::FileMissing)
     }
 
     /// Get the [SourceFile] corresponding to the given [SourceSpan]
@@ -157,7 +337,16 @@ impl CodeMap {
     ///
     /// Returns `None` if the given [SourceId] has no parent
     pub fn parent(&self, file_id: SourceId) -> Option<SourceSpan> {
-        self.get(file_id).ok().and_then(|f| f.parent())
+        SourceLookup::parent(self, file_id)
+    }
+
+    /// Get the [ExpandedSpan] for `span`, using the parent of its file as the call site
+    ///
+    /// If the file of `span` has no parent, i.e. it was not added with `add_child`, both the
+    /// call and definition sites are `span`.
+    pub fn expansion_of(&self, span: SourceSpan) -> ExpandedSpan {
+        let call_site = self.parent(span.source_id()).unwrap_or(span);
+        ExpandedSpan::new(call_site, span)
     }
 
     /// Get the [SourceId] corresponding to the given [FileName]
@@ -171,6 +360,22 @@ impl CodeMap {
         self.get_file_id(filename).and_then(|id| self.get(id).ok())
     }
 
+    /// Get the [SourceFile] previously loaded from `path`
+    ///
+    /// Unlike `add_file`, this never reads from disk, and returns `None` if no
+    /// file has been loaded from `path`.
+    pub fn get_by_path(&self, path: impl AsRef<Path>) -> Option<Arc<SourceFile>> {
+        let guard = self.seen.guard();
+        let id = self.seen.get(path.as_ref(), &guard).copied()?;
+        self.get(id).ok()
+    }
+
+    /// Get the [SourceId] registered under the logical `key`, see `add_with_key`
+    pub fn get_file_id_by_key(&self, key: &str) -> Option<SourceId> {
+        let guard = self.keys.guard();
+        self.keys.get(key, &guard).copied()
+    }
+
     /// Get the [FileName] corresponding to the given [SourceId]
     ///
     /// Returns `Err` if `file_id` is not in this map.
@@ -179,6 +384,11 @@ impl CodeMap {
         Ok(file.name().clone())
     }
 
+    /// Like `name`, but returns `None` if `file_id` is [SourceId::UNKNOWN] or not in this map
+    pub fn name_opt(&self, file_id: SourceId) -> Option<FileName> {
+        self.name(file_id).ok()
+    }
+
     /// Get the [FileName] associated with the given [SourceSpan]
     ///
     /// Returns `Err` if `span` is [SourceSpan::UNKNOWN].
@@ -197,11 +407,7 @@ impl CodeMap {
         line: impl Into<LineIndex>,
         column: impl Into<ColumnIndex>,
     ) -> Result<SourceSpan, Error> {
-        let f = self.get(file_id)?;
-        let span = f.line_column_to_span(line.into(), column.into())?;
-        let start = SourceIndex::new(file_id, span.start());
-        let end = SourceIndex::new(file_id, span.end());
-        Ok(SourceSpan::new(start, end))
+        SourceLookup::line_column_to_span(self, file_id, line, column)
     }
 
     fn line_span(
@@ -223,10 +429,16 @@ impl CodeMap {
 
     /// Get a [Location] from a [SourceSpan]
     ///
-    /// Returns `Err` if `span` is [SourceSpan::UNKNOWN].
+    /// The line and column are always 0-based, see [crate::DiagnosticsHandler::display_location]
+    /// for the numbers shown to users. Returns `Err` if `span` is [SourceSpan::UNKNOWN].
     pub fn location<S: Spanned>(&self, spanned: &S) -> Result<Location, Error> {
-        let span = spanned.span();
-        self.location_at_index(span.source_id, span.start)
+        SourceLookup::location(self, spanned)
+    }
+
+    /// Like `location`, but returns `None` if `spanned` has an unknown span, or
+    /// if its file is not in this map
+    pub fn location_opt<S: Spanned>(&self, spanned: &S) -> Option<Location> {
+        self.location(spanned).ok()
     }
 
     /// Get a [Location] from a given [SourceId] and byte index.
@@ -235,12 +447,138 @@ impl CodeMap {
         file_id: SourceId,
         byte_index: impl Into<ByteIndex>,
     ) -> Result<Location, Error> {
-        self.get(file_id)?.location(byte_index)
+        SourceLookup::location_at_index(self, file_id, byte_index)
+    }
+
+    /// Resolves each of `spans` to the name of its file, and the locations at which it starts
+    /// and ends, in the same order as `spans`.
+    ///
+    /// Each file is fetched from the map only once, however many of `spans` refer to it, which
+    /// makes this preferable to resolving spans one at a time when rendering many of them,
+    /// e.g. in a custom renderer. A span resolves to `None` if it is unknown, its file is not
+    /// in this map, or it is out of bounds for its file.
+    pub fn resolve_spans(&self, spans: &[SourceSpan]) -> Vec<Option<ResolvedSpan>> {
+        let mut files = rustc_hash::FxHashMap::<SourceId, Option<Arc<SourceFile>>>::default();
+        spans
+            .iter()
+            .map(|span| {
+                let file = files
+                    .entry(span.source_id())
+                    .or_insert_with(|| self.get(span.source_id()).ok())
+                    .as_ref()?;
+                Some(ResolvedSpan {
+                    file: file.name().clone(),
+                    start: file.location(span.start_index()).ok()?,
+                    end: file.location(span.end_index()).ok()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Get the visual column of `index`, i.e. the display width of the text preceding it on
+    /// its line, as it would be rendered in a terminal.
+    ///
+    /// Wide characters, e.g. CJK, count as two columns, and tabs advance to the next multiple
+    /// of `tab_width`, matching how [crate::term] renders source snippets. As a result, this
+    /// may differ from both the byte and character column of `index`.
+    pub fn visual_column(&self, index: SourceIndex, tab_width: usize) -> Result<usize, Error> {
+        let f = self.get(index.source_id())?;
+        let byte_index = index.index();
+        let line_start = f.line_start(f.line_index(byte_index))?;
+        let prefix = f.source_slice(line_start.to_usize()..byte_index.to_usize())?;
+        Ok(prefix
+            .chars()
+            .fold(0, |column, c| column + char_width(c, column, tab_width)))
+    }
+
+    /// Renders a compact preview of `span`, consisting of the source line on which it starts,
+    /// followed by a line of carets underlining it, e.g.:
+    ///
+    /// ```text
+    /// let x = foo(bar);
+    ///         ^^^
+    /// ```
+    ///
+    /// This is a lightweight alternative to rendering a full diagnostic, e.g. for plain logs.
+    /// Tabs are expanded as described in [CodeMap::visual_column], so that the carets line up.
+    /// A span which continues past the end of its first line is underlined to the end of that
+    /// line, and an empty span is shown with a single caret.
+    pub fn caret_preview(&self, span: SourceSpan, tab_width: usize) -> Result<String, Error> {
+        let f = self.get(span.source_id())?;
+        let line_span = f.line_span(f.line_index(span.start_index()))?;
+        let line_start = line_span.start().to_usize();
+        let line = f.source_slice(line_start..line_span.end().to_usize())?;
+        let line = line.trim_end_matches(&['\n', '\r'][..]);
+        // A span may start past the content of its line, e.g. at the end of the file, or at
+        // the line terminator, which is not part of the preview
+        let start = (span.start_index().to_usize() - line_start).min(line.len());
+        let end = (span.end_index().to_usize() - line_start).clamp(start, line.len());
+        let column = |offset: usize| {
+            let index = SourceIndex::new(span.source_id(), ByteIndex((line_start + offset) as u32));
+            self.visual_column(index, tab_width)
+        };
+        let start_column = column(start)?;
+        let end_column = column(end)?;
+
+        let (mut preview, _) = expand_tabs(line, tab_width);
+        preview.push('\n');
+        preview.extend(core::iter::repeat(' ').take(start_column));
+        preview.extend(core::iter::repeat('^').take((end_column - start_column).max(1)));
+        Ok(preview)
     }
 
     /// Get a [SourceSpan] representing the entire content of `file_id`
     pub fn source_span(&self, file_id: SourceId) -> Result<SourceSpan, Error> {
-        Ok(self.get(file_id)?.source_span())
+        SourceLookup::source_span(self, file_id)
+    }
+
+    /// Checks that `span` refers to a file in this [CodeMap], and that its range lies within
+    /// the content of that file, on character boundaries.
+    ///
+    /// This is intended to catch spans being used with the wrong [CodeMap] early, e.g. in a
+    /// `debug_assert!`, rather than producing diagnostics which refer to the wrong content.
+    pub fn validate_span(&self, span: SourceSpan) -> Result<(), Error> {
+        let file = self.get(span.source_id())?;
+        let source = file.source();
+        for index in [span.start_index().to_usize(), span.end_index().to_usize()] {
+            if index > source.len() {
+                return Err(Error::IndexTooLarge {
+                    given: index,
+                    max: source.len(),
+                });
+            }
+            if !source.is_char_boundary(index) {
+                return Err(Error::InvalidCharBoundary { given: index });
+            }
+        }
+        Ok(())
+    }
+
+    /// Get a zero-width [SourceSpan] at the end of `file_id`, see [SourceFile::eof_span]
+    pub fn eof_span(&self, file_id: SourceId) -> Result<SourceSpan, Error> {
+        Ok(self.get(file_id)?.eof_span())
+    }
+
+    /// Like `source_span`, but returns `None` if `file_id` is [SourceId::UNKNOWN] or not in this map
+    pub fn source_span_opt(&self, file_id: SourceId) -> Option<SourceSpan> {
+        self.source_span(file_id).ok()
+    }
+
+    /// Get a [SourceText] handle to the content of `file_id`
+    ///
+    /// Unlike `source_slice`, the returned handle owns a reference to the underlying
+    /// [SourceFile], so content borrowed from it is soundly tied to the lifetime of the handle.
+    pub fn source_arc(&self, file_id: SourceId) -> Result<SourceText, Error> {
+        self.get(file_id).map(SourceText::new)
+    }
+
+    /// Get a [SourceText] handle through which the content of `file_id` can be accessed as
+    /// bytes, see [SourceText::as_bytes]
+    ///
+    /// Like `source_arc`, the handle owns a reference to the underlying [SourceFile], so the
+    /// bytes remain valid regardless of what happens to this [CodeMap].
+    pub fn source_bytes(&self, file_id: SourceId) -> Result<SourceText, Error> {
+        self.source_arc(file_id)
     }
 
     /// Get the original source content corresponding to `spanned` as a `&str`
@@ -251,8 +589,123 @@ impl CodeMap {
         unsafe { Ok(std::mem::transmute::<&str, &'a str>(slice)) }
     }
 
+    /// Get each character of the source content corresponding to `spanned`, along with the
+    /// index at which it starts.
+    ///
+    /// Unlike [str::char_indices], the indices are relative to the start of the file, not
+    /// the start of the span.
+    pub fn span_char_indices<S: Spanned>(
+        &self,
+        spanned: &S,
+    ) -> Result<Vec<(ByteIndex, char)>, Error> {
+        let start = spanned.span().start_index();
+        let slice = self.source_slice(spanned)?;
+        Ok(slice
+            .char_indices()
+            .map(|(offset, c)| (start + ByteOffset(offset as i64), c))
+            .collect())
+    }
+
+    /// Get the number of newline (`\n`) characters in the source content corresponding to `spanned`
+    pub fn newline_count<S: Spanned>(&self, spanned: &S) -> Result<usize, Error> {
+        let slice = self.source_slice(spanned)?;
+        Ok(slice.bytes().filter(|b| *b == b'\n').count())
+    }
+
+    /// Returns the number of files in this [CodeMap]
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Returns true if no files have been added to this [CodeMap]
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Returns the total size in bytes of the content of all files in this [CodeMap]
+    ///
+    /// Files added via `add_lazy` which have not been loaded yet are not counted, as computing
+    /// their size would require loading them.
+    pub fn total_bytes(&self) -> usize {
+        let guard = self.files.guard();
+        self.files
+            .values(&guard)
+            .filter(|file| file.is_loaded())
+            .map(|file| file.source().len())
+            .sum()
+    }
+
+    /// Returns the total number of lines across all files in this [CodeMap],
+    /// see [SourceFile::line_count]
+    ///
+    /// Like `total_bytes`, files which have not been loaded yet are not counted.
+    pub fn total_lines(&self) -> usize {
+        let guard = self.files.guard();
+        self.files
+            .values(&guard)
+            .filter(|file| file.is_loaded())
+            .map(|file| file.line_count())
+            .sum()
+    }
+
+    /// Removes all files from this [CodeMap], so that it may be reused, e.g. between
+    /// independent compilations in a test harness.
+    ///
+    /// Ids are assigned from the start again afterwards, so any [SourceId] obtained before
+    /// the clear is invalidated for lookup, and may later refer to a different file. Any
+    /// outstanding `Arc<SourceFile>` remains valid, but is no longer part of this map.
+    ///
+    /// This requires exclusive access, as content borrowed from this map, e.g. via
+    /// `source_slice`, must not outlive the files it belongs to. A [CodeMap] which is shared,
+    /// e.g. via `Arc`, cannot be cleared; create a new [CodeMap] instead.
+    pub fn clear(&mut self) {
+        let files_guard = self.files.guard();
+        self.files.clear(&files_guard);
+        let names_guard = self.names.guard();
+        self.names.clear(&names_guard);
+        let keys_guard = self.keys.guard();
+        self.keys.clear(&keys_guard);
+        let seen_guard = self.seen.guard();
+        self.seen.clear(&seen_guard);
+        self.next_file_id.store(1, Ordering::Relaxed);
+    }
+
+    /// Seals this [CodeMap], converting it into a [SealedCodeMap] for faster reads.
+    ///
+    /// No more files can be added once the map has been sealed. This is only available with
+    /// the `sealed` feature.
+    #[cfg(feature = "sealed")]
+    pub fn seal(self) -> SealedCodeMap {
+        let files_guard = self.files.guard();
+        let files = self
+            .files
+            .iter(&files_guard)
+            .map(|(id, file)| (*id, file.clone()))
+            .collect();
+        let names_guard = self.names.guard();
+        let names = self
+            .names
+            .iter(&names_guard)
+            .map(|(name, id)| (name.clone(), *id))
+            .collect();
+        let keys_guard = self.keys.guard();
+        let keys = self
+            .keys
+            .iter(&keys_guard)
+            .map(|(key, id)| (key.clone(), *id))
+            .collect();
+        SealedCodeMap { files, names, keys }
+    }
+
     #[inline(always)]
-    fn next_file_id(&self) -> SourceId {
+    fn next_file_id(&self, name: &FileName) -> SourceId {
+        if self.deterministic {
+            // FNV-1a, chosen for being trivially stable across platforms and releases
+            let hash = name.to_string().bytes().fold(0x811c9dc5u32, |hash, b| {
+                (hash ^ b as u32).wrapping_mul(0x01000193)
+            });
+            return SourceId::new(hash % (SourceId::UNKNOWN_SOURCE_ID - 1) + 1);
+        }
         let id = self.next_file_id.fetch_add(1, Ordering::Relaxed);
         SourceId::new(id)
     }
@@ -262,6 +715,119 @@ impl Default for CodeMap {
         Self::new()
     }
 }
+
+/// Returns the number of columns occupied by `c` when rendered at `column` in a terminal,
+/// see [CodeMap::visual_column]
+pub(crate) fn char_width(c: char, column: usize, tab_width: usize) -> usize {
+    use unicode_width::UnicodeWidthChar;
+
+    match (c, tab_width) {
+        ('\t', 0) => 0,
+        ('\t', _) => tab_width - (column % tab_width),
+        (c, _) => c.width().unwrap_or(0),
+    }
+}
+
+/// Expands tabs in `line` to the next multiple of `tab_width`, returning the expanded line,
+/// and the visual column at which each byte of `line` starts, plus one for the end of the line
+pub(crate) fn expand_tabs(line: &str, tab_width: usize) -> (String, Vec<usize>) {
+    let mut expanded = String::with_capacity(line.len());
+    let mut columns = Vec::with_capacity(line.len() + 1);
+    let mut column = 0;
+    for c in line.chars() {
+        columns.extend(core::iter::repeat(column).take(c.len_utf8()));
+        let width = char_width(c, column, tab_width);
+        if c == '\t' {
+            expanded.extend(core::iter::repeat(' ').take(width));
+        } else {
+            expanded.push(c);
+        }
+        column += width;
+    }
+    columns.push(column);
+    (expanded, columns)
+}
+
+/// [SourceLookup] provides the read-only API shared by [CodeMap] and `SealedCodeMap`, see
+/// `CodeMap::seal`, so that code which only resolves spans can work with either.
+///
+/// Implementations only need to provide access to the files they contain, via `with_file`.
+pub trait SourceLookup {
+    /// Calls `f` with the [SourceFile] corresponding to `file_id`, returning its result
+    ///
+    /// Returns `Err` if `file_id` is not in this map, or if the file failed to load.
+    fn with_file<R, F>(&self, file_id: SourceId, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&SourceFile) -> R;
+
+    /// Get the [SourceSpan] corresponding to the parent of a given [SourceId].
+    ///
+    /// Returns `None` if the given [SourceId] has no parent
+    fn parent(&self, file_id: SourceId) -> Option<SourceSpan> {
+        self.with_file(file_id, |f| f.parent()).ok().flatten()
+    }
+
+    /// Get a [SourceSpan] corresponding to the given line:column
+    ///
+    /// NOTE: The returned [SourceSpan] points only to line:column, it does not
+    /// span any neighboring source locations, callers must extend the returned
+    /// span if so desired.
+    fn line_column_to_span(
+        &self,
+        file_id: SourceId,
+        line: impl Into<LineIndex>,
+        column: impl Into<ColumnIndex>,
+    ) -> Result<SourceSpan, Error> {
+        let span = self.with_file(file_id, |f| {
+            f.line_column_to_span(line.into(), column.into())
+        })??;
+        let start = SourceIndex::new(file_id, span.start().into());
+        let end = SourceIndex::new(file_id, span.end().into());
+        Ok(SourceSpan::new(start, end))
+    }
+
+    /// Get a [Location] from a [SourceSpan]
+    ///
+    /// Returns `Err` if `span` is [SourceSpan::UNKNOWN].
+    fn location<S: Spanned>(&self, spanned: &S) -> Result<Location, Error> {
+        let span = spanned.span();
+        self.location_at_index(span.source_id, span.start)
+    }
+
+    /// Get a [Location] from a given [SourceId] and byte index.
+    fn location_at_index(
+        &self,
+        file_id: SourceId,
+        byte_index: impl Into<ByteIndex>,
+    ) -> Result<Location, Error> {
+        self.with_file(file_id, |f| f.location(byte_index))?
+    }
+
+    /// Get a [SourceSpan] representing the entire content of `file_id`
+    fn source_span(&self, file_id: SourceId) -> Result<SourceSpan, Error> {
+        self.with_file(file_id, |f| f.source_span())
+    }
+}
+impl SourceLookup for CodeMap {
+    fn with_file<R, F>(&self, file_id: SourceId, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&SourceFile) -> R,
+    {
+        self.get(file_id).map(|file| f(&file))
+    }
+}
+
+/// A [SourceSpan] resolved to the name of its file, and the locations at which it starts and
+/// ends, see [CodeMap::resolve_spans]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedSpan {
+    /// The name of the file to which the span refers
+    pub file: FileName,
+    /// The location at which the span starts
+    pub start: Location,
+    /// The location at which the span ends
+    pub end: Location,
+}
 impl<'a> Files<'a> for CodeMap {
     type FileId = SourceId;
     type Name = String;
@@ -288,3 +854,605 @@ impl<'a> Files<'a> for CodeMap {
         Ok(span.start().to_usize()..span.end().to_usize())
     }
 }
+
+/// [SealedCodeMap] is an immutable snapshot of a [CodeMap], obtained via [CodeMap::seal].
+///
+/// Once all sources have been loaded, a [CodeMap] can be sealed in order to make lookups
+/// cheaper in read-heavy workloads, e.g. rendering diagnostics. Files are stored in a plain,
+/// immutable map keyed by [SourceId], so lookups do not need to acquire a guard as is the
+/// case with [CodeMap].
+///
+/// The read-only API shared with [CodeMap] is provided by [SourceLookup]. Additionally, as a
+/// [SealedCodeMap] cannot be modified, content can be borrowed directly from it.
+///
+/// This is only available with the `sealed` feature.
+#[cfg(feature = "sealed")]
+#[derive(Debug)]
+pub struct SealedCodeMap {
+    files: rustc_hash::FxHashMap<SourceId, Arc<SourceFile>>,
+    names: rustc_hash::FxHashMap<FileName, SourceId>,
+    keys: rustc_hash::FxHashMap<String, SourceId>,
+}
+#[cfg(feature = "sealed")]
+impl SealedCodeMap {
+    /// Get the [SourceFile] corresponding to the given [SourceId]
+    pub fn get(&self, file_id: SourceId) -> Result<&Arc<SourceFile>, Error> {
+        if file_id == SourceId::UNKNOWN {
+            return Err(Error::FileMissing);
+        }
+        let file = self.files.get(&file_id).ok_or(Error::FileMissing)?;
+        file.load()?;
+        Ok(file)
+    }
+
+    /// Get the [SourceFile] corresponding to the given [SourceSpan]
+    ///
+    /// Returns `Err` if the span is `SourceSpan::UNKNOWN`
+    pub fn get_with_span(&self, span: SourceSpan) -> Result<&Arc<SourceFile>, Error> {
+        self.get(span.source_id)
+    }
+
+    /// Get the [SourceId] corresponding to the given [FileName]
+    pub fn get_file_id(&self, filename: &FileName) -> Option<SourceId> {
+        self.names.get(filename).copied()
+    }
+
+    /// Get the [SourceFile] corresponding to the given [FileName]
+    pub fn get_by_name(&self, filename: &FileName) -> Option<&Arc<SourceFile>> {
+        self.get_file_id(filename).and_then(|id| self.get(id).ok())
+    }
+
+    /// Get the [SourceId] registered under the logical `key`, see [CodeMap::add_with_key]
+    pub fn get_file_id_by_key(&self, key: &str) -> Option<SourceId> {
+        self.keys.get(key).copied()
+    }
+
+    /// Get the [FileName] corresponding to the given [SourceId]
+    ///
+    /// Returns `Err` if `file_id` is not in this map.
+    pub fn name(&self, file_id: SourceId) -> Result<&FileName, Error> {
+        self.get(file_id).map(|f| f.name())
+    }
+
+    /// Get the [FileName] associated with the given [SourceSpan]
+    ///
+    /// Returns `Err` if `span` is [SourceSpan::UNKNOWN].
+    pub fn name_for_spanned<S: Spanned>(&self, spanned: &S) -> Result<&FileName, Error> {
+        self.name(spanned.span().source_id)
+    }
+
+    /// Get the content of `file_id` as a byte slice
+    pub fn source_bytes(&self, file_id: SourceId) -> Result<&[u8], Error> {
+        Ok(self.get(file_id)?.as_bytes())
+    }
+
+    /// Get the original source content corresponding to `spanned` as a `&str`
+    pub fn source_slice<S: Spanned>(&self, spanned: &S) -> Result<&str, Error> {
+        let span = spanned.span();
+        self.get(span.source_id)?.source_slice(span)
+    }
+}
+#[cfg(feature = "sealed")]
+impl SourceLookup for SealedCodeMap {
+    fn with_file<R, F>(&self, file_id: SourceId, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&SourceFile) -> R,
+    {
+        self.get(file_id).map(|file| f(file))
+    }
+}
+#[cfg(feature = "sealed")]
+impl<'a> Files<'a> for SealedCodeMap {
+    type FileId = SourceId;
+    type Name = String;
+    type Source = &'a str;
+
+    fn name(&self, file_id: Self::FileId) -> Result<Self::Name, Error> {
+        Ok(format!("{}", self.get(file_id)?.name()))
+    }
+
+    fn source(&'a self, file_id: Self::FileId) -> Result<&'a str, Error> {
+        Ok(self.get(file_id)?.source())
+    }
+
+    fn line_index(&self, file_id: Self::FileId, byte_index: usize) -> Result<usize, Error> {
+        let f = self.get(file_id)?;
+        Ok(f.line_index(ByteIndex(byte_index as u32)).to_usize())
+    }
+
+    fn line_range(&self, file_id: Self::FileId, line_index: usize) -> Result<Range<usize>, Error> {
+        let span = self.get(file_id)?.line_span(LineIndex(line_index as u32))?;
+
+        Ok(span.start().to_usize()..span.end().to_usize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(file_id: SourceId, start: u32, end: u32) -> SourceSpan {
+        SourceSpan::new(
+            SourceIndex::new(file_id, ByteIndex(start)),
+            SourceIndex::new(file_id, ByteIndex(end)),
+        )
+    }
+
+    #[test]
+    fn source_text_outlives_codemap() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "let x = 1;\n".to_string());
+        let other = codemap.add("other", "y".to_string());
+        let text = codemap.source_arc(id).unwrap();
+        drop(codemap);
+
+        assert_eq!(text.as_str(), "let x = 1;\n");
+        assert_eq!(text.slice(span(id, 4, 5)), Some("x"));
+        assert_eq!(text.slice(span(id, 4, 20)), None);
+        assert_eq!(text.slice(span(other, 0, 1)), None);
+    }
+
+    #[test]
+    fn visual_column_counts_wide_characters() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "x\n漢字 = 1;\n\tfoo\n".to_string());
+        // `=` follows two CJK characters of 3 bytes each, and a space
+        let eq = SourceIndex::new(id, ByteIndex(2 + 7));
+        let char_column = codemap
+            .location_at_index(id, eq.index())
+            .unwrap()
+            .column
+            .to_usize();
+        assert_eq!(char_column, 3);
+        assert_eq!(codemap.visual_column(eq, 4).unwrap(), 5);
+
+        let foo = SourceIndex::new(id, ByteIndex(2 + 12 + 1));
+        assert_eq!(codemap.visual_column(foo, 4).unwrap(), 4);
+        assert_eq!(codemap.visual_column(foo, 8).unwrap(), 8);
+    }
+
+    #[test]
+    fn opt_variants_return_none_for_unknown() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "abc".to_string());
+        assert!(codemap.location_opt(&SourceSpan::UNKNOWN).is_none());
+        assert!(codemap.name_opt(SourceId::UNKNOWN).is_none());
+        assert!(codemap.source_span_opt(SourceId::UNKNOWN).is_none());
+        assert!(codemap.name_opt(SourceId::new(2)).is_none());
+
+        assert!(codemap.location_opt(&span(id, 1, 2)).is_some());
+        assert_eq!(codemap.name_opt(id), Some(FileName::from("test")));
+        assert_eq!(codemap.source_span_opt(id), Some(span(id, 0, 3)));
+    }
+
+    #[test]
+    fn lookup_by_key() {
+        let codemap = CodeMap::new();
+        let a = codemap.add_with_key("std::collections", "collections.masm", "a".to_string());
+        let b = codemap.add_with_key("std::math", "math.masm", "b".to_string());
+        assert_eq!(codemap.get_file_id_by_key("std::collections"), Some(a));
+        assert_eq!(codemap.get_file_id_by_key("std::math"), Some(b));
+        assert_eq!(codemap.get_file_id_by_key("collections.masm"), None);
+        assert_eq!(codemap.get_file_id(&FileName::from("math.masm")), Some(b));
+
+        // Re-registering a key refers it to the new file
+        let c = codemap.add_with_key("std::math", "math2.masm", "c".to_string());
+        assert_eq!(codemap.get_file_id_by_key("std::math"), Some(c));
+    }
+
+    #[test]
+    fn get_by_path_does_not_read_from_disk() {
+        let dir = std::env::temp_dir().join(format!(
+            "miden-diagnostics-get-by-path-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.masm");
+        std::fs::write(&path, "file a").unwrap();
+
+        let codemap = CodeMap::new();
+        assert!(codemap.get_by_path(&path).is_none());
+        let id = codemap.add_file(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let file = codemap.get_by_path(&path).unwrap();
+        assert_eq!(file.id(), id);
+        assert_eq!(file.source(), "file a");
+        assert!(codemap.get_by_path(dir.join("b.masm")).is_none());
+    }
+
+    #[test]
+    fn deterministic_ids_do_not_depend_on_order() {
+        let names = ["a.masm", "b.masm", "c.masm", "lib/d.masm"];
+        let mapping = |order: &[usize]| {
+            let codemap = CodeMap::with_deterministic_ids();
+            for &i in order {
+                codemap.add(names[i], format!("file {}", i));
+            }
+            names
+                .iter()
+                .map(|name| {
+                    let id = codemap.get_file_id(&FileName::from(*name)).unwrap();
+                    assert_eq!(codemap.get(id).unwrap().id(), id);
+                    id
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let forward = mapping(&[0, 1, 2, 3]);
+        assert_eq!(mapping(&[3, 2, 1, 0]), forward);
+        assert_eq!(mapping(&[2, 0, 3, 1]), forward);
+
+        // Adding the same name again probes for the next free id
+        let codemap = CodeMap::with_deterministic_ids();
+        let first = codemap.add("a.masm", "first".to_string());
+        let second = codemap.add("a.masm", "second".to_string());
+        assert_ne!(first, second);
+        assert_eq!(codemap.get(first).unwrap().source(), "first");
+        assert_eq!(codemap.get(second).unwrap().source(), "second");
+        assert_eq!(codemap.get(second).unwrap().id(), second);
+
+        // Without deterministic ids, the order of addition determines the ids
+        let codemap = CodeMap::new();
+        let b = codemap.add("b.masm", String::new());
+        let a = codemap.add("a.masm", String::new());
+        assert!(b < a);
+    }
+
+    #[test]
+    fn clear_resets_ids() {
+        let mut codemap = CodeMap::new();
+        let a = codemap.add("a", "a".to_string());
+        codemap.add("b", "b".to_string());
+        let file = codemap.get(a).unwrap();
+        assert_eq!(codemap.len(), 2);
+
+        codemap.clear();
+        assert_eq!(codemap.len(), 0);
+        assert!(codemap.is_empty());
+        assert!(codemap.get(a).is_err());
+        assert!(codemap.get_file_id(&FileName::from("a")).is_none());
+        assert_eq!(file.source(), "a");
+
+        let c = codemap.add("c", "c".to_string());
+        assert_eq!(c, SourceId::new(1));
+        assert_eq!(codemap.get(c).unwrap().source(), "c");
+    }
+
+    #[test]
+    fn rename_file_keeps_id() {
+        let codemap = CodeMap::new();
+        let old = std::path::PathBuf::from("old.masm");
+        let new = std::path::PathBuf::from("new.masm");
+        let id = codemap.add(old.clone(), "begin\nend\n".to_string());
+        let s = span(id, 6, 9);
+
+        codemap.rename_file(id, new.clone()).unwrap();
+        assert_eq!(codemap.name(id).unwrap(), FileName::from(new.clone()));
+        assert_eq!(codemap.get_file_id(&FileName::from(new.clone())), Some(id));
+        assert_eq!(codemap.get_file_id(&FileName::from(old.clone())), None);
+        assert_eq!(codemap.get_by_path(&new).unwrap().id(), id);
+        assert!(codemap.get_by_path(&old).is_none());
+        assert_eq!(codemap.source_slice(&s).unwrap(), "end");
+
+        assert!(codemap.rename_file(SourceId::new(2), "other").is_err());
+    }
+
+    #[test]
+    fn totals_across_files() {
+        let codemap = CodeMap::new();
+        assert_eq!(codemap.total_bytes(), 0);
+        assert_eq!(codemap.total_lines(), 0);
+        codemap.add("a", "push.1\nadd\n".to_string());
+        codemap.add("b", "begin\n  nop\nend".to_string());
+        assert_eq!(codemap.total_bytes(), 11 + 15);
+        assert_eq!(codemap.total_lines(), 2 + 3);
+    }
+
+    #[test]
+    fn validate_span() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "let é = 1;\n".to_string());
+        assert!(codemap.validate_span(span(id, 0, 3)).is_ok());
+        assert!(codemap.validate_span(span(id, 0, 12)).is_ok());
+        assert!(matches!(
+            codemap.validate_span(span(id, 4, 13)),
+            Err(Error::IndexTooLarge { given: 13, max: 12 })
+        ));
+        assert!(matches!(
+            codemap.validate_span(span(id, 4, 5)),
+            Err(Error::InvalidCharBoundary { given: 5 })
+        ));
+        assert!(matches!(
+            codemap.validate_span(span(SourceId::new(42), 0, 1)),
+            Err(Error::FileMissing)
+        ));
+    }
+
+    #[test]
+    fn span_char_indices_are_file_relative() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "let café = 1;\n".to_string());
+        assert_eq!(
+            codemap.span_char_indices(&span(id, 4, 9)).unwrap(),
+            vec![
+                (ByteIndex(4), 'c'),
+                (ByteIndex(5), 'a'),
+                (ByteIndex(6), 'f'),
+                (ByteIndex(7), 'é'),
+            ]
+        );
+        assert!(codemap.span_char_indices(&span(id, 4, 8)).is_err());
+    }
+
+    #[test]
+    fn newline_count() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "begin\n  nop\nend\n".to_string());
+        assert_eq!(codemap.newline_count(&span(id, 2, 14)).unwrap(), 2);
+        assert_eq!(codemap.newline_count(&span(id, 0, 5)).unwrap(), 0);
+        assert_eq!(codemap.newline_count(&span(id, 0, 16)).unwrap(), 3);
+    }
+
+    #[test]
+    fn add_file_records_modification_time() {
+        let dir =
+            std::env::temp_dir().join(format!("miden-diagnostics-modified-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.masm");
+        std::fs::write(&path, "file a").unwrap();
+
+        let codemap = CodeMap::new();
+        let id = codemap.add_file(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let modified = codemap.get(id).unwrap().modified().unwrap();
+        let age = std::time::SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        assert!(age < std::time::Duration::from_secs(60), "{:?}", age);
+
+        let virtual_file = codemap.add("b.masm", "file b".to_string());
+        assert!(codemap.get(virtual_file).unwrap().modified().is_none());
+    }
+
+    #[test]
+    fn resolve_spans_preserves_order() {
+        let codemap = CodeMap::new();
+        let a = codemap.add("a.masm", "push.1\nadd\n".to_string());
+        let b = codemap.add("b.masm", "drop\n".to_string());
+        let resolved = |file: &'static str, start: (u32, u32), end: (u32, u32)| {
+            Some(ResolvedSpan {
+                file: FileName::from(file),
+                start: Location::new(start.0, start.1),
+                end: Location::new(end.0, end.1),
+            })
+        };
+        assert_eq!(
+            codemap.resolve_spans(&[
+                span(a, 7, 10),
+                span(b, 0, 4),
+                SourceSpan::UNKNOWN,
+                span(a, 0, 6),
+                span(b, 2, 40),
+                span(SourceId::new(42), 0, 1),
+            ]),
+            vec![
+                resolved("a.masm", (1, 0), (1, 3)),
+                resolved("b.masm", (0, 0), (0, 4)),
+                None,
+                resolved("a.masm", (0, 0), (0, 6)),
+                None,
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn caret_preview_mid_line() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "let x = foo(bar);\n".to_string());
+        let preview = codemap.caret_preview(span(id, 8, 11), 4).unwrap();
+        assert_eq!(preview, "let x = foo(bar);\n        ^^^");
+    }
+
+    #[test]
+    fn caret_preview_eof_span() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "abc\n".to_string());
+        let preview = codemap
+            .caret_preview(codemap.eof_span(id).unwrap(), 4)
+            .unwrap();
+        assert_eq!(preview, "abc\n   ^");
+    }
+
+    #[test]
+    fn caret_preview_crlf_line() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "abc\r\ndef\r\n".to_string());
+        assert_eq!(
+            codemap.caret_preview(span(id, 3, 5), 4).unwrap(),
+            "abc\n   ^"
+        );
+        assert_eq!(
+            codemap.caret_preview(span(id, 4, 5), 4).unwrap(),
+            "abc\n   ^"
+        );
+        assert_eq!(
+            codemap.caret_preview(span(id, 6, 8), 4).unwrap(),
+            "def\n ^^"
+        );
+    }
+
+    #[test]
+    fn caret_preview_tab_indented_line() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "\tx = y;\n".to_string());
+        assert_eq!(
+            codemap.caret_preview(span(id, 1, 2), 4).unwrap(),
+            "    x = y;\n    ^"
+        );
+        assert_eq!(
+            codemap.caret_preview(span(id, 5, 6), 8).unwrap(),
+            "        x = y;\n            ^"
+        );
+    }
+
+    #[test]
+    fn add_files_in_order() {
+        let dir = std::env::temp_dir().join(format!("miden-diagnostics-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = (0..4)
+            .map(|i| {
+                let path = dir.join(format!("{}.masm", i));
+                std::fs::write(&path, format!("file {}", i)).unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+        let missing = dir.join("missing.masm");
+        let inputs = vec![
+            paths[0].clone(),
+            paths[1].clone(),
+            missing.clone(),
+            paths[2].clone(),
+            paths[0].clone(),
+            paths[3].clone(),
+            missing,
+        ];
+
+        let codemap = CodeMap::new();
+        let results = codemap.add_files(&inputs);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(results.len(), inputs.len());
+        // The errors are those returned by the OS, rather than copies of them
+        for i in [2, 6] {
+            let err = results[i].as_ref().unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+            assert!(err.raw_os_error().is_some(), "{:?}", err);
+        }
+        let ids = [0, 1, 3, 4, 5].map(|i| *results[i].as_ref().unwrap());
+        assert_eq!(ids[0], ids[3]);
+        for (id, i) in ids.iter().zip([0, 1, 2, 0, 3]) {
+            assert_eq!(codemap.get(*id).unwrap().source(), format!("file {}", i));
+        }
+        assert_eq!(codemap.len(), 4);
+    }
+
+    #[test]
+    fn lazy_file_is_loaded_once_on_first_access() {
+        let loads = Arc::new(AtomicU32::new(0));
+        let codemap = CodeMap::new();
+        let id = codemap.add_lazy("lazy.masm", {
+            let loads = loads.clone();
+            Box::new(move || {
+                loads.fetch_add(1, Ordering::SeqCst);
+                Ok("abc\ndef\n".to_string())
+            })
+        });
+        codemap.add("eager.masm", "x\n".to_string());
+
+        codemap.rename_file(id, "renamed.masm").unwrap();
+        assert_eq!(codemap.total_bytes(), 2);
+        assert_eq!(codemap.total_lines(), 1);
+        assert_eq!(loads.load(Ordering::SeqCst), 0);
+
+        assert_eq!(codemap.get(id).unwrap().source(), "abc\ndef\n");
+        assert_eq!(codemap.source_slice(&span(id, 4, 7)).unwrap(), "def");
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert_eq!(codemap.total_bytes(), 10);
+        assert_eq!(codemap.total_lines(), 3);
+    }
+
+    #[test]
+    fn lazy_file_which_fails_to_load() {
+        let codemap = CodeMap::new();
+        let id = codemap.add_lazy(
+            "missing.masm",
+            Box::new(|| Err(std::io::Error::from(std::io::ErrorKind::NotFound))),
+        );
+
+        assert!(matches!(codemap.get(id), Err(Error::Io(_))));
+        assert!(matches!(codemap.get(id), Err(Error::FileMissing)));
+        assert!(codemap.source_slice(&span(id, 0, 0)).is_err());
+        assert_eq!(codemap.total_bytes(), 0);
+    }
+
+    #[cfg(feature = "sealed")]
+    #[test]
+    fn sealed_lookups_match_unsealed() {
+        let codemap = CodeMap::new();
+        let a = codemap.add("a.masm", "begin\n\tpush.1\nend\n".to_string());
+        let b = codemap.add_with_key("std::math", "b.masm", "export.foo\n  add\nend".to_string());
+        let c = codemap.add_child("c.masm", "ä ö\n".to_string(), span(a, 6, 13));
+        let spans = [
+            span(a, 0, 5),
+            span(a, 7, 13),
+            span(a, 18, 18),
+            span(b, 11, 16),
+            span(b, 20, 23),
+            span(c, 0, 2),
+            span(c, 3, 5),
+            SourceSpan::UNKNOWN,
+        ];
+
+        let expected = spans
+            .iter()
+            .map(|span| {
+                let id = span.source_id();
+                (
+                    SourceLookup::location(&codemap, span).ok(),
+                    codemap.source_slice(span).ok().map(str::to_string),
+                    codemap.name(id).ok(),
+                    SourceLookup::parent(&codemap, id),
+                    SourceLookup::source_span(&codemap, id).ok(),
+                    SourceLookup::line_column_to_span(&codemap, id, 1, 1).ok(),
+                    Files::line_range(&codemap, id, 0).ok(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let key = codemap.get_file_id_by_key("std::math");
+        let by_name = codemap.get_file_id(&"c.masm".into());
+
+        let sealed = codemap.seal();
+        let actual = spans
+            .iter()
+            .map(|span| {
+                let id = span.source_id();
+                (
+                    SourceLookup::location(&sealed, span).ok(),
+                    sealed.source_slice(span).ok().map(str::to_string),
+                    sealed.name(id).ok().cloned(),
+                    SourceLookup::parent(&sealed, id),
+                    SourceLookup::source_span(&sealed, id).ok(),
+                    SourceLookup::line_column_to_span(&sealed, id, 1, 1).ok(),
+                    Files::line_range(&sealed, id, 0).ok(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(actual, expected);
+        assert_eq!(sealed.get_file_id_by_key("std::math"), key);
+        assert_eq!(sealed.get_file_id(&"c.masm".into()), by_name);
+    }
+
+    #[test]
+    fn source_bytes_match_spans() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "let ä = 1;\n".to_string());
+        let text = codemap.source_bytes(id).unwrap();
+        assert_eq!(
+            text.as_bytes().len(),
+            codemap.get(id).unwrap().source().len()
+        );
+        assert_eq!(text.as_bytes().len(), 12);
+
+        let span = span(id, 4, 6);
+        let range = span.start_index().to_usize()..span.end_index().to_usize();
+        assert_eq!(codemap.source_slice(&span).unwrap(), "ä");
+        assert!(codemap.source_bytes(SourceId::UNKNOWN).is_err());
+
+        // The bytes remain valid after the map is gone
+        drop(codemap);
+        assert_eq!(&text.as_bytes()[range], "ä".as_bytes());
+    }
+}
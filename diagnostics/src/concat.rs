@@ -0,0 +1,104 @@
+use crate::*;
+
+/// A [ConcatView] maps offsets in a virtual concatenation of a sequence of files in a
+/// [CodeMap] back to locations in the individual files, and vice versa.
+///
+/// This is intended for interop with tools which flatten multi-file input into a single
+/// buffer, and track positions as a global byte offset into that buffer. The files are
+/// concatenated in the given order, with no separators between them.
+#[derive(Debug, Clone)]
+pub struct ConcatView {
+    /// The global offset at which each file starts, in ascending order
+    starts: Vec<(usize, SourceId)>,
+    len: usize,
+}
+impl ConcatView {
+    /// Constructs a [ConcatView] of `files`, in the given order, using the content in `codemap`
+    ///
+    /// Returns `Err` if any of `files` is not in `codemap`.
+    pub fn new(codemap: &CodeMap, files: &[SourceId]) -> Result<Self, Error> {
+        let mut starts = Vec::with_capacity(files.len());
+        let mut len = 0;
+        for file_id in files.iter().copied() {
+            let file = codemap.get(file_id)?;
+            starts.push((len, file_id));
+            len += file.source().len();
+        }
+        Ok(Self { starts, len })
+    }
+
+    /// Returns the total length in bytes of the concatenated content
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the concatenated content is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Maps `global_offset` to the [SourceIndex] in the file which contains it
+    ///
+    /// An offset at the boundary between two files belongs to the latter, unless it is the
+    /// end of the last file. Returns `None` if `global_offset` is past the end of the content.
+    pub fn locate(&self, global_offset: usize) -> Option<SourceIndex> {
+        if global_offset > self.len || self.starts.is_empty() {
+            return None;
+        }
+        let i = match self
+            .starts
+            .binary_search_by(|(start, _)| start.cmp(&global_offset))
+        {
+            // Several empty files may start at the same offset, prefer the last of them
+            Ok(i) => {
+                let start = self.starts[i].0;
+                i + self.starts[i..]
+                    .iter()
+                    .take_while(|(s, _)| *s == start)
+                    .count()
+                    - 1
+            }
+            Err(i) => i - 1,
+        };
+        let (start, file_id) = self.starts[i];
+        Some(SourceIndex::new(
+            file_id,
+            ByteIndex((global_offset - start) as u32),
+        ))
+    }
+
+    /// Maps `index` to its global offset in the concatenated content
+    ///
+    /// Returns `None` if the file of `index` is not part of this view.
+    pub fn offset_of(&self, index: SourceIndex) -> Option<usize> {
+        self.starts
+            .iter()
+            .find(|(_, file_id)| *file_id == index.source_id())
+            .map(|(start, _)| start + index.index().to_usize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locate_in_middle_file() {
+        let codemap = CodeMap::new();
+        let a = codemap.add("a", "aaaa".to_string());
+        let b = codemap.add("b", "bbb".to_string());
+        let c = codemap.add("c", "cc".to_string());
+        let view = ConcatView::new(&codemap, &[a, b, c]).unwrap();
+        assert_eq!(view.len(), 9);
+
+        assert_eq!(view.locate(0), Some(SourceIndex::new(a, ByteIndex(0))));
+        assert_eq!(view.locate(4), Some(SourceIndex::new(b, ByteIndex(0))));
+        assert_eq!(view.locate(5), Some(SourceIndex::new(b, ByteIndex(1))));
+        assert_eq!(view.locate(9), Some(SourceIndex::new(c, ByteIndex(2))));
+        assert_eq!(view.locate(10), None);
+
+        assert_eq!(view.offset_of(SourceIndex::new(b, ByteIndex(1))), Some(5));
+        let d = codemap.add("d", "d".to_string());
+        assert_eq!(view.offset_of(SourceIndex::new(d, ByteIndex(0))), None);
+    }
+}
@@ -1,10 +1,115 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::term::termcolor::ColorChoice;
 use crate::term::Config;
+use crate::{Emitter, LintLevel, Severity};
 
 #[derive(Debug, Clone)]
 pub struct DiagnosticsConfig {
     pub verbosity: Verbosity,
     pub warnings_as_errors: bool,
     pub no_warn: bool,
+    /// When true, a [crate::FatalError] is raised as soon as the first error diagnostic has
+    /// been emitted, for build modes where only the first problem matters.
+    ///
+    /// The error is rendered before the [crate::FatalError] is raised. Warnings promoted to
+    /// errors by `warnings_as_errors` count as errors. Off by default.
+    pub fail_fast: bool,
+    /// When set, diagnostics with a severity lower than this are not emitted.
+    ///
+    /// This is checked after warnings are promoted to errors by `warnings_as_errors`.
+    pub min_severity: Option<Severity>,
+    /// When true, each label is annotated with the raw byte range of its span when rendered.
+    ///
+    /// This is intended as an aid for debugging span construction, and is off by default.
+    pub show_byte_offsets: bool,
+    /// When true (the default), line numbers are reported starting from 1 in rendered output.
+    ///
+    /// Consumers which expect 0-based positions, such as language servers implementing the
+    /// Language Server Protocol, should set this and `one_based_columns` to false. Note that
+    /// this only affects how locations are reported to users; a [crate::Location] always
+    /// contains 0-based indices, regardless of this setting.
+    pub one_based_lines: bool,
+    /// When true (the default), column numbers are reported starting from 1 in rendered output.
+    ///
+    /// See `one_based_lines` for details.
+    pub one_based_columns: bool,
+    /// When set, this offset is added to line numbers in rendered output.
+    ///
+    /// This is intended for rendering diagnostics for a fragment of a larger file, e.g. an
+    /// offset of `-99` displays line 100 of the file as line 1. It is display-only, spans are
+    /// resolved against the full file as usual. Line numbers are never displayed as negative.
+    pub line_number_offset: Option<i64>,
+    /// When set, this text is appended as a final note to every emitted diagnostic with a
+    /// severity of at least `footer_min_severity`, e.g. to point users at further help.
+    ///
+    /// The note is not added if the diagnostic already has an identical note.
+    pub error_footer: Option<String>,
+    /// The minimum severity of diagnostics to which `error_footer` is appended.
+    ///
+    /// Defaults to [Severity::Error].
+    pub footer_min_severity: Severity,
+    /// The text used for severity labels in rendered output, e.g. `error` in `error: ...`
+    ///
+    /// Defaults to English.
+    pub severity_labels: SeverityLabels,
+    /// Overrides the level of lints by name, e.g. as given by `-W`/`-D`/`-A` flags.
+    ///
+    /// See [crate::LintRegistry].
+    pub lint_levels: BTreeMap<String, LintLevel>,
+    /// When set, status messages, i.e. those printed by `info`, `debug`, `notice`, `success`
+    /// and `failed` of [crate::DiagnosticsHandler], are written to this emitter, rather than
+    /// the one used for diagnostics, e.g. so that they can go to stdout rather than stderr.
+    pub status_emitter: Option<Arc<dyn Emitter>>,
+    /// When true, each emitted diagnostic is also recorded along with the order in which it
+    /// was emitted, and the thread which emitted it, see [crate::DiagnosticsHandler::take_recorded].
+    pub record_diagnostics: bool,
+    /// When set, diagnostic messages and notes are wrapped at whitespace so that rendered
+    /// lines fit within this many columns, where possible.
+    ///
+    /// Source snippets and label messages are never wrapped.
+    pub wrap_width: Option<usize>,
+    /// When set along with `wrap_width`, the minimum width, in columns, reserved for the code
+    /// to the right of the gutter of source snippets.
+    ///
+    /// The gutter is as wide as the largest line number displayed, so on a narrow terminal,
+    /// large line numbers can leave only a cramped column for the code. When fewer than this
+    /// many columns would remain, only the trailing digits of line numbers are shown in the
+    /// gutter, so that it fits in the remaining space. The location in the header of each
+    /// snippet always shows the full line number. Notes, which are indented past the gutter,
+    /// are wrapped to the code area as well.
+    pub min_code_width: Option<usize>,
+    /// When set, source lines on which labels are displayed, which are longer than this many
+    /// characters, are truncated to a window of this many characters around the label on
+    /// them, with an ellipsis marking the omitted content, e.g. for generated code with very
+    /// long lines.
+    ///
+    /// The window is centered on the label, or starts at it if the label is too long to fit,
+    /// in which case the underline is truncated as well. Line and column numbers are reported
+    /// as in the original source.
+    pub max_label_width: Option<usize>,
+    /// When set, rendered diagnostics, as well as messages printed by `info` and `debug` of
+    /// [crate::DiagnosticsHandler], are prefixed with this name, e.g. `miden: error: ...`.
+    ///
+    /// This is intended for attributing output when the compiler is embedded in a larger tool.
+    pub program_name: Option<String>,
+    /// When set, the unlabeled lines between two labeled lines of a source snippet are only
+    /// collapsed to a `·` separator when there are more than this many of them; otherwise
+    /// they are shown in full.
+    ///
+    /// By default, they are collapsed whenever there is more than one of them. Values below 1
+    /// have the same effect as the default, as a single line is always shown.
+    pub max_gap_lines: Option<usize>,
+    /// When true, adjacent labels with identical messages are merged when rendered, see
+    /// [crate::merge_adjacent_labels].
+    pub merge_adjacent_labels: bool,
+    /// When true, suggestions which affect a single line are rendered as that line with the
+    /// replacement applied, rather than as a diff. Other suggestions are rendered as a diff.
+    pub inline_suggestions: bool,
+    /// Whether to render diagnostics with color, when the emitter is selected automatically,
+    /// see [crate::DiagnosticsHandler::from_config]. Defaults to [ColorChoice::Auto].
+    pub color: ColorChoice,
     pub display: Config,
 }
 impl Default for DiagnosticsConfig {
@@ -13,10 +118,90 @@ impl Default for DiagnosticsConfig {
             verbosity: Verbosity::Info,
             warnings_as_errors: false,
             no_warn: false,
+            fail_fast: false,
+            min_severity: None,
+            show_byte_offsets: false,
+            one_based_lines: true,
+            one_based_columns: true,
+            line_number_offset: None,
+            error_footer: None,
+            footer_min_severity: Severity::Error,
+            severity_labels: SeverityLabels::default(),
+            lint_levels: BTreeMap::new(),
+            status_emitter: None,
+            record_diagnostics: false,
+            wrap_width: None,
+            min_code_width: None,
+            max_label_width: None,
+            program_name: None,
+            max_gap_lines: None,
+            merge_adjacent_labels: false,
+            inline_suggestions: false,
+            color: ColorChoice::Auto,
             display: Config::default(),
         }
     }
 }
+impl DiagnosticsConfig {
+    /// Returns the default configuration, adjusted for rendering to plain text of a known
+    /// width, e.g. a log file: color is disabled, only ASCII characters are used to draw
+    /// source snippets, and messages are wrapped at `width` columns.
+    ///
+    /// Color is disabled via `color`, which is used to select the emitter in
+    /// [crate::DiagnosticsHandler::from_config]. When an emitter is provided explicitly,
+    /// whether it writes ANSI color codes is up to that emitter.
+    pub fn plain(width: usize) -> Self {
+        Self {
+            color: ColorChoice::Never,
+            wrap_width: Some(width),
+            display: Config {
+                chars: crate::term::Chars::ascii(),
+                ..Config::default()
+            },
+            ..Self::default()
+        }
+    }
+}
+
+/// The text used to label each severity level in rendered output, which may be
+/// customized for localization or rebranding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeverityLabels {
+    pub bug: String,
+    pub error: String,
+    pub warning: String,
+    pub note: String,
+    pub help: String,
+    /// Used by [crate::DiagnosticsHandler::info]
+    pub info: String,
+    /// Used by [crate::DiagnosticsHandler::debug]
+    pub debug: String,
+}
+impl SeverityLabels {
+    /// Returns the label for `severity`
+    pub fn get(&self, severity: Severity) -> &str {
+        match severity {
+            Severity::Bug => &self.bug,
+            Severity::Error => &self.error,
+            Severity::Warning => &self.warning,
+            Severity::Note => &self.note,
+            Severity::Help => &self.help,
+        }
+    }
+}
+impl Default for SeverityLabels {
+    fn default() -> Self {
+        Self {
+            bug: "bug".to_string(),
+            error: "error".to_string(),
+            warning: "warning".to_string(),
+            note: "note".to_string(),
+            help: "help".to_string(),
+            info: "info".to_string(),
+            debug: "debug".to_string(),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Verbosity {
@@ -0,0 +1,216 @@
+use std::ops::Range;
+
+use crate::term::termcolor::Buffer;
+use crate::*;
+
+/// A [DetachedDiagnostic] is a self-contained diagnostic, which embeds the source snippets
+/// required to render it, so that it can be rendered without access to the [CodeMap] from
+/// which it was produced, e.g. in a separate reporting process.
+///
+/// Use [CodeMap::detach] to construct one, and [render_detached] to render it.
+#[derive(Debug, Clone)]
+pub struct DetachedDiagnostic {
+    diagnostic: codespan_reporting::diagnostic::Diagnostic<usize>,
+    sources: Vec<DetachedSource>,
+}
+
+/// A snippet of a source file, consisting of the whole lines referenced by a diagnostic.
+#[derive(Debug, Clone)]
+struct DetachedSource {
+    name: String,
+    source: String,
+    /// The line index in the original file at which this snippet starts
+    line_offset: usize,
+    line_starts: Vec<usize>,
+}
+
+impl DetachedDiagnostic {
+    /// Returns the underlying diagnostic, whose file ids refer to the embedded sources
+    pub fn diagnostic(&self) -> &codespan_reporting::diagnostic::Diagnostic<usize> {
+        &self.diagnostic
+    }
+
+    fn get(&self, file_id: usize) -> Result<&DetachedSource, Error> {
+        self.sources.get(file_id).ok_or(Error::FileMissing)
+    }
+}
+
+impl CodeMap {
+    /// Produces a [DetachedDiagnostic] from `diagnostic`, bundling it with the source
+    /// content needed to render its labels.
+    ///
+    /// Only the lines covered by the labels of each file are retained. Labels which refer
+    /// to files not present in this map are dropped.
+    pub fn detach(&self, diagnostic: &Diagnostic) -> DetachedDiagnostic {
+        let mut file_ids = Vec::<SourceId>::new();
+        let mut sources = Vec::new();
+        let mut offsets = Vec::new();
+        for label in diagnostic.labels.iter() {
+            if file_ids.contains(&label.file_id) {
+                continue;
+            }
+            file_ids.push(label.file_id);
+            let file = match self.get(label.file_id) {
+                Ok(file) => file,
+                Err(_) => {
+                    offsets.push(None);
+                    continue;
+                }
+            };
+            let ranges = diagnostic
+                .labels
+                .iter()
+                .filter(|l| l.file_id == label.file_id)
+                .map(|l| l.range.clone());
+            let (start, end) = ranges.fold((usize::MAX, 0), |(start, end), range| {
+                (start.min(range.start), end.max(range.end))
+            });
+            let source = file.source();
+            let end = end.min(source.len());
+            let start_line = file.line_index(ByteIndex(start.min(end) as u32));
+            let end_line = file.line_index(ByteIndex(end as u32));
+            let lo = file.line_start(start_line).unwrap().to_usize();
+            let hi = file
+                .line_span(end_line)
+                .map(|span| span.end().to_usize())
+                .unwrap_or(source.len());
+            let snippet = source[lo..hi].to_string();
+            let line_starts = codespan_reporting::files::line_starts(&snippet).collect();
+            offsets.push(Some((sources.len(), lo)));
+            sources.push(DetachedSource {
+                name: file.name().to_string(),
+                source: snippet,
+                line_offset: start_line.to_usize(),
+                line_starts,
+            });
+        }
+
+        let labels = diagnostic
+            .labels
+            .iter()
+            .filter_map(|label| {
+                let index = file_ids.iter().position(|id| id == &label.file_id).unwrap();
+                let (file_id, lo) = offsets[index]?;
+                let range = Range {
+                    start: label.range.start - lo,
+                    end: label.range.end - lo,
+                };
+                Some(
+                    codespan_reporting::diagnostic::Label::new(label.style, file_id, range)
+                        .with_message(label.message.clone()),
+                )
+            })
+            .collect();
+
+        let detached = codespan_reporting::diagnostic::Diagnostic {
+            severity: diagnostic.severity,
+            code: diagnostic.code.clone(),
+            message: diagnostic.message.clone(),
+            labels,
+            notes: diagnostic.notes.clone(),
+        };
+        DetachedDiagnostic {
+            diagnostic: detached,
+            sources,
+        }
+    }
+}
+
+/// Renders a [DetachedDiagnostic] to a string, using the default display configuration
+/// and without color.
+pub fn render_detached(detached: &DetachedDiagnostic) -> String {
+    let mut buffer = Buffer::no_color();
+    crate::term::emit(
+        &mut buffer,
+        &crate::term::Config::default(),
+        detached,
+        &detached.diagnostic,
+    )
+    .unwrap();
+    String::from_utf8_lossy(buffer.as_slice()).into_owned()
+}
+
+impl<'a> Files<'a> for DetachedDiagnostic {
+    type FileId = usize;
+    type Name = &'a str;
+    type Source = &'a str;
+
+    fn name(&'a self, file_id: usize) -> Result<&'a str, Error> {
+        Ok(self.get(file_id)?.name.as_str())
+    }
+
+    fn source(&'a self, file_id: usize) -> Result<&'a str, Error> {
+        Ok(self.get(file_id)?.source.as_str())
+    }
+
+    fn line_index(&'a self, file_id: usize, byte_index: usize) -> Result<usize, Error> {
+        let file = self.get(file_id)?;
+        Ok(file
+            .line_starts
+            .binary_search(&byte_index)
+            .unwrap_or_else(|next_line| next_line - 1))
+    }
+
+    fn line_number(&'a self, file_id: usize, line_index: usize) -> Result<usize, Error> {
+        Ok(self.get(file_id)?.line_offset + line_index + 1)
+    }
+
+    fn line_range(&'a self, file_id: usize, line_index: usize) -> Result<Range<usize>, Error> {
+        let file = self.get(file_id)?;
+        let start = *file
+            .line_starts
+            .get(line_index)
+            .ok_or(Error::LineTooLarge {
+                given: line_index,
+                max: file.line_starts.len() - 1,
+            })?;
+        let end = file
+            .line_starts
+            .get(line_index + 1)
+            .copied()
+            .unwrap_or(file.source.len());
+        Ok(start..end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detached_renders_without_codemap() {
+        let codemap = CodeMap::new();
+        let source = (1..=10)
+            .map(|i| format!("line {:02}\n", i))
+            .collect::<String>();
+        let id = codemap.add("test.masm", source);
+        let span = |line: u32| {
+            SourceSpan::new(
+                SourceIndex::new(id, ByteIndex((line - 1) * 8)),
+                SourceIndex::new(id, ByteIndex((line - 1) * 8 + 4)),
+            )
+        };
+        let diagnostic = Diagnostic::error()
+            .with_message("something went wrong")
+            .with_labels(vec![
+                Label::primary(id, span(7)).with_message("here"),
+                Label::secondary(id, span(5)).with_message("because of this"),
+            ])
+            .with_notes(vec!["a note".to_string()]);
+
+        let mut buffer = Buffer::no_color();
+        crate::term::emit(
+            &mut buffer,
+            &crate::term::Config::default(),
+            &codemap,
+            &diagnostic,
+        )
+        .unwrap();
+        let expected = String::from_utf8(buffer.into_inner()).unwrap();
+
+        let detached = codemap.detach(&diagnostic);
+        drop(codemap);
+        assert_eq!(render_detached(&detached), expected);
+        assert!(expected.contains("7 │ line 07"), "{}", expected);
+    }
+}
@@ -1,11 +1,94 @@
+use std::any::Any;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::term::DisplayStyle;
 use crate::*;
 
+/// Returns the primary [Label] of `diagnostic`, i.e. the main location at which it originates.
+///
+/// If there are multiple primary labels, the first is returned. Returns `None` if there are none.
+pub fn primary_label(diagnostic: &Diagnostic) -> Option<&Label> {
+    diagnostic
+        .labels
+        .iter()
+        .find(|label| label.style == LabelStyle::Primary)
+}
+
+/// Returns the [SourceSpan] of the primary [Label] of `diagnostic`, see [primary_label].
+pub fn primary_span(diagnostic: &Diagnostic) -> Option<SourceSpan> {
+    primary_label(diagnostic).map(|label| SourceSpan {
+        source_id: label.file_id,
+        start: ByteIndex(label.range.start as u32),
+        end: ByteIndex(label.range.end as u32),
+    })
+}
+
+/// Computes a fingerprint of `diagnostic`, which identifies it across changes to the source
+/// code which do not affect it, e.g. for tracking known diagnostics between commits in CI.
+///
+/// The fingerprint is derived from the severity, code and message of the diagnostic, and the
+/// content of the line on which its primary label starts, ignoring leading and trailing
+/// whitespace. Notably, it does not depend on line numbers, so a diagnostic keeps the same
+/// fingerprint when the code it refers to merely moves, or is re-indented. The fingerprint is
+/// stable across runs, but not necessarily across versions of this crate.
+pub fn fingerprint(codemap: &CodeMap, diagnostic: &Diagnostic) -> u64 {
+    use core::hash::Hasher;
+
+    let mut hasher = rustc_hash::FxHasher::default();
+    hasher.write_u8(match diagnostic.severity {
+        Severity::Bug => 0,
+        Severity::Error => 1,
+        Severity::Warning => 2,
+        Severity::Note => 3,
+        Severity::Help => 4,
+    });
+    hasher.write(diagnostic.code.as_deref().unwrap_or("").as_bytes());
+    hasher.write_u8(0xff);
+    hasher.write(diagnostic.message.as_bytes());
+    hasher.write_u8(0xff);
+    let line = primary_span(diagnostic).and_then(|span| {
+        let file = codemap.get(span.source_id()).ok()?;
+        let line_span = file.line_span(file.line_index(span.start_index())).ok()?;
+        Some(file.source_slice(line_span).ok()?.trim().to_string())
+    });
+    if let Some(line) = line {
+        hasher.write(line.as_bytes());
+    }
+    hasher.finish()
+}
+
+/// Merges consecutive labels of `diagnostic` which are in the same file, have the same style
+/// and message, and whose spans are adjacent or overlapping, into a single label covering them.
+///
+/// This is intended to declutter diagnostics produced by analyses which emit many small labels,
+/// e.g. one per character. Labels with different messages are never merged.
+pub fn merge_adjacent_labels(diagnostic: &mut Diagnostic) {
+    let labels = core::mem::take(&mut diagnostic.labels);
+    for label in labels {
+        if let Some(last) = diagnostic.labels.last_mut() {
+            if last.file_id == label.file_id
+                && last.style == label.style
+                && last.message == label.message
+                && label.range.start <= last.range.end
+                && last.range.start <= label.range.end
+            {
+                last.range.start = last.range.start.min(label.range.start);
+                last.range.end = last.range.end.max(label.range.end);
+                continue;
+            }
+        }
+        diagnostic.labels.push(label);
+    }
+}
+
 /// Constructs an in-flight diagnostic using the builder pattern
 pub struct InFlightDiagnostic<'h> {
     handler: &'h DiagnosticsHandler,
     file_id: Option<SourceId>,
     diagnostic: Diagnostic,
     severity: Severity,
+    attachments: Attachments,
 }
 impl<'h> InFlightDiagnostic<'h> {
     pub(crate) fn new(handler: &'h DiagnosticsHandler, severity: Severity) -> Self {
@@ -14,6 +97,7 @@ impl<'h> InFlightDiagnostic<'h> {
             file_id: None,
             diagnostic: Diagnostic::new(severity),
             severity,
+            attachments: Attachments::default(),
         }
     }
 
@@ -27,8 +111,28 @@ impl<'h> InFlightDiagnostic<'h> {
     /// diagnostics in-flight by formatting functions which do
     /// not know what the current diagnostic configuration is
     pub fn verbose(&self) -> bool {
-        use crate::term::DisplayStyle;
-        matches!(self.handler.display.display_style, DisplayStyle::Rich)
+        let style = self
+            .attachments
+            .display_style
+            .as_ref()
+            .unwrap_or(&self.handler.display.display_style);
+        matches!(style, DisplayStyle::Rich)
+    }
+
+    /// Overrides the configured [DisplayStyle] when rendering this diagnostic
+    ///
+    /// This is useful for rendering high-volume diagnostics more compactly
+    /// than others, e.g. using [DisplayStyle::Short].
+    pub fn with_display_style(mut self, style: DisplayStyle) -> Self {
+        self.attachments.display_style = Some(style);
+        self
+    }
+
+    /// Sets the [DiagnosticOrigin] of this diagnostic, i.e. whether it is caused by the code
+    /// being compiled, or by the environment of the tool, see [DiagnosticsHandler::tool_error_count]
+    pub fn with_origin(mut self, origin: DiagnosticOrigin) -> Self {
+        self.attachments.origin = origin;
+        self
     }
 
     /// Sets the current source file to which this diagnostic applies
@@ -58,6 +162,9 @@ impl<'h> InFlightDiagnostic<'h> {
     /// A primary label is one which should be rendered as the relevant source code
     /// at which a diagnostic originates. Secondary labels are used for related items
     /// involved in the diagnostic.
+    ///
+    /// This may be called multiple times, in which case all of the primary labels are
+    /// rendered underlined as part of the same diagnostic, in the order they were added.
     pub fn with_primary_label(mut self, span: SourceSpan, message: impl ToString) -> Self {
         self.diagnostic
             .labels
@@ -65,6 +172,65 @@ impl<'h> InFlightDiagnostic<'h> {
         self
     }
 
+    /// Adds labels for both sites of `span`: a primary label at the call site, reading
+    /// "expanded from the macro here", and a secondary label at the definition site, reading
+    /// "in this macro definition".
+    ///
+    /// If both sites are the same, i.e. the code was not produced by expansion, only a primary
+    /// label without a message is added.
+    pub fn with_expanded_span(self, span: ExpandedSpan) -> Self {
+        if span.call_site == span.def_site {
+            return self.with_primary_span(span.call_site);
+        }
+        self.with_primary_label(span.call_site, "expanded from the macro here")
+            .with_secondary_label(span.def_site, "in this macro definition")
+    }
+
+    /// Sets the diagnostic message to `title`, and adds a primary label for `span` with `label`
+    ///
+    /// This encourages the convention of a short, general title for the diagnostic as a whole,
+    /// e.g. "type mismatch", with the specifics given inline at the location of the issue,
+    /// e.g. "expected i32, found bool".
+    pub fn with_primary(
+        self,
+        span: SourceSpan,
+        title: impl ToString,
+        label: impl ToString,
+    ) -> Self {
+        self.with_message(title).with_primary_label(span, label)
+    }
+
+    /// Adds a pair of primary labels for `open` and `close` to this diagnostic, with the
+    /// given message attached to `open`.
+    ///
+    /// This is a convenience for diagnostics which originate at two locations which are
+    /// logically part of the same error, e.g. mismatched delimiters.
+    pub fn with_primary_pair(
+        self,
+        open: SourceSpan,
+        close: SourceSpan,
+        message: impl ToString,
+    ) -> Self {
+        self.with_primary_label(open, message)
+            .with_primary_span(close)
+    }
+
+    /// Sets the diagnostic message to `message`, and adds primary labels for a pair of
+    /// conflicting items, e.g. duplicate definitions, which may be in different files.
+    ///
+    /// The labels read "first defined here" for `first`, and "redefined here" for `second`.
+    /// Each is rendered beneath the name of its own file.
+    pub fn with_conflict(
+        self,
+        first: SourceSpan,
+        second: SourceSpan,
+        message: impl ToString,
+    ) -> Self {
+        self.with_message(message)
+            .with_primary_label(first, "first defined here")
+            .with_primary_label(second, "redefined here")
+    }
+
     /// Adds a secondary label for `span` to this diagnostic, with the given message
     ///
     /// A secondary label is used to point out related items in the source code which
@@ -77,6 +243,41 @@ impl<'h> InFlightDiagnostic<'h> {
         self
     }
 
+    /// Adds a primary label covering the entire content of `file_id`, with the given message
+    ///
+    /// This is intended for diagnostics which pertain to a file as a whole, rather than to
+    /// any specific location within it, e.g. a module which is missing a required item. In
+    /// the short display style, only the file name is rendered.
+    ///
+    /// If `file_id` is not in the [CodeMap], no label is added.
+    pub fn with_file_label(mut self, file_id: SourceId, message: impl ToString) -> Self {
+        if let Ok(span) = self.handler.codemap.source_span(file_id) {
+            self.diagnostic
+                .labels
+                .push(Label::primary(file_id, span).with_message(message.to_string()));
+        }
+        self
+    }
+
+    /// Adds a secondary label for each of the given `items`, with a message produced by
+    /// calling `message_fn` with the index of the item.
+    ///
+    /// This is useful when pointing out a set of related items, e.g. conflicting declarations.
+    pub fn with_secondary_labels_from<'a, I, S, F>(mut self, items: I, message_fn: F) -> Self
+    where
+        I: IntoIterator<Item = &'a S>,
+        S: Spanned + 'a,
+        F: Fn(usize) -> String,
+    {
+        for (i, item) in items.into_iter().enumerate() {
+            let span = item.span();
+            self.diagnostic
+                .labels
+                .push(Label::secondary(span.source_id(), span).with_message(message_fn(i)));
+        }
+        self
+    }
+
     /// Like `with_primary_label`, but rather than a [SourceSpan], it accepts a
     /// line and column number, which will be mapped to an appropriate span by
     /// the [CodeMap].
@@ -134,6 +335,27 @@ impl<'h> InFlightDiagnostic<'h> {
         }
     }
 
+    /// Attaches an opaque `annotation` to the most recently added label of this diagnostic
+    ///
+    /// Annotations do not affect rendering by the built-in renderers, they are an extension
+    /// point for custom [Emitter]s, which can retrieve them via [DiagnosticRecord]. If no label
+    /// has been added yet, the annotation is discarded.
+    ///
+    /// With the `serde` feature, an annotation which is a `serde_json::Value` is also written
+    /// by [crate::RustcJsonEmitter], as the `annotation` of the span of the label.
+    pub fn with_label_annotation<T>(mut self, annotation: T) -> Self
+    where
+        T: Any + Send + Sync,
+    {
+        if let Some(label) = self.diagnostic.labels.len().checked_sub(1) {
+            self.attachments.annotations.push(LabelAnnotation {
+                label,
+                value: Arc::new(annotation),
+            });
+        }
+        self
+    }
+
     /// Adds a note to the diagnostic
     ///
     /// Notes are used for explaining general concepts or suggestions
@@ -151,13 +373,246 @@ impl<'h> InFlightDiagnostic<'h> {
         self.diagnostic.notes.push(note.to_string());
     }
 
+    /// Appends an expansion backtrace to the diagnostic, with one note per frame
+    ///
+    /// The frames are expected to be ordered from the innermost expansion, i.e. closest to
+    /// the error site, to the outermost, i.e. the originating source. Each frame is resolved
+    /// to a location via the [CodeMap], and rendered like so:
+    ///
+    /// ```text
+    /// #0: in this expansion at foo.masm:3:5
+    /// ```
+    pub fn with_expansion_backtrace(mut self, frames: Vec<SourceSpan>) -> Self {
+        for (i, frame) in frames.into_iter().enumerate() {
+            let note = match self.handler.display_location(&frame) {
+                Ok((line, column)) => {
+                    let name = self.handler.codemap.name(frame.source_id()).unwrap();
+                    format!("#{}: in this expansion at {}:{}:{}", i, name, line, column)
+                }
+                Err(_) => format!("#{}: in this expansion at <unknown location>", i),
+            };
+            self.diagnostic.notes.push(note);
+        }
+        self
+    }
+
+    /// Attaches a child diagnostic, with its own severity, message, and labels
+    ///
+    /// Children are rendered beneath, and indented relative to, their parent. Unlike notes,
+    /// they may point to source locations of their own, which makes them suitable for
+    /// explaining the context of an error in detail, e.g. where a conflicting item was defined.
+    ///
+    /// Children are not subject to filtering or counting by the [DiagnosticsHandler], they
+    /// are emitted if and only if their parent is.
+    pub fn with_child(
+        mut self,
+        severity: Severity,
+        message: impl ToString,
+        labels: Vec<Label>,
+    ) -> Self {
+        let child = Diagnostic::new(severity)
+            .with_message(message.to_string())
+            .with_labels(labels);
+        self.attachments.children.push(DiagnosticTree::from(child));
+        self
+    }
+
+    /// Attaches a [Suggestion], titled `title`, to replace the content of `span` with `replacement`
+    ///
+    /// Suggestions are rendered beneath the diagnostic as a diff of the affected lines, which
+    /// makes them suitable for fixes which span multiple lines. They are omitted when using
+    /// the short display style.
+    pub fn with_suggestion(
+        mut self,
+        span: SourceSpan,
+        replacement: impl Into<String>,
+        title: impl ToString,
+    ) -> Self {
+        self.attachments
+            .suggestions
+            .push(Suggestion::new(span, replacement, title));
+        self
+    }
+
     /// Consume this [InFlightDiagnostic] and extract the underlying [Diagnostic]
+    ///
+    /// NOTE: Any children or suggestions attached to this diagnostic are discarded.
     pub fn take(self) -> Diagnostic {
         self.diagnostic
     }
 
     /// Emit the underlying [Diagnostic] via the [DiagnosticsHandler]
     pub fn emit(self) {
-        self.handler.emit(self.diagnostic);
+        self.handler
+            .emit_diagnostic(self.diagnostic, self.attachments);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn handler(source: &str) -> (DiagnosticsHandler, SourceId) {
+        let codemap = Arc::new(CodeMap::new());
+        let id = codemap.add("test.masm", source.to_string());
+        let emitter = Arc::new(NullEmitter::default());
+        let handler = DiagnosticsHandler::new(DiagnosticsConfig::default(), codemap, emitter);
+        (handler, id)
+    }
+
+    fn span(id: SourceId, start: u32, end: u32) -> SourceSpan {
+        SourceSpan::new(
+            SourceIndex::new(id, ByteIndex(start)),
+            SourceIndex::new(id, ByteIndex(end)),
+        )
+    }
+
+    #[test]
+    fn primary_label_is_first_primary() {
+        let id = SourceId::new(1);
+        let diagnostic = Diagnostic::error().with_labels(vec![
+            Label::secondary(id, span(id, 0, 1)).with_message("secondary"),
+            Label::primary(id, span(id, 2, 4)).with_message("first"),
+            Label::primary(id, span(id, 6, 8)).with_message("second"),
+        ]);
+        assert_eq!(primary_label(&diagnostic).unwrap().message, "first");
+        assert_eq!(primary_span(&diagnostic), Some(span(id, 2, 4)));
+
+        let diagnostic =
+            Diagnostic::error().with_labels(vec![Label::secondary(id, span(id, 0, 1))]);
+        assert!(primary_label(&diagnostic).is_none());
+        assert_eq!(primary_span(&diagnostic), None);
+    }
+
+    #[test]
+    fn expansion_backtrace_notes_are_ordered() {
+        let (handler, id) = handler("a\nb\nc\n");
+        let diagnostic = handler
+            .diagnostic(Severity::Error)
+            .with_message("expansion failed")
+            .with_expansion_backtrace(vec![span(id, 4, 5), span(id, 2, 3), span(id, 0, 1)])
+            .take();
+        assert_eq!(
+            diagnostic.notes,
+            [
+                "#0: in this expansion at <test.masm>:3:1",
+                "#1: in this expansion at <test.masm>:2:1",
+                "#2: in this expansion at <test.masm>:1:1",
+            ]
+        );
+    }
+
+    #[test]
+    fn secondary_labels_from_items() {
+        let (handler, id) = handler("fn a() {}\nfn a() {}\nfn a() {}\n");
+        let items = [span(id, 3, 4), span(id, 13, 14), span(id, 23, 24)];
+        let diagnostic = handler
+            .diagnostic(Severity::Error)
+            .with_message("conflicting declarations")
+            .with_secondary_labels_from(items.iter(), |i| format!("declaration #{}", i))
+            .take();
+
+        assert_eq!(diagnostic.labels.len(), 3);
+        for (i, (label, span)) in diagnostic.labels.iter().zip(items).enumerate() {
+            assert_eq!(label.style, LabelStyle::Secondary);
+            assert_eq!(label.file_id, id);
+            assert_eq!(label.range, std::ops::Range::<usize>::from(span));
+            assert_eq!(label.message, format!("declaration #{}", i));
+        }
+    }
+
+    #[test]
+    fn fingerprint_ignores_line_numbers() {
+        let codemap = CodeMap::new();
+        let before = codemap.add("before.masm", "begin\n  add.x\nend\n".to_string());
+        let after = codemap.add(
+            "after.masm",
+            "# moved\nbegin\n\n    add.x\nend\n".to_string(),
+        );
+        let diagnostic = |id, start, end| {
+            Diagnostic::error()
+                .with_code("E001")
+                .with_message("invalid immediate")
+                .with_labels(vec![Label::primary(id, span(id, start, end))])
+        };
+        let original = fingerprint(&codemap, &diagnostic(before, 12, 13));
+        assert_eq!(fingerprint(&codemap, &diagnostic(after, 23, 24)), original);
+        assert_ne!(
+            fingerprint(&codemap, &diagnostic(before, 0, 5)),
+            original,
+            "the content of the primary line should be part of the fingerprint"
+        );
+        assert_ne!(
+            fingerprint(
+                &codemap,
+                &diagnostic(before, 12, 13).with_message("another message")
+            ),
+            original
+        );
+    }
+}
+
+/// Data attached to an [InFlightDiagnostic] which is not part of the [Diagnostic] model,
+/// but which is needed by the [DiagnosticsHandler] when emitting it.
+#[derive(Default)]
+pub(crate) struct Attachments {
+    /// Overrides the configured display style
+    pub display_style: Option<DisplayStyle>,
+    /// Whether the diagnostic is caused by the code being compiled, or by the tool itself
+    pub origin: DiagnosticOrigin,
+    /// Child diagnostics, rendered beneath the parent
+    pub children: Vec<DiagnosticTree>,
+    /// Suggested fixes, rendered as diffs beneath the parent
+    pub suggestions: Vec<Suggestion>,
+    /// Opaque data attached to labels, for use by custom emitters
+    pub annotations: Vec<LabelAnnotation>,
+    /// The error from which the diagnostic was produced, see [DiagnosticsHandler::emit_error]
+    pub source: Option<Arc<dyn std::error::Error + Send + Sync>>,
+}
+
+/// The origin of a diagnostic, which distinguishes problems with the code being compiled from
+/// failures of the tool itself, so that they can be counted and reported separately.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum DiagnosticOrigin {
+    /// The diagnostic is caused by the code being compiled, e.g. a type error
+    #[default]
+    User,
+    /// The diagnostic is caused by the environment of the tool, e.g. a file which could not
+    /// be read, or by the tool itself, e.g. an internal compiler error
+    Tool,
+}
+
+/// An opaque value attached to a label of a diagnostic, see
+/// [InFlightDiagnostic::with_label_annotation].
+///
+/// Annotations are ignored by the built-in renderers, but are made available to custom
+/// [Emitter]s via [DiagnosticRecord], e.g. to tint the underline of a label by a
+/// confidence value.
+#[derive(Clone)]
+pub struct LabelAnnotation {
+    /// The index of the annotated label in the labels of the diagnostic
+    pub label: usize,
+    /// The annotation value
+    pub value: Arc<dyn Any + Send + Sync>,
+}
+impl LabelAnnotation {
+    /// Returns the annotation value as a `T`, if it is one
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.value.downcast_ref::<T>()
+    }
+
+    /// Returns the annotation value as JSON, if it is a [serde_json::Value]
+    #[cfg(feature = "serde")]
+    pub fn as_json(&self) -> Option<&serde_json::Value> {
+        self.downcast_ref::<serde_json::Value>()
+    }
+}
+impl fmt::Debug for LabelAnnotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LabelAnnotation")
+            .field("label", &self.label)
+            .finish_non_exhaustive()
     }
 }
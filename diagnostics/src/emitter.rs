@@ -1,6 +1,12 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
 use parking_lot::Mutex;
 
 use crate::term::termcolor::*;
+use crate::{CodeMap, Diagnostic, Severity};
 
 /// The [Emitter] trait is used for controlling how diagnostics are displayed.
 ///
@@ -10,11 +16,85 @@ use crate::term::termcolor::*;
 /// When a diagnostic is being emitted, a new [Buffer] is allocated,
 /// the diagnostic is rendered into it, and then the buffer is passed
 /// to `print` for display by the [Emitter] implementation.
+///
+/// Alternatively, an [Emitter] may handle diagnostics in structured form, e.g. to
+/// serialize them, by implementing `emit_structured`.
 pub trait Emitter: Send + Sync {
     /// Construct a new [Buffer] for use by the renderer
     fn buffer(&self) -> Buffer;
     /// Display the contents of the given [Buffer]
     fn print(&self, buffer: Buffer) -> std::io::Result<()>;
+    /// Emit the given diagnostic in structured form, rather than having it rendered
+    ///
+    /// Returns `Ok(false)` if this [Emitter] does not handle structured diagnostics, in
+    /// which case the diagnostic is rendered to a [Buffer] and passed to `print` instead.
+    /// This is the default behavior.
+    fn emit_structured(
+        &self,
+        _codemap: &CodeMap,
+        _record: &DiagnosticRecord<'_>,
+    ) -> std::io::Result<bool> {
+        Ok(false)
+    }
+    /// Display the contents of the given [Buffer], containing a rendered diagnostic of the
+    /// given `severity`
+    ///
+    /// This is used instead of `print` for diagnostics, so that emitters can take the
+    /// severity into account, e.g. when forwarding to a logging framework. By default,
+    /// it delegates to `print`.
+    fn print_diagnostic(&self, _severity: Severity, buffer: Buffer) -> std::io::Result<()> {
+        self.print(buffer)
+    }
+
+    /// Returns true if buffers produced by this [Emitter] will render with color
+    ///
+    /// This is useful when building messages which may choose different glyphs or
+    /// formatting depending on whether color is available. Defaults to false.
+    fn supports_color(&self) -> bool {
+        false
+    }
+}
+impl std::fmt::Debug for dyn Emitter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("dyn Emitter")
+    }
+}
+
+/// A [DiagnosticRecord] is the structured form of a diagnostic being emitted, including
+/// any data attached to it which is not part of the [Diagnostic] itself.
+#[non_exhaustive]
+pub struct DiagnosticRecord<'a> {
+    /// The diagnostic being emitted
+    pub diagnostic: &'a Diagnostic,
+    /// The child diagnostics attached to `diagnostic`, each with its own children, if any,
+    /// see [crate::DiagnosticTree]
+    pub children: &'a [crate::DiagnosticTree],
+    /// The suggested fixes attached to `diagnostic`
+    pub suggestions: &'a [crate::Suggestion],
+    /// The opaque annotations attached to the labels of `diagnostic`
+    pub annotations: &'a [crate::LabelAnnotation],
+    /// The compiler pass during which the diagnostic was emitted, if known,
+    /// see [crate::DiagnosticsHandler::in_pass]
+    pub pass: Option<&'a str>,
+    pub(crate) handler: &'a crate::DiagnosticsHandler,
+    pub(crate) display_style: Option<crate::term::DisplayStyle>,
+}
+impl<'a> DiagnosticRecord<'a> {
+    /// Renders this diagnostic without color, exactly as the [crate::DiagnosticsHandler]
+    /// emitting it would have rendered it for an [Emitter] which does not handle structured
+    /// diagnostics, i.e. using the same display configuration, suggestions, and children.
+    pub fn render(&self) -> String {
+        let mut buffer = Buffer::no_color();
+        self.handler.render_with_attachments(
+            &mut buffer,
+            self.diagnostic.clone(),
+            self.display_style.clone(),
+            self.suggestions,
+            self.children,
+            self.pass,
+        );
+        String::from_utf8_lossy(buffer.as_slice()).into_owned()
+    }
 }
 
 /// [DefaultEmitter] is used for rendering to stderr, and as is implied
@@ -39,6 +119,11 @@ impl Emitter for DefaultEmitter {
     fn print(&self, buffer: Buffer) -> std::io::Result<()> {
         self.writer.print(&buffer)
     }
+
+    #[inline]
+    fn supports_color(&self) -> bool {
+        self.writer.buffer().supports_color()
+    }
 }
 
 /// [CaptureEmitter] is used to capture diagnostics which are emitted, for later examination.
@@ -60,6 +145,21 @@ impl CaptureEmitter {
         let buf = self.buffer.lock();
         String::from_utf8_lossy(buf.as_slice()).into_owned()
     }
+
+    /// Like `captured`, but with paths relative to `root` rather than absolute, by stripping
+    /// any occurrences of `root` followed by a path separator.
+    ///
+    /// This is intended for snapshot testing, where the captured output must be the same
+    /// regardless of where the project is located on a given machine.
+    pub fn captured_normalized(&self, root: &Path) -> String {
+        let captured = self.captured();
+        let root = root.to_string_lossy();
+        let root = root.trim_end_matches(std::path::MAIN_SEPARATOR);
+        if root.is_empty() {
+            return captured;
+        }
+        captured.replace(&format!("{}{}", root, std::path::MAIN_SEPARATOR), "")
+    }
 }
 impl Emitter for CaptureEmitter {
     #[inline]
@@ -108,4 +208,492 @@ impl Emitter for NullEmitter {
     fn print(&self, _buffer: Buffer) -> std::io::Result<()> {
         Ok(())
     }
+
+    #[inline(always)]
+    fn supports_color(&self) -> bool {
+        self.ansi
+    }
+}
+
+/// [CallbackEmitter] passes rendered output to a callback, rather than writing it anywhere.
+///
+/// The callback receives the severity of each diagnostic, or `None` for other output, e.g.
+/// from [crate::DiagnosticsHandler::info], along with the text rendered without color. This
+/// makes it easy to forward diagnostics to a logging framework, such as `tracing`:
+///
+/// ```ignore
+/// let emitter = CallbackEmitter::new(|severity, rendered| match severity {
+///     Some(Severity::Error | Severity::Bug) => tracing::error!("{}", rendered),
+///     Some(Severity::Warning) => tracing::warn!("{}", rendered),
+///     _ => tracing::info!("{}", rendered),
+/// });
+/// ```
+pub struct CallbackEmitter {
+    callback: Box<Callback>,
+}
+type Callback = dyn Fn(Option<Severity>, &str) + Send + Sync;
+impl CallbackEmitter {
+    /// Create a new [CallbackEmitter] which passes all output to `callback`
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(Option<Severity>, &str) + Send + Sync + 'static,
+    {
+        Self {
+            callback: Box::new(callback),
+        }
+    }
+}
+impl Emitter for CallbackEmitter {
+    #[inline]
+    fn buffer(&self) -> Buffer {
+        Buffer::no_color()
+    }
+
+    fn print(&self, buffer: Buffer) -> std::io::Result<()> {
+        (self.callback)(None, &String::from_utf8_lossy(buffer.as_slice()));
+        Ok(())
+    }
+
+    fn print_diagnostic(&self, severity: Severity, buffer: Buffer) -> std::io::Result<()> {
+        (self.callback)(Some(severity), &String::from_utf8_lossy(buffer.as_slice()));
+        Ok(())
+    }
+}
+
+/// [TracingEmitter] emits rendered output as `tracing` events, rather than writing it anywhere,
+/// so that diagnostics are handled by the subscribers and filters of the application.
+///
+/// Output is rendered without color. Each diagnostic is emitted at the level corresponding to
+/// its severity, i.e. errors and bugs at `ERROR`, warnings at `WARN`, and notes and help at
+/// `INFO`. Other output, e.g. from [crate::DiagnosticsHandler::info], is emitted at `INFO`.
+///
+/// This is only available with the `tracing` feature.
+#[cfg(feature = "tracing")]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct TracingEmitter;
+#[cfg(feature = "tracing")]
+impl Emitter for TracingEmitter {
+    #[inline]
+    fn buffer(&self) -> Buffer {
+        Buffer::no_color()
+    }
+
+    fn print(&self, buffer: Buffer) -> std::io::Result<()> {
+        let rendered = String::from_utf8_lossy(buffer.as_slice());
+        tracing::info!("{}", rendered.trim_end());
+        Ok(())
+    }
+
+    fn print_diagnostic(&self, severity: Severity, buffer: Buffer) -> std::io::Result<()> {
+        let rendered = String::from_utf8_lossy(buffer.as_slice());
+        let rendered = rendered.trim_end();
+        match severity {
+            Severity::Bug | Severity::Error => tracing::error!("{}", rendered),
+            Severity::Warning => tracing::warn!("{}", rendered),
+            Severity::Note | Severity::Help => tracing::info!("{}", rendered),
+        }
+        Ok(())
+    }
+}
+
+/// [RingBufferEmitter] retains the most recently emitted diagnostics in memory, while passing
+/// all output through to another [Emitter] for display.
+///
+/// This is intended for post-mortem debugging, e.g. a panic hook can call `dump` to report the
+/// diagnostics which preceded a crash. Only the `capacity` most recent diagnostics are kept,
+/// with older ones discarded as new ones arrive. Other output, e.g. from
+/// [crate::DiagnosticsHandler::info], is not retained.
+///
+/// Since diagnostics must be rendered in order to be retained, the wrapped emitter is never
+/// asked to handle them in structured form, i.e. its `emit_structured` is not called.
+pub struct RingBufferEmitter {
+    inner: Arc<dyn Emitter>,
+    capacity: usize,
+    retained: Mutex<VecDeque<String>>,
+}
+impl RingBufferEmitter {
+    /// Create a new [RingBufferEmitter] which retains up to `capacity` diagnostics, and passes
+    /// all output through to `inner`
+    pub fn new(inner: Arc<dyn Emitter>, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            retained: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Returns the retained diagnostics, rendered without color, from oldest to newest
+    pub fn dump(&self) -> Vec<String> {
+        self.retained.lock().iter().cloned().collect()
+    }
+}
+impl Emitter for RingBufferEmitter {
+    #[inline]
+    fn buffer(&self) -> Buffer {
+        self.inner.buffer()
+    }
+
+    #[inline]
+    fn print(&self, buffer: Buffer) -> std::io::Result<()> {
+        self.inner.print(buffer)
+    }
+
+    fn print_diagnostic(&self, severity: Severity, buffer: Buffer) -> std::io::Result<()> {
+        if self.capacity > 0 {
+            let rendered = strip_ansi(&String::from_utf8_lossy(buffer.as_slice()));
+            let mut retained = self.retained.lock();
+            if retained.len() == self.capacity {
+                retained.pop_front();
+            }
+            retained.push_back(rendered);
+        }
+        self.inner.print_diagnostic(severity, buffer)
+    }
+
+    #[inline]
+    fn supports_color(&self) -> bool {
+        self.inner.supports_color()
+    }
+}
+
+/// Statistics about the output of a [MeteringEmitter]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct EmitterStats {
+    /// The number of diagnostics emitted, including those handled in structured form
+    pub diagnostics: usize,
+    /// The number of other messages printed, e.g. by [crate::DiagnosticsHandler::info]
+    pub messages: usize,
+    /// The total number of bytes of rendered output, including any color codes
+    pub bytes: usize,
+}
+
+/// [MeteringEmitter] passes all output through to another [Emitter], while measuring how
+/// much output was produced, see `stats`.
+///
+/// Unlike the diagnostic counts of a [crate::DiagnosticsHandler], this only reflects what
+/// actually reached the emitter, i.e. after filtering, suppression, and throttling. Bytes are
+/// only counted for rendered output, so diagnostics handled by the wrapped emitter in
+/// structured form contribute to the number of diagnostics, but not the number of bytes.
+pub struct MeteringEmitter {
+    inner: Arc<dyn Emitter>,
+    diagnostics: AtomicUsize,
+    messages: AtomicUsize,
+    bytes: AtomicUsize,
+}
+impl MeteringEmitter {
+    /// Create a new [MeteringEmitter] which passes all output through to `inner`
+    pub fn new(inner: Arc<dyn Emitter>) -> Self {
+        Self {
+            inner,
+            diagnostics: AtomicUsize::new(0),
+            messages: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the statistics gathered so far
+    pub fn stats(&self) -> EmitterStats {
+        EmitterStats {
+            diagnostics: self.diagnostics.load(Ordering::Relaxed),
+            messages: self.messages.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+impl Emitter for MeteringEmitter {
+    #[inline]
+    fn buffer(&self) -> Buffer {
+        self.inner.buffer()
+    }
+
+    fn print(&self, buffer: Buffer) -> std::io::Result<()> {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.bytes
+            .fetch_add(buffer.as_slice().len(), Ordering::Relaxed);
+        self.inner.print(buffer)
+    }
+
+    fn emit_structured(
+        &self,
+        codemap: &CodeMap,
+        record: &DiagnosticRecord<'_>,
+    ) -> std::io::Result<bool> {
+        let handled = self.inner.emit_structured(codemap, record)?;
+        if handled {
+            self.diagnostics.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(handled)
+    }
+
+    fn print_diagnostic(&self, severity: Severity, buffer: Buffer) -> std::io::Result<()> {
+        self.diagnostics.fetch_add(1, Ordering::Relaxed);
+        self.bytes
+            .fetch_add(buffer.as_slice().len(), Ordering::Relaxed);
+        self.inner.print_diagnostic(severity, buffer)
+    }
+
+    #[inline]
+    fn supports_color(&self) -> bool {
+        self.inner.supports_color()
+    }
+}
+
+/// Removes any ANSI escape sequences, e.g. color codes, from `input`
+///
+/// This is useful when writing assertions about rendered output which may have been
+/// produced with color enabled.
+pub fn strip_ansi(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\u{1b}' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            // Control sequence, terminated by a byte in the range `@` to `~`
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // Operating system command, terminated by BEL or ST (i.e. `ESC \`)
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\u{7}' {
+                        break;
+                    }
+                    if c == '\u{1b}' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            // Any other escape sequence consists of a single character
+            _ => (),
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn supports_color() {
+        assert!(!DefaultEmitter::new(ColorChoice::Never).supports_color());
+        assert!(!NullEmitter::new(ColorChoice::Never).supports_color());
+        assert!(NullEmitter::new(ColorChoice::Always).supports_color());
+        assert!(!CaptureEmitter::new().supports_color());
+
+        let handler = DiagnosticsHandler::new(
+            DiagnosticsConfig::default(),
+            Arc::new(CodeMap::new()),
+            Arc::new(NullEmitter::new(ColorChoice::Always)),
+        );
+        assert!(handler.supports_color());
+    }
+
+    #[test]
+    fn captured_normalized_strips_root() {
+        let root = std::env::temp_dir().join("project");
+        let codemap = Arc::new(CodeMap::new());
+        let id = codemap.add(
+            root.join("src").join("main.masm"),
+            "begin\nend\n".to_string(),
+        );
+        let emitter = Arc::new(CaptureEmitter::new());
+        let handler =
+            DiagnosticsHandler::new(DiagnosticsConfig::default(), codemap, emitter.clone());
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("unexpected end")
+            .with_primary_span(SourceSpan::new(
+                SourceIndex::new(id, ByteIndex(6)),
+                SourceIndex::new(id, ByteIndex(9)),
+            ))
+            .emit();
+
+        let expected = Path::new("src").join("main.masm");
+        let expected = format!("┌─ {}:2:1\n", expected.display());
+        assert!(!emitter.captured().contains(&expected));
+        let normalized = emitter.captured_normalized(&root);
+        assert!(normalized.contains(&expected), "{}", normalized);
+        assert!(
+            !normalized.contains(&*root.to_string_lossy()),
+            "{}",
+            normalized
+        );
+        // A trailing separator on the root makes no difference
+        let mut trailing = root.into_os_string();
+        trailing.push(std::path::MAIN_SEPARATOR.to_string());
+        assert_eq!(
+            emitter.captured_normalized(Path::new(&trailing)),
+            normalized
+        );
+    }
+
+    #[test]
+    fn strip_ansi_removes_escape_sequences() {
+        use std::io::Write;
+
+        assert_eq!(
+            strip_ansi("\u{1b}[1;31merror\u{1b}[0m: \u{1b}[1mbad\u{1b}[0m"),
+            "error: bad"
+        );
+        assert_eq!(
+            strip_ansi("\u{1b}]8;;https://example.com\u{7}link\u{1b}]8;;\u{1b}\\ text"),
+            "link text"
+        );
+        assert_eq!(strip_ansi("plain │ text"), "plain │ text");
+
+        let mut buffer = Buffer::ansi();
+        buffer
+            .set_color(ColorSpec::new().set_fg(Some(Color::Red)).set_bold(true))
+            .unwrap();
+        write!(buffer, "warning").unwrap();
+        buffer.reset().unwrap();
+        writeln!(buffer, ": unused").unwrap();
+        let colored = String::from_utf8(buffer.into_inner()).unwrap();
+        assert_ne!(colored, "warning: unused\n");
+        assert_eq!(strip_ansi(&colored), "warning: unused\n");
+    }
+
+    #[test]
+    fn callback_receives_severity() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let emitter = {
+            let received = received.clone();
+            CallbackEmitter::new(move |severity, rendered| {
+                received.lock().push((severity, rendered.to_string()))
+            })
+        };
+        let handler = DiagnosticsHandler::new(
+            DiagnosticsConfig::default(),
+            Arc::new(CodeMap::new()),
+            Arc::new(emitter),
+        );
+        handler.error("something went wrong");
+        handler.info("just so you know");
+
+        let received = received.lock();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].0, Some(Severity::Error));
+        assert!(received[0].1.contains("something went wrong"));
+        assert_eq!(received[1].0, None);
+        assert!(received[1].1.contains("just so you know"));
+    }
+
+    #[test]
+    fn ring_buffer_retains_most_recent() {
+        let inner = Arc::new(CaptureEmitter::new());
+        let emitter = Arc::new(RingBufferEmitter::new(inner.clone(), 2));
+        let handler = DiagnosticsHandler::new(
+            DiagnosticsConfig::default(),
+            Arc::new(CodeMap::new()),
+            emitter.clone(),
+        );
+        handler.error("first");
+        handler.info("not retained");
+        handler.error("second");
+        handler.error("third");
+
+        let dumped = emitter.dump();
+        assert_eq!(dumped.len(), 2);
+        assert!(dumped[0].contains("second"));
+        assert!(dumped[1].contains("third"));
+        let captured = inner.captured();
+        for message in ["first", "not retained", "second", "third"] {
+            assert!(captured.contains(message), "{}", message);
+        }
+    }
+
+    #[test]
+    fn metering_counts_output() {
+        let inner = Arc::new(CaptureEmitter::new());
+        let emitter = Arc::new(MeteringEmitter::new(inner.clone()));
+        let handler = DiagnosticsHandler::new(
+            DiagnosticsConfig::default(),
+            Arc::new(CodeMap::new()),
+            emitter.clone(),
+        );
+        assert_eq!(emitter.stats(), EmitterStats::default());
+        handler.error("first");
+        handler.warn("second");
+        handler.info("status");
+
+        let stats = emitter.stats();
+        assert_eq!(stats.diagnostics, 2);
+        assert_eq!(stats.messages, 1);
+        assert_eq!(stats.bytes, inner.captured().len());
+        assert!(stats.bytes > 0);
+    }
+
+    #[test]
+    #[cfg(feature = "tracing")]
+    fn tracing_emitter_uses_severity_level() {
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Level, Metadata, Subscriber};
+
+        /// Records the level and message of each event
+        struct TestSubscriber(Arc<Mutex<Vec<(Level, String)>>>);
+        impl Subscriber for TestSubscriber {
+            fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _span: &Attributes<'_>) -> Id {
+                Id::from_u64(1)
+            }
+            fn record(&self, _span: &Id, _values: &Record<'_>) {}
+            fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+            fn event(&self, event: &Event<'_>) {
+                struct Message(String);
+                impl tracing::field::Visit for Message {
+                    fn record_debug(
+                        &mut self,
+                        field: &tracing::field::Field,
+                        value: &dyn std::fmt::Debug,
+                    ) {
+                        if field.name() == "message" {
+                            self.0 = format!("{:?}", value);
+                        }
+                    }
+                }
+                let mut message = Message(String::new());
+                event.record(&mut message);
+                self.0.lock().push((*event.metadata().level(), message.0));
+            }
+            fn enter(&self, _span: &Id) {}
+            fn exit(&self, _span: &Id) {}
+        }
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = TestSubscriber(events.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let handler = DiagnosticsHandler::new(
+                DiagnosticsConfig::default(),
+                Arc::new(CodeMap::new()),
+                Arc::new(TracingEmitter),
+            );
+            handler.error("undefined variable `x`");
+            handler.warn("unused import");
+            handler.info("compiling");
+        });
+
+        let events = events.lock();
+        assert_eq!(
+            *events,
+            [
+                (Level::ERROR, "error: undefined variable `x`".to_string()),
+                (Level::WARN, "warning: unused import".to_string()),
+                (Level::INFO, "info: compiling".to_string()),
+            ]
+        );
+    }
 }
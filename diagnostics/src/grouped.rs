@@ -0,0 +1,100 @@
+use crate::term::termcolor::Buffer;
+use crate::term::DisplayStyle;
+use crate::*;
+
+/// Renders `diagnostics` to a string as a report grouped by file, without color.
+///
+/// Diagnostics are grouped by the file of their primary label, with groups ordered by the
+/// first diagnostic referencing each file, and diagnostics within a group kept in their
+/// original order. The name of each file is printed once, as a header above its group, and
+/// is omitted from the locations of the diagnostics beneath it, e.g. `┌─ 3:5` rather than
+/// `┌─ src/main.masm:3:5`. Labels in other files are rendered as usual. Diagnostics without
+/// a primary label are rendered last, without a header.
+pub fn render_grouped(
+    codemap: &CodeMap,
+    diagnostics: &[Diagnostic],
+    config: &crate::term::Config,
+) -> String {
+    let mut groups = Vec::<(Option<SourceId>, Vec<&Diagnostic>)>::new();
+    for diagnostic in diagnostics {
+        let file_id = primary_span(diagnostic).map(|span| span.source_id());
+        match groups.iter_mut().find(|(id, _)| *id == file_id) {
+            Some((_, group)) => group.push(diagnostic),
+            None => groups.push((file_id, vec![diagnostic])),
+        }
+    }
+    // Diagnostics without a primary label go last
+    groups.sort_by_key(|(file_id, _)| file_id.is_none());
+
+    let mut out = String::new();
+    for (file_id, group) in groups {
+        let name = file_id.and_then(|file_id| Files::name(codemap, file_id).ok());
+        if let Some(name) = name.as_deref() {
+            out.push_str(name);
+            out.push_str(":\n");
+        }
+        for diagnostic in group {
+            let mut buffer = Buffer::no_color();
+            crate::term::emit(&mut buffer, config, codemap, diagnostic).unwrap();
+            let rendered = String::from_utf8_lossy(buffer.as_slice());
+            match name.as_deref() {
+                Some(name) => strip_file_name(&mut out, &rendered, name, config),
+                None => out.push_str(&rendered),
+            }
+        }
+    }
+    out
+}
+
+/// Appends `rendered` to `out`, omitting `name` from the locations it contains
+fn strip_file_name(out: &mut String, rendered: &str, name: &str, config: &crate::term::Config) {
+    let locus = format!("{} {}:", config.chars.snippet_start, name);
+    let short_locus = format!("{}:", name);
+    for line in rendered.split_inclusive('\n') {
+        if let Some(i) = line.find(&locus) {
+            out.push_str(&line[..i]);
+            out.push_str(&config.chars.snippet_start);
+            out.push(' ');
+            out.push_str(&line[(i + locus.len())..]);
+        } else if matches!(config.display_style, DisplayStyle::Short) {
+            out.push_str(line.strip_prefix(&short_locus).unwrap_or(line));
+        } else {
+            out.push_str(line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(id: SourceId, start: u32, end: u32, message: &str) -> Diagnostic {
+        let span = SourceSpan::new(
+            SourceIndex::new(id, ByteIndex(start)),
+            SourceIndex::new(id, ByteIndex(end)),
+        );
+        Diagnostic::error()
+            .with_message(message)
+            .with_labels(vec![Label::primary(id, span)])
+    }
+
+    #[test]
+    fn file_headers_are_printed_once() {
+        let codemap = CodeMap::new();
+        let a = codemap.add("a.masm", "push.1\nadd\nmul\n".to_string());
+        let b = codemap.add("b.masm", "drop\n".to_string());
+        let diagnostics = vec![
+            error(a, 0, 6, "first"),
+            error(b, 0, 4, "second"),
+            error(a, 7, 10, "third"),
+            error(a, 11, 14, "fourth"),
+        ];
+        let rendered = render_grouped(&codemap, &diagnostics, &crate::term::Config::default());
+        assert_eq!(rendered.matches("<a.masm>").count(), 1);
+        assert_eq!(rendered.matches("<b.masm>").count(), 1);
+        assert_eq!(
+            rendered,
+            "<a.masm>:\nerror: first\n  ┌─ 1:1\n  │\n1 │ push.1\n  │ ^^^^^^\n\nerror: third\n  ┌─ 2:1\n  │\n2 │ add\n  │ ^^^\n\nerror: fourth\n  ┌─ 3:1\n  │\n3 │ mul\n  │ ^^^\n\n<b.masm>:\nerror: second\n  ┌─ 1:1\n  │\n1 │ drop\n  │ ^^^^\n\n"
+        );
+    }
+}
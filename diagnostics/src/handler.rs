@@ -1,9 +1,19 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::io::Write;
-use std::ops::Deref;
+use std::ops::{Deref, Range};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread::ThreadId;
 
-use crate::term::termcolor::{Color, ColorSpec, WriteColor};
+use parking_lot::{Mutex, RwLock};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::codemap::expand_tabs;
+use crate::diagnostic::Attachments;
+use crate::term::termcolor::{Buffer, Color, ColorChoice, ColorSpec, WriteColor};
+use crate::term::DisplayStyle;
+use crate::truncate::{truncate_lines, TruncatedSource};
 use crate::*;
 
 /// [DiagnosticsHandler] acts as the nexus point for configuring and
@@ -19,17 +29,46 @@ use crate::*;
 /// to be passed around freely as needed throughout your project.
 pub struct DiagnosticsHandler {
     emitter: Arc<dyn Emitter>,
+    status_emitter: Option<Arc<dyn Emitter>>,
     pub(crate) codemap: Arc<CodeMap>,
     err_count: AtomicUsize,
+    tool_err_count: AtomicUsize,
     verbosity: Verbosity,
     warnings_as_errors: bool,
     no_warn: bool,
+    fail_fast: bool,
     silent: bool,
+    min_severity: Option<Severity>,
+    show_byte_offsets: bool,
+    merge_adjacent_labels: bool,
+    inline_suggestions: bool,
+    line_base: usize,
+    line_number_offset: i64,
+    column_base: usize,
+    error_footer: Option<String>,
+    footer_min_severity: Severity,
+    /// Only set if the labels differ from the defaults, as they must be substituted when rendering
+    severity_labels: Option<SeverityLabels>,
+    wrap_width: Option<usize>,
+    max_label_width: Option<usize>,
+    min_code_width: Option<usize>,
+    max_gap_lines: Option<usize>,
+    program_name: Option<String>,
     pub(crate) display: crate::term::Config,
+    lint_levels: BTreeMap<String, LintLevel>,
+    lints: RwLock<LintRegistry>,
+    suppressions: RwLock<Suppressions>,
+    throttles: Mutex<FxHashMap<String, Throttle>>,
+    passes: Mutex<FxHashMap<ThreadId, Vec<String>>>,
+    once_keys: Mutex<FxHashSet<String>>,
+    recorded: Option<Mutex<Recorded>>,
+    #[cfg(feature = "test-util")]
+    pub(crate) captured: Option<Arc<crate::testing::CapturedDiagnostics>>,
 }
 
 // We can safely implement these traits for DiagnosticsHandler,
-// as the only two non-atomic fields are read-only after creation
+// as the non-atomic fields are either read-only after creation,
+// or are guarded by a lock
 unsafe impl Send for DiagnosticsHandler {}
 unsafe impl Sync for DiagnosticsHandler {}
 
@@ -44,27 +83,211 @@ impl DiagnosticsHandler {
         let no_warn = config.no_warn || config.verbosity > Verbosity::Warning;
         Self {
             emitter,
+            status_emitter: config.status_emitter,
             codemap,
             err_count: AtomicUsize::new(0),
+            tool_err_count: AtomicUsize::new(0),
             verbosity: config.verbosity,
             warnings_as_errors: config.warnings_as_errors,
             no_warn,
+            fail_fast: config.fail_fast,
             silent: config.verbosity == Verbosity::Silent,
+            min_severity: config.min_severity,
+            show_byte_offsets: config.show_byte_offsets,
+            merge_adjacent_labels: config.merge_adjacent_labels,
+            inline_suggestions: config.inline_suggestions,
+            line_base: config.one_based_lines as usize,
+            line_number_offset: config.line_number_offset.unwrap_or(0),
+            column_base: config.one_based_columns as usize,
+            error_footer: config.error_footer,
+            footer_min_severity: config.footer_min_severity,
+            severity_labels: Some(config.severity_labels)
+                .filter(|labels| labels != &SeverityLabels::default()),
+            wrap_width: config.wrap_width,
+            max_label_width: config.max_label_width,
+            min_code_width: config.min_code_width,
+            max_gap_lines: config.max_gap_lines,
+            program_name: config.program_name,
             display: config.display,
+            lint_levels: config.lint_levels,
+            lints: RwLock::new(LintRegistry::default()),
+            suppressions: RwLock::new(Suppressions::default()),
+            throttles: Mutex::new(FxHashMap::default()),
+            passes: Mutex::new(FxHashMap::default()),
+            once_keys: Mutex::new(FxHashSet::default()),
+            recorded: config
+                .record_diagnostics
+                .then(|| Mutex::new(Recorded::default())),
+            #[cfg(feature = "test-util")]
+            captured: None,
         }
     }
 
+    /// Create a new [DiagnosticsHandler] from the given [DiagnosticsConfig] and [CodeMap],
+    /// with an [Emitter] selected according to the configuration.
+    ///
+    /// If the verbosity is [Verbosity::Silent], a [NullEmitter] is used, otherwise a
+    /// [DefaultEmitter] which writes to stderr, using color as specified by `color`. With
+    /// [ColorChoice::Auto], color is only used if stderr is a terminal. Use `new` to provide
+    /// a custom [Emitter] instead.
+    pub fn from_config(config: DiagnosticsConfig, codemap: Arc<CodeMap>) -> Self {
+        let color = match config.color {
+            ColorChoice::Auto if !atty::is(atty::Stream::Stderr) => ColorChoice::Never,
+            color => color,
+        };
+        let emitter: Arc<dyn Emitter> = match config.verbosity {
+            Verbosity::Silent => Arc::new(NullEmitter::new(color)),
+            _ => Arc::new(DefaultEmitter::new(color)),
+        };
+        Self::new(config, codemap, emitter)
+    }
+
     /// Get the [SourceId] corresponding to the given `filename`
     pub fn lookup_file_id(&self, filename: impl Into<FileName>) -> Option<SourceId> {
         let filename = filename.into();
         self.codemap.get_file_id(&filename)
     }
 
+    /// Returns the user-facing `(line, column)` numbers at which `spanned` starts
+    ///
+    /// Unlike [CodeMap::location], which returns 0-based indices, the numbers returned here
+    /// follow the `one_based_lines` and `one_based_columns` settings of [DiagnosticsConfig],
+    /// i.e. they match what is displayed in rendered diagnostics.
+    pub fn display_location<S: Spanned>(&self, spanned: &S) -> Result<(usize, usize), Error> {
+        let span = spanned.span();
+        let loc = self
+            .files()
+            .location(span.source_id(), span.start_index().to_usize())?;
+        Ok((loc.line_number, loc.column_number))
+    }
+
+    #[inline]
+    fn files(&self) -> DisplayFiles<'_> {
+        DisplayFiles {
+            codemap: self.codemap.deref(),
+            line_base: self.line_base,
+            line_number_offset: self.line_number_offset,
+            column_base: self.column_base,
+            gutter_digits: None,
+            truncated: vec![],
+        }
+    }
+
+    /// Returns true if diagnostics emitted by this handler are rendered with color
+    ///
+    /// See [Emitter::supports_color].
+    pub fn supports_color(&self) -> bool {
+        self.emitter.supports_color()
+    }
+
     /// Returns true if the [DiagnosticsHandler] has emitted any error diagnostics
+    ///
+    /// Diagnostics with [Severity::Bug] count as errors, as well as those with
+    /// [Severity::Error], so this also returns true after an internal compiler error.
     pub fn has_errors(&self) -> bool {
         self.err_count.load(Ordering::Relaxed) > 0
     }
 
+    /// Returns the number of errors emitted by this handler which are caused by the code being
+    /// compiled, i.e. those with [DiagnosticOrigin::User]
+    pub fn user_error_count(&self) -> usize {
+        self.err_count
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.tool_error_count())
+    }
+
+    /// Returns the number of errors emitted by this handler which are caused by the tool or
+    /// its environment, i.e. those with [DiagnosticOrigin::Tool]
+    ///
+    /// Diagnostics with [Severity::Bug] are always counted as tool errors, regardless of
+    /// their origin.
+    pub fn tool_error_count(&self) -> usize {
+        self.tool_err_count.load(Ordering::Relaxed)
+    }
+
+    /// Resets the diagnostic counts of this handler, as if no diagnostics had been emitted
+    ///
+    /// This is intended for reusing a handler across independent compilations, e.g. each line
+    /// evaluated by a REPL, so that `has_errors` reflects only the current one. The counts used
+    /// for throttling are also reset. This only affects counters, output which has already
+    /// been emitted is unaffected.
+    pub fn reset_counts(&self) {
+        self.err_count.store(0, Ordering::Relaxed);
+        self.tool_err_count.store(0, Ordering::Relaxed);
+        for throttle in self.throttles.lock().values_mut() {
+            throttle.emitted = 0;
+        }
+    }
+
+    /// Adds the diagnostic counts of `other` to those of this handler
+    ///
+    /// This is intended for sub-compilations which report via handlers of their own, so that
+    /// the handler of the parent compilation reflects their errors as well, e.g. in `has_errors`
+    /// and `exit_code`. The counts of `other` are unaffected, so merging the same handler twice
+    /// counts its errors twice.
+    pub fn merge_counts_from(&self, other: &DiagnosticsHandler) {
+        self.err_count
+            .fetch_add(other.err_count.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.tool_err_count.fetch_add(
+            other.tool_err_count.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Takes the diagnostics recorded since the last call, in the order they were emitted
+    ///
+    /// Diagnostics are only recorded if `record_diagnostics` of [DiagnosticsConfig] is set,
+    /// otherwise this always returns an empty vector. Each [RecordedDiagnostic] carries its
+    /// sequence number and the thread which emitted it, so that output produced by parallel
+    /// compilation can be reordered deterministically, e.g. by file and source position.
+    pub fn take_recorded(&self) -> Vec<RecordedDiagnostic> {
+        match self.recorded.as_ref() {
+            Some(recorded) => core::mem::take(&mut recorded.lock().diagnostics),
+            None => vec![],
+        }
+    }
+
+    /// Captures the current diagnostic counts, so that the diagnostics emitted by a
+    /// sub-operation can later be queried independently of those emitted before it.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            errors: self.err_count.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Begins a [DiagnosticTransaction], which buffers diagnostics until it is committed
+    ///
+    /// This is intended for speculative work, e.g. a parser which tries an alternative and
+    /// may backtrack, in which case the diagnostics it produced along the way are discarded.
+    pub fn begin_transaction(&self) -> DiagnosticTransaction<'_> {
+        DiagnosticTransaction {
+            handler: self,
+            pending: vec![],
+        }
+    }
+
+    /// Returns a conventional process exit code reflecting the diagnostics emitted so far
+    ///
+    /// The mapping is as follows:
+    ///
+    /// * `0`, if no error diagnostics have been emitted
+    /// * `1`, if one or more error diagnostics have been emitted, see `has_errors`
+    /// * `2`, if one or more diagnostics with [Severity::Bug] or [DiagnosticOrigin::Tool] have
+    ///   been emitted, i.e. an internal compiler error occurred, or the tool otherwise failed.
+    ///   This takes precedence over other errors.
+    ///
+    /// NOTE: Emitting a diagnostic with [Severity::Bug] increments the error count, as well as
+    /// the tool error count, so `has_errors` and `abort_if_errors` treat it as an error.
+    pub fn exit_code(&self) -> i32 {
+        if self.tool_error_count() > 0 {
+            2
+        } else if self.has_errors() {
+            1
+        } else {
+            0
+        }
+    }
+
     /// Triggers a panic if the [DiagnosticsHandler] has emitted any error diagnostics
     #[track_caller]
     pub fn abort_if_errors(&self) {
@@ -73,6 +296,19 @@ impl DiagnosticsHandler {
         }
     }
 
+    /// Returns `Err` if the [DiagnosticsHandler] has emitted any error diagnostics
+    ///
+    /// This is the non-unwinding counterpart to `abort_if_errors`, for use in contexts where
+    /// unwinding is not possible, e.g. across FFI boundaries or when built with `panic = "abort"`,
+    /// allowing the error to be propagated with `?` instead.
+    pub fn check_errors(&self) -> Result<(), FatalError> {
+        if self.has_errors() {
+            Err(FatalError)
+        } else {
+            Ok(())
+        }
+    }
+
     /// Emits an error message and produces a FatalError object
     /// which can be used to terminate execution immediately
     pub fn fatal(&self, err: impl ToString) -> FatalError {
@@ -80,6 +316,180 @@ impl DiagnosticsHandler {
         FatalError
     }
 
+    /// Emits the given diagnostic, and then raises a [FatalError] to terminate execution
+    ///
+    /// This is the structured counterpart to `fatal`, for when the error should be reported
+    /// with labels, notes, etc.
+    pub fn fatal_diagnostic(&self, diagnostic: impl ToDiagnostic) -> ! {
+        self.emit(diagnostic);
+        FatalError.raise()
+    }
+
+    /// Suppresses any diagnostic with the given `code` whose primary label falls within `region`
+    ///
+    /// This is intended to support in-source lint suppression, e.g. `#[allow(...)]`-style
+    /// annotations, where `region` is the span of the item to which the annotation applies.
+    /// Diagnostics without a code, or without a primary label, are never suppressed.
+    pub fn suppress(&self, region: SourceSpan, code: impl Into<String>) {
+        self.suppressions.write().insert(region, code.into());
+    }
+
+    /// Like `suppress`, but registers many `(region, code)` suppressions at once
+    pub fn suppress_all<I, C>(&self, suppressions: I)
+    where
+        I: IntoIterator<Item = (SourceSpan, C)>,
+        C: Into<String>,
+    {
+        let mut guard = self.suppressions.write();
+        for (region, code) in suppressions {
+            guard.insert(region, code.into());
+        }
+    }
+
+    /// Returns true if `diagnostic` has been suppressed via `suppress`
+    fn is_suppressed(&self, diagnostic: &Diagnostic) -> bool {
+        let code = match diagnostic.code.as_deref() {
+            Some(code) => code,
+            None => return false,
+        };
+        match primary_label(diagnostic) {
+            Some(label) => self
+                .suppressions
+                .read()
+                .contains(label.file_id, code, &label.range),
+            None => false,
+        }
+    }
+
+    /// Registers a lint with the [LintRegistry] of this handler
+    ///
+    /// See [LintRegistry::register_lint].
+    pub fn register_lint(
+        &self,
+        name: impl Into<String>,
+        default_level: LintLevel,
+        description: impl ToString,
+    ) {
+        self.lints
+            .write()
+            .register_lint(name, default_level, description);
+    }
+
+    /// Replaces the [LintRegistry] of this handler with `registry`
+    pub fn set_lint_registry(&self, registry: LintRegistry) {
+        *self.lints.write() = registry;
+    }
+
+    /// Returns a copy of the [LintRegistry] of this handler, e.g. to list the known lints
+    pub fn lint_registry(&self) -> LintRegistry {
+        self.lints.read().clone()
+    }
+
+    /// Returns the effective level of the lint named `name`, taking into account the
+    /// `lint_levels` overrides of [DiagnosticsConfig], or `None` if no such lint is registered
+    pub fn lint_level(&self, name: &str) -> Option<LintLevel> {
+        let default_level = self.lints.read().get(name)?.default_level;
+        Some(self.lint_levels.get(name).copied().unwrap_or(default_level))
+    }
+
+    /// Limits the number of diagnostics with the given `code` which are rendered to `limit`
+    ///
+    /// Once the limit is reached, further diagnostics with that code are still counted,
+    /// but are not rendered. Call `finish` once compilation is complete to emit a summary
+    /// of how many were omitted.
+    pub fn throttle(&self, code: impl Into<String>, limit: usize) {
+        self.throttles
+            .lock()
+            .insert(code.into(), Throttle { limit, emitted: 0 });
+    }
+
+    /// Returns true if `diagnostic` should not be rendered due to throttling of its code
+    fn is_throttled(&self, diagnostic: &Diagnostic) -> bool {
+        let code = match diagnostic.code.as_deref() {
+            Some(code) => code,
+            None => return false,
+        };
+        match self.throttles.lock().get_mut(code) {
+            Some(throttle) => {
+                throttle.emitted += 1;
+                throttle.emitted > throttle.limit
+            }
+            None => false,
+        }
+    }
+
+    /// Emits any deferred diagnostics, such as summaries of throttled diagnostics.
+    ///
+    /// This is intended to be called once at the end of compilation. The summaries are written
+    /// directly to the emitter, so unlike other notes, they are not subject to the configured
+    /// verbosity, minimum severity, or suppressions, only to [Verbosity::Silent].
+    pub fn finish(&self) {
+        if self.silent {
+            return;
+        }
+        let mut summaries = self
+            .throttles
+            .lock()
+            .iter()
+            .filter(|(_, throttle)| throttle.emitted > throttle.limit)
+            .map(|(code, throttle)| (code.clone(), throttle.emitted - throttle.limit))
+            .collect::<Vec<_>>();
+        summaries.sort();
+        for (code, omitted) in summaries {
+            let summary = Diagnostic::note().with_message(format!(
+                "... and {} more diagnostics with code {}",
+                omitted, code
+            ));
+            let mut buffer = self.emitter.buffer();
+            self.write_program_name(&mut buffer);
+            self.render(&mut buffer, &self.display, summary);
+            self.emitter
+                .print_diagnostic(Severity::Note, buffer)
+                .unwrap();
+        }
+    }
+
+    /// Runs `f` as the compiler pass named `pass`, tagging diagnostics emitted during it
+    ///
+    /// The pass is tracked per-thread, and passes may be nested, in which case the innermost
+    /// pass is the one used. The pass of a diagnostic is made available to emitters via
+    /// [DiagnosticRecord], and when the verbosity is [Verbosity::Debug], it is rendered as
+    /// a prefix of the message, e.g. `error: [typeck] type mismatch`.
+    pub fn in_pass<F, T>(&self, pass: impl Into<String>, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        let thread = std::thread::current().id();
+        self.passes
+            .lock()
+            .entry(thread)
+            .or_default()
+            .push(pass.into());
+        // Pop the pass even if `f` unwinds, so the stack remains balanced
+        struct PopPass<'a>(&'a DiagnosticsHandler, ThreadId);
+        impl<'a> Drop for PopPass<'a> {
+            fn drop(&mut self) {
+                let mut passes = self.0.passes.lock();
+                if let Some(stack) = passes.get_mut(&self.1) {
+                    stack.pop();
+                    if stack.is_empty() {
+                        passes.remove(&self.1);
+                    }
+                }
+            }
+        }
+        let _pop = PopPass(self, thread);
+        f()
+    }
+
+    /// Returns the name of the innermost pass being run on the current thread, see `in_pass`
+    pub fn current_pass(&self) -> Option<String> {
+        self.passes
+            .lock()
+            .get(&std::thread::current().id())
+            .and_then(|stack| stack.last().cloned())
+    }
+
     /// Report an error diagnostic
     pub fn error(&self, error: impl ToString) {
         let diagnostic = Diagnostic::error().with_message(error.to_string());
@@ -97,19 +507,73 @@ impl DiagnosticsHandler {
         self.emit(diagnostic);
     }
 
+    /// Reports an error diagnostic with the message produced by `error`, if `cond` is true
+    ///
+    /// Returns `cond`, i.e. whether the diagnostic was reported. If `cond` is false, `error`
+    /// is not called, so the cost of building the message is only paid when it is needed.
+    pub fn error_if<F, M>(&self, cond: bool, error: F) -> bool
+    where
+        F: FnOnce() -> M,
+        M: ToString,
+    {
+        if cond {
+            self.error(error());
+        }
+        cond
+    }
+
+    /// Reports a warning diagnostic with the message produced by `warning`, if `cond` is true
+    ///
+    /// See `error_if` and `warn` for details.
+    pub fn warn_if<F, M>(&self, cond: bool, warning: F) -> bool
+    where
+        F: FnOnce() -> M,
+        M: ToString,
+    {
+        if cond {
+            self.warn(warning());
+        }
+        cond
+    }
+
+    /// Returns the [Emitter] to which status messages, e.g. from `info`, are written
+    #[inline]
+    fn status_emitter(&self) -> &dyn Emitter {
+        self.status_emitter
+            .as_deref()
+            .unwrap_or(self.emitter.as_ref())
+    }
+
     /// Emits an informational diagnostic
     pub fn info(&self, message: impl ToString) {
         if self.verbosity > Verbosity::Info {
             return;
         }
         let info_color = self.display.styles.header(Severity::Help);
-        let mut buffer = self.emitter.buffer();
+        let emitter = self.status_emitter();
+        let mut buffer = emitter.buffer();
+        self.write_program_name(&mut buffer);
         buffer.set_color(info_color).ok();
-        buffer.write_all(b"info").unwrap();
+        buffer
+            .write_all(self.severity_labels().info.as_bytes())
+            .unwrap();
         buffer.set_color(&self.display.styles.header_message).ok();
         writeln!(&mut buffer, ": {}", message.to_string()).unwrap();
         buffer.reset().ok();
-        self.emitter.print(buffer).unwrap();
+        emitter.print(buffer).unwrap();
+    }
+
+    /// Like `info`, but only prints `message` the first time it is called with a given `key`
+    ///
+    /// This is intended for banners which must appear once, e.g. version information or a
+    /// deprecation notice, regardless of how many times the code printing them runs.
+    pub fn info_once(&self, key: impl Into<String>, message: impl ToString) {
+        if self.verbosity > Verbosity::Info {
+            return;
+        }
+        if self.once_keys.lock().insert(key.into()) {
+            self.info(message);
+        }
     }
 
     /// Emits a debug diagnostic
@@ -119,13 +583,17 @@ impl DiagnosticsHandler {
         }
         let mut debug_color = self.display.styles.header_message.clone();
         debug_color.set_fg(Some(Color::Blue));
-        let mut buffer = self.emitter.buffer();
+        let emitter = self.status_emitter();
+        let mut buffer = emitter.buffer();
+        self.write_program_name(&mut buffer);
         buffer.set_color(&debug_color).ok();
-        buffer.write_all(b"debug").unwrap();
+        buffer
+            .write_all(self.severity_labels().debug.as_bytes())
+            .unwrap();
         buffer.set_color(&self.display.styles.header_message).ok();
         writeln!(&mut buffer, ": {}", message.to_string()).unwrap();
         buffer.reset().ok();
-        self.emitter.print(buffer).unwrap();
+        emitter.print(buffer).unwrap();
     }
 
     /// Emits a note diagnostic
@@ -166,15 +634,36 @@ impl DiagnosticsHandler {
     }
 
     fn write_prefixed(&self, color: &ColorSpec, prefix: &str, message: impl ToString) {
-        let mut buffer = self.emitter.buffer();
+        let emitter = self.status_emitter();
+        let mut buffer = emitter.buffer();
         buffer.set_color(color).ok();
         write!(&mut buffer, "{:>12} ", prefix).unwrap();
         buffer.reset().ok();
         let message = message.to_string();
         buffer.write_all(message.as_bytes()).unwrap();
+        emitter.print(buffer).unwrap();
+    }
+
+    /// Writes `bytes` as-is to the output stream, via the [Emitter]
+    ///
+    /// This is intended for content which is not a diagnostic, but which should appear in
+    /// order with diagnostics, e.g. a table rendered by another library. Nothing is written
+    /// if the handler is silent.
+    pub fn emit_raw(&self, bytes: &[u8]) {
+        if self.silent {
+            return;
+        }
+        let mut buffer = self.emitter.buffer();
+        buffer.write_all(bytes).unwrap();
         self.emitter.print(buffer).unwrap();
     }
 
+    /// Like `emit_raw`, but for string content
+    #[inline]
+    pub fn emit_raw_str(&self, content: &str) {
+        self.emit_raw(content.as_bytes());
+    }
+
     /// Starts building an [InFlightDiagnostic] for rich compiler diagnostics.
     ///
     /// The caller is responsible for dropping/emitting the diagnostic using the
@@ -186,32 +675,2133 @@ impl DiagnosticsHandler {
     /// Emits the given diagnostic
     #[inline(always)]
     pub fn emit(&self, diagnostic: impl ToDiagnostic) {
-        if self.silent {
-            return;
+        self.emit_diagnostic(diagnostic.to_diagnostic(), Attachments::default());
+    }
+
+    /// Emits the diagnostic at the root of `tree`, with the rest of the tree beneath it
+    ///
+    /// Only the root is subject to filtering and counting, the rest of the tree is emitted if
+    /// and only if the root is, see [InFlightDiagnostic::with_child].
+    pub fn emit_tree(&self, tree: DiagnosticTree) {
+        let attachments = Attachments {
+            children: tree.children,
+            ..Attachments::default()
+        };
+        self.emit_diagnostic(tree.diagnostic, attachments);
+    }
+
+    /// Emits the diagnostic produced from `error`, retaining `error` itself so that it can be
+    /// recovered from the recorded diagnostic, see [RecordedDiagnostic::downcast_ref].
+    ///
+    /// Since producing the diagnostic consumes the error, it is cloned first. If diagnostics
+    /// are not being recorded, this is equivalent to `emit`.
+    pub fn emit_error<E>(&self, error: E)
+    where
+        E: ToDiagnostic + std::error::Error + Clone + Send + Sync + 'static,
+    {
+        let source = self
+            .recorded
+            .is_some()
+            .then(|| Arc::new(error.clone()) as Arc<dyn std::error::Error + Send + Sync>);
+        let attachments = Attachments {
+            source,
+            ..Attachments::default()
+        };
+        self.emit_diagnostic(error.to_diagnostic(), attachments);
+    }
+
+    /// Emits the given diagnostics in a deterministic order, regardless of the order in which
+    /// they were produced, e.g. by concurrent passes.
+    ///
+    /// Diagnostics are sorted by the key `(source_id, start, end, severity, message)`, using
+    /// the span of their primary label, with the most severe first among those sharing the same
+    /// span. Diagnostics without a primary label are emitted last, ordered by severity and message.
+    pub fn emit_sorted<I, D>(&self, diagnostics: I)
+    where
+        I: IntoIterator<Item = D>,
+        D: ToDiagnostic,
+    {
+        let mut diagnostics = diagnostics
+            .into_iter()
+            .map(ToDiagnostic::to_diagnostic)
+            .collect::<Vec<_>>();
+        diagnostics.sort_by(|a, b| {
+            let key = |d: &Diagnostic| {
+                primary_span(d).map_or((1, None), |span| {
+                    (
+                        0,
+                        Some((span.source_id(), span.start_index(), span.end_index())),
+                    )
+                })
+            };
+            key(a)
+                .cmp(&key(b))
+                .then_with(|| {
+                    b.severity
+                        .partial_cmp(&a.severity)
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                })
+                .then_with(|| a.message.cmp(&b.message))
+        });
+        for diagnostic in diagnostics {
+            self.emit_diagnostic(diagnostic, Attachments::default());
         }
+    }
 
-        let mut diagnostic = diagnostic.to_diagnostic();
-        match diagnostic.severity {
-            Severity::Note if self.verbosity > Verbosity::Info => return,
-            Severity::Warning if self.no_warn => return,
-            Severity::Warning if self.warnings_as_errors => {
-                diagnostic.severity = Severity::Error;
-            }
+    /// Emits the given diagnostic, rendering it with `style` rather than the configured
+    /// [crate::term::DisplayStyle].
+    pub fn emit_with_style(&self, diagnostic: impl ToDiagnostic, style: DisplayStyle) {
+        let attachments = Attachments {
+            display_style: Some(style),
+            ..Attachments::default()
+        };
+        self.emit_diagnostic(diagnostic.to_diagnostic(), attachments);
+    }
+
+    pub(crate) fn emit_diagnostic(&self, diagnostic: Diagnostic, attachments: Attachments) {
+        if self.emit_unless_filtered(diagnostic, attachments) && self.fail_fast {
+            FatalError.raise();
+        }
+    }
+
+    /// Returns the severity with which `diagnostic` would be emitted by this handler, once
+    /// lint levels and `warnings_as_errors` are applied, or `None` if it would be filtered out.
+    fn effective_severity(&self, diagnostic: &Diagnostic) -> Option<Severity> {
+        if self.silent || self.is_suppressed(diagnostic) {
+            return None;
+        }
+
+        let mut severity = diagnostic.severity;
+        if let Some(level) = diagnostic
+            .code
+            .as_deref()
+            .and_then(|code| self.lint_level(code))
+        {
+            severity = level.severity()?;
+        }
+        match severity {
+            Severity::Note if self.verbosity > Verbosity::Info => return None,
+            Severity::Warning if self.no_warn => return None,
+            Severity::Warning if self.warnings_as_errors => severity = Severity::Error,
             _ => (),
         }
 
-        if diagnostic.severity == Severity::Error {
-            self.err_count.fetch_add(1, Ordering::Relaxed);
+        match self.min_severity {
+            Some(min_severity) if severity < min_severity => None,
+            _ => Some(severity),
+        }
+    }
+
+    /// Emits `diagnostic`, unless it is filtered out by the configuration of this handler,
+    /// returning true if it was counted as an error.
+    fn emit_unless_filtered(&self, mut diagnostic: Diagnostic, attachments: Attachments) -> bool {
+        match self.effective_severity(&diagnostic) {
+            Some(severity) => diagnostic.severity = severity,
+            None => return false,
+        }
+
+        let is_error = match diagnostic.severity {
+            Severity::Error => {
+                self.err_count.fetch_add(1, Ordering::Relaxed);
+                if attachments.origin == DiagnosticOrigin::Tool {
+                    self.tool_err_count.fetch_add(1, Ordering::Relaxed);
+                }
+                true
+            }
+            Severity::Bug => {
+                self.err_count.fetch_add(1, Ordering::Relaxed);
+                self.tool_err_count.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        };
+
+        if self.is_throttled(&diagnostic) {
+            return is_error;
+        }
+
+        if let Some(footer) = self.error_footer.as_ref() {
+            if diagnostic.severity >= self.footer_min_severity && !diagnostic.notes.contains(footer)
+            {
+                diagnostic.notes.push(footer.clone());
+            }
+        }
+
+        #[cfg(feature = "test-util")]
+        if let Some(captured) = self.captured.as_ref() {
+            captured.record(&diagnostic);
+        }
+        if let Some(recorded) = self.recorded.as_ref() {
+            recorded
+                .lock()
+                .record(&diagnostic, attachments.source.clone());
+        }
+
+        let pass = self.current_pass();
+        let record = DiagnosticRecord {
+            diagnostic: &diagnostic,
+            children: attachments.children.as_slice(),
+            suggestions: attachments.suggestions.as_slice(),
+            annotations: attachments.annotations.as_slice(),
+            pass: pass.as_deref(),
+            handler: self,
+            display_style: attachments.display_style.clone(),
+        };
+        if self
+            .emitter
+            .emit_structured(&self.codemap, &record)
+            .unwrap()
+        {
+            return is_error;
         }
 
+        let severity = diagnostic.severity;
         let mut buffer = self.emitter.buffer();
-        crate::term::emit(
+        self.write_program_name(&mut buffer);
+        self.render_with_attachments(
             &mut buffer,
-            &self.display,
-            self.codemap.deref(),
-            &diagnostic,
-        )
-        .unwrap();
-        self.emitter.print(buffer).unwrap();
+            diagnostic,
+            attachments.display_style,
+            &attachments.suggestions,
+            &attachments.children,
+            pass.as_deref(),
+        );
+        self.emitter.print_diagnostic(severity, buffer).unwrap();
+        is_error
+    }
+
+    /// Renders `diagnostic`, followed by its `suggestions` and `children`, to `buffer`
+    pub(crate) fn render_with_attachments(
+        &self,
+        buffer: &mut Buffer,
+        mut diagnostic: Diagnostic,
+        display_style: Option<DisplayStyle>,
+        suggestions: &[Suggestion],
+        children: &[DiagnosticTree],
+        pass: Option<&str>,
+    ) {
+        let display = match display_style {
+            Some(display_style) => Cow::Owned(crate::term::Config {
+                display_style,
+                ..self.display.clone()
+            }),
+            None => Cow::Borrowed(&self.display),
+        };
+        if let Some(pass) = pass.filter(|_| self.verbosity == Verbosity::Debug) {
+            diagnostic.message = format!("[{}] {}", pass, diagnostic.message);
+        }
+        self.render(buffer, &display, diagnostic);
+        if !matches!(display.display_style, DisplayStyle::Short) {
+            let files = self.files();
+            for suggestion in suggestions.iter() {
+                let line_number = |line_index| {
+                    files
+                        .line_number(suggestion.span.source_id(), line_index)
+                        .unwrap()
+                };
+                let inline = self.inline_suggestions
+                    && crate::suggestion::render_inline(
+                        buffer,
+                        &self.codemap,
+                        &display,
+                        &self.severity_labels(),
+                        suggestion,
+                        line_number,
+                    )
+                    .unwrap();
+                if !inline {
+                    crate::suggestion::render_diff(
+                        buffer,
+                        &self.codemap,
+                        &display,
+                        &self.severity_labels(),
+                        suggestion,
+                        line_number,
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        self.render_children(buffer, &display, children);
+    }
+
+    /// Renders `children` beneath their parent, indented to show the nesting, and their own
+    /// children recursively, so that each level of the tree is indented further.
+    fn render_children(
+        &self,
+        buffer: &mut Buffer,
+        display: &crate::term::Config,
+        children: &[DiagnosticTree],
+    ) {
+        for child in children {
+            let mut child_buffer = if buffer.supports_color() {
+                Buffer::ansi()
+            } else {
+                Buffer::no_color()
+            };
+            self.render(&mut child_buffer, display, child.diagnostic.clone());
+            self.render_children(&mut child_buffer, display, &child.children);
+            for line in child_buffer.as_slice().split_inclusive(|b| *b == b'\n') {
+                if line != b"\n" {
+                    buffer.write_all(b"    ").unwrap();
+                }
+                buffer.write_all(line).unwrap();
+            }
+        }
+    }
+
+    fn render(
+        &self,
+        buffer: &mut Buffer,
+        display: &crate::term::Config,
+        mut diagnostic: Diagnostic,
+    ) {
+        if self.merge_adjacent_labels {
+            merge_adjacent_labels(&mut diagnostic);
+        }
+        if self.show_byte_offsets {
+            for label in diagnostic.labels.iter_mut() {
+                let span = format!(
+                    "[{}..{}@{}]",
+                    label.range.start,
+                    label.range.end,
+                    label.file_id.get()
+                );
+                if label.message.is_empty() {
+                    label.message = span;
+                } else {
+                    label.message = format!("{} {}", label.message, span);
+                }
+            }
+        }
+        let mut files = self.files();
+        if let Some(width) = self.wrap_width {
+            // Reserve the code area by truncating the gutter, which is followed by ` │ `
+            let gutter_width = gutter_width(&files, &diagnostic);
+            if let Some(min_code_width) = self.min_code_width {
+                let max_gutter_width = width.saturating_sub(min_code_width + 3).max(1);
+                if gutter_width > max_gutter_width {
+                    files.gutter_digits = Some(max_gutter_width);
+                }
+            }
+            let gutter_width = files.gutter_digits.unwrap_or(gutter_width);
+            self.wrap(&mut diagnostic, width, gutter_width);
+        }
+        if let Some(max_width) = self.max_label_width {
+            files.truncated = truncate_lines(&self.codemap, &mut diagnostic, max_width);
+        }
+
+        match self.max_gap_lines {
+            Some(max_gap_lines) if max_gap_lines > 1 => {
+                let mut recorder = GapRecorder::new(buffer.supports_color());
+                self.emit_localized(&mut recorder, display, &files, &diagnostic);
+                recorder
+                    .replay(buffer, display, &files, &diagnostic, max_gap_lines)
+                    .unwrap();
+            }
+            _ => self.emit_localized(buffer, display, &files, &diagnostic),
+        }
+    }
+
+    /// Renders `diagnostic` to `out`, substituting the configured severity labels, if any
+    fn emit_localized<W: WriteColor>(
+        &self,
+        out: &mut W,
+        display: &crate::term::Config,
+        files: &DisplayFiles<'_>,
+        diagnostic: &Diagnostic,
+    ) {
+        match self.severity_labels.as_ref() {
+            None => crate::term::emit(out, display, files, diagnostic).unwrap(),
+            Some(labels) => {
+                let mut writer = LocalizedWriter::new(out, labels, &display.styles);
+                crate::term::emit(&mut writer, display, files, diagnostic).unwrap()
+            }
+        }
+    }
+
+    /// Wraps the message and notes of `diagnostic` to fit within `width` columns once rendered,
+    /// where `gutter_width` is the width of the line numbers in the gutter of its snippets
+    fn wrap(&self, diagnostic: &mut Diagnostic, width: usize, gutter_width: usize) {
+        use crate::wrap::wrap_text;
+
+        // The message follows the header, e.g. `error[E001]: `, on the first line only
+        let header_width = self.severity_labels().get(diagnostic.severity).len()
+            + diagnostic.code.as_ref().map_or(0, |code| code.len() + 2)
+            + 2;
+        diagnostic.message = wrap_text(
+            &diagnostic.message,
+            width.saturating_sub(header_width),
+            width,
+        );
+        // Notes are indented past the gutter
+        let note_width = width.saturating_sub(gutter_width + 3);
+        for note in diagnostic.notes.iter_mut() {
+            *note = wrap_text(note, note_width, note_width);
+        }
+    }
+
+    /// Writes the `program_name` prefix, if configured, to `buffer`
+    fn write_program_name(&self, buffer: &mut Buffer) {
+        if let Some(name) = self.program_name.as_deref() {
+            buffer.set_color(&self.display.styles.header_message).ok();
+            write!(buffer, "{}: ", name).unwrap();
+            buffer.reset().ok();
+        }
+    }
+
+    fn severity_labels(&self) -> Cow<'_, SeverityLabels> {
+        match self.severity_labels.as_ref() {
+            Some(labels) => Cow::Borrowed(labels),
+            None => Cow::Owned(SeverityLabels::default()),
+        }
+    }
+}
+
+/// The set of suppressed diagnostic codes, represented as an interval map per file, keyed by code.
+///
+/// The intervals for each code are kept sorted by start index.
+#[derive(Default)]
+struct Suppressions {
+    files: FxHashMap<SourceId, FxHashMap<String, Vec<Range<usize>>>>,
+}
+impl Suppressions {
+    fn insert(&mut self, region: SourceSpan, code: String) {
+        if region.is_unknown() {
+            return;
+        }
+        let intervals = self
+            .files
+            .entry(region.source_id())
+            .or_default()
+            .entry(code)
+            .or_default();
+        let range: Range<usize> = region.into();
+        let index = intervals.partition_point(|r| r.start <= range.start);
+        intervals.insert(index, range);
+    }
+
+    fn contains(&self, file_id: SourceId, code: &str, range: &Range<usize>) -> bool {
+        let intervals = match self.files.get(&file_id).and_then(|codes| codes.get(code)) {
+            Some(intervals) => intervals,
+            None => return false,
+        };
+        // Only intervals starting at or before `range` can contain it
+        let candidates = intervals.partition_point(|r| r.start <= range.start);
+        intervals[..candidates].iter().any(|r| r.end >= range.end)
+    }
+}
+
+/// Returns the width of the largest line number displayed in the gutter of the snippets of
+/// `diagnostic`, i.e. that of the line on which one of its labels starts or ends
+fn gutter_width(files: &DisplayFiles<'_>, diagnostic: &Diagnostic) -> usize {
+    diagnostic
+        .labels
+        .iter()
+        .flat_map(|label| {
+            [
+                (label.file_id, label.range.start),
+                (label.file_id, label.range.end),
+            ]
+        })
+        .filter_map(|(file_id, index)| {
+            let line_index = Files::line_index(files, file_id, index).ok()?;
+            Some(files.full_line_number(line_index).to_string().len())
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// A view of a [CodeMap] used when rendering diagnostics, which numbers lines and columns
+/// according to the configured bases and offsets.
+struct DisplayFiles<'c> {
+    codemap: &'c CodeMap,
+    line_base: usize,
+    line_number_offset: i64,
+    column_base: usize,
+    /// When set, only this many trailing digits of line numbers are shown in the gutter, see
+    /// `min_code_width` of [DiagnosticsConfig]
+    gutter_digits: Option<usize>,
+    /// Files whose long lines have been truncated, which are displayed in place of the
+    /// originals, see `max_label_width` of [DiagnosticsConfig]
+    truncated: Vec<TruncatedSource>,
+}
+impl<'c> DisplayFiles<'c> {
+    fn full_line_number(&self, line_index: usize) -> usize {
+        let line_number = (line_index + self.line_base) as i64 + self.line_number_offset;
+        line_number.max(0) as usize
+    }
+
+    fn truncated(&self, file_id: SourceId) -> Option<&TruncatedSource> {
+        self.truncated.iter().find(|t| t.file_id == file_id)
+    }
+}
+impl<'a, 'c: 'a> Files<'a> for DisplayFiles<'c> {
+    type FileId = SourceId;
+    type Name = String;
+    type Source = &'a str;
+
+    #[inline]
+    fn name(&'a self, file_id: SourceId) -> Result<String, Error> {
+        Files::name(self.codemap, file_id)
+    }
+
+    #[inline]
+    fn source(&'a self, file_id: SourceId) -> Result<&'a str, Error> {
+        match self.truncated(file_id) {
+            Some(truncated) => Ok(truncated.source.as_str()),
+            None => Files::source(self.codemap, file_id),
+        }
+    }
+
+    #[inline]
+    fn line_index(&'a self, file_id: SourceId, byte_index: usize) -> Result<usize, Error> {
+        match self.truncated(file_id) {
+            Some(truncated) => Ok(truncated.line_index(byte_index)),
+            None => Files::line_index(self.codemap, file_id, byte_index),
+        }
+    }
+
+    #[inline]
+    fn line_range(&'a self, file_id: SourceId, line_index: usize) -> Result<Range<usize>, Error> {
+        match self.truncated(file_id) {
+            Some(truncated) => truncated.line_range(line_index),
+            None => Files::line_range(self.codemap, file_id, line_index),
+        }
+    }
+
+    fn line_number(&'a self, _file_id: SourceId, line_index: usize) -> Result<usize, Error> {
+        let line_number = self.full_line_number(line_index);
+        match self.gutter_digits {
+            Some(digits) => Ok(line_number % 10usize.pow(digits.min(19) as u32)),
+            None => Ok(line_number),
+        }
+    }
+
+    fn location(
+        &'a self,
+        file_id: SourceId,
+        byte_index: usize,
+    ) -> Result<codespan_reporting::files::Location, Error> {
+        // The header shows the full line number, even if the gutter is truncated
+        let line_index = self.line_index(file_id, byte_index)?;
+        Ok(codespan_reporting::files::Location {
+            line_number: self.full_line_number(line_index),
+            column_number: self.column_number(file_id, line_index, byte_index)?,
+        })
+    }
+
+    fn column_number(
+        &'a self,
+        file_id: SourceId,
+        line_index: usize,
+        byte_index: usize,
+    ) -> Result<usize, Error> {
+        // Columns are reported as in the original source, even if the line is truncated
+        let byte_index = self
+            .truncated(file_id)
+            .map_or(byte_index, |truncated| truncated.original_index(byte_index));
+        let source = Files::source(self.codemap, file_id)?;
+        let line_range = Files::line_range(self.codemap, file_id, line_index)?;
+        let column_index = codespan_reporting::files::column_index(source, line_range, byte_index);
+        Ok(column_index + self.column_base)
+    }
+}
+
+/// A [WriteColor] adapter which substitutes the severity labels written by the renderer
+/// with those configured in [SeverityLabels].
+///
+/// The renderer writes the header of a diagnostic, i.e. its severity label followed by its
+/// code, if any, using the header style for that severity. Everything written in that style
+/// is buffered until the style changes, at which point a leading default severity label is
+/// replaced, regardless of how the renderer split up its writes.
+struct LocalizedWriter<'a, W: WriteColor> {
+    inner: &'a mut W,
+    labels: &'a SeverityLabels,
+    styles: &'a crate::term::Styles,
+    header: Option<Vec<u8>>,
+}
+impl<'a, W: WriteColor> LocalizedWriter<'a, W> {
+    const SEVERITIES: [Severity; 5] = [
+        Severity::Bug,
+        Severity::Error,
+        Severity::Warning,
+        Severity::Note,
+        Severity::Help,
+    ];
+
+    fn new(inner: &'a mut W, labels: &'a SeverityLabels, styles: &'a crate::term::Styles) -> Self {
+        Self {
+            inner,
+            labels,
+            styles,
+            header: None,
+        }
+    }
+
+    /// Writes out the buffered header, if any, substituting its severity label
+    fn flush_header(&mut self) -> std::io::Result<()> {
+        let header = match self.header.take() {
+            Some(header) => header,
+            None => return Ok(()),
+        };
+        let defaults = SeverityLabels::default();
+        for severity in Self::SEVERITIES {
+            if let Some(rest) = header.strip_prefix(defaults.get(severity).as_bytes()) {
+                if rest.is_empty() || rest.starts_with(b"[") {
+                    self.inner.write_all(self.labels.get(severity).as_bytes())?;
+                    return self.inner.write_all(rest);
+                }
+            }
+        }
+        self.inner.write_all(&header)
+    }
+}
+impl<'a, W: WriteColor> std::io::Write for LocalizedWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.header.as_mut() {
+            Some(header) => {
+                header.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            None => self.inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.flush_header()?;
+        self.inner.flush()
+    }
+}
+impl<'a, W: WriteColor> WriteColor for LocalizedWriter<'a, W> {
+    fn supports_color(&self) -> bool {
+        self.inner.supports_color()
+    }
+
+    fn set_color(&mut self, spec: &ColorSpec) -> std::io::Result<()> {
+        self.flush_header()?;
+        if Self::SEVERITIES
+            .iter()
+            .any(|severity| self.styles.header(*severity) == spec)
+        {
+            self.header = Some(vec![]);
+        }
+        self.inner.set_color(spec)
+    }
+
+    fn reset(&mut self) -> std::io::Result<()> {
+        self.flush_header()?;
+        self.inner.reset()
+    }
+}
+impl<'a, W: WriteColor> Drop for LocalizedWriter<'a, W> {
+    fn drop(&mut self) {
+        self.flush_header().ok();
+    }
+}
+
+/// A [WriteColor] which records the output of the renderer, so that the breaks it renders
+/// between distant lines of a source snippet can be expanded according to `max_gap_lines` of
+/// [DiagnosticsConfig], before the output is written out via `replay`.
+///
+/// The renderer collapses the lines between two labeled lines whenever there is more than one
+/// of them, which is not configurable, so each break which stands in for no more than
+/// `max_gap_lines` lines is replaced with those lines, rendered as the renderer would have.
+struct GapRecorder {
+    color: bool,
+    lines: Vec<Vec<Segment>>,
+}
+
+/// A single operation recorded by a [GapRecorder]
+enum Segment {
+    Color(ColorSpec),
+    Reset,
+    Text(Vec<u8>),
+}
+
+impl GapRecorder {
+    fn new(color: bool) -> Self {
+        Self {
+            color,
+            lines: vec![vec![]],
+        }
+    }
+
+    #[inline]
+    fn push(&mut self, segment: Segment) {
+        self.lines.last_mut().unwrap().push(segment);
+    }
+
+    /// Writes the recorded output to `out`, expanding breaks which stand in for no more than
+    /// `max_gap_lines` lines
+    fn replay<W: WriteColor>(
+        self,
+        out: &mut W,
+        display: &crate::term::Config,
+        files: &DisplayFiles<'_>,
+        diagnostic: &Diagnostic,
+        max_gap_lines: usize,
+    ) -> std::io::Result<()> {
+        // Snippets are rendered for each file in the order in which it is first labeled
+        let mut file_ids = Vec::<SourceId>::new();
+        for label in diagnostic.labels.iter() {
+            if !file_ids.contains(&label.file_id) {
+                file_ids.push(label.file_id);
+            }
+        }
+
+        let mut file_id = None;
+        let mut snippets = file_ids.iter();
+        let mut previous = None;
+        for (i, line) in self.lines.iter().enumerate() {
+            if Self::is_snippet_start(line, display) {
+                file_id = snippets.next().copied();
+                previous = None;
+            } else if let Some((line_number, _)) = Self::line_number(line, display) {
+                previous = Some(line_number);
+            } else if Self::is_break(line, display) {
+                let next = self
+                    .lines
+                    .get(i + 1)
+                    .and_then(|line| Self::line_number(line, display));
+                let lines = match (file_id, previous, next) {
+                    (Some(file_id), Some(previous), Some((next, padding)))
+                        if next - previous - 1 <= max_gap_lines =>
+                    {
+                        Self::gap_lines(display, files, file_id, previous + 1..next)
+                            .map(|lines| (lines, padding))
+                    }
+                    _ => None,
+                };
+                if let Some((lines, padding)) = lines {
+                    for (line_number, line) in lines {
+                        out.set_color(&display.styles.line_number)?;
+                        write!(out, "{:>width$}", line_number, width = padding)?;
+                        out.reset()?;
+                        write!(out, " ")?;
+                        out.set_color(&display.styles.source_border)?;
+                        write!(out, "{}", display.chars.source_border_left)?;
+                        out.reset()?;
+                        writeln!(out, " {}", line)?;
+                    }
+                    continue;
+                }
+            }
+            for segment in line.iter() {
+                match segment {
+                    Segment::Color(spec) => out.set_color(spec)?,
+                    Segment::Reset => out.reset()?,
+                    Segment::Text(text) => out.write_all(text)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the content of the lines of `file_id` with the given line numbers, with tabs
+    /// expanded as the renderer does, or `None` if any of them are not in the file
+    fn gap_lines(
+        display: &crate::term::Config,
+        files: &DisplayFiles<'_>,
+        file_id: SourceId,
+        line_numbers: Range<usize>,
+    ) -> Option<Vec<(usize, String)>> {
+        let source = Files::source(files, file_id).ok()?;
+        line_numbers
+            .map(|line_number| {
+                let line_index =
+                    line_number as i64 - files.line_base as i64 - files.line_number_offset;
+                let line_index = usize::try_from(line_index).ok()?;
+                let range = Files::line_range(files, file_id, line_index).ok()?;
+                let line = source[range].trim_end_matches(['\n', '\r', '\0'].as_ref());
+                Some((line_number, expand_tabs(line, display.tab_width).0))
+            })
+            .collect()
+    }
+
+    /// Returns the line number of a source line, and the width to which it is padded
+    fn line_number(line: &[Segment], display: &crate::term::Config) -> Option<(usize, usize)> {
+        match Self::trim_start(line) {
+            [Segment::Color(spec), Segment::Text(number), ..]
+                if spec == &display.styles.line_number =>
+            {
+                let number = std::str::from_utf8(number).ok()?;
+                Some((number.trim_start().parse().ok()?, number.len()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true if `line` is a break, with no multi-line labels passing through it
+    fn is_break(line: &[Segment], display: &crate::term::Config) -> bool {
+        match Self::trim_start(line) {
+            [Segment::Color(spec), Segment::Text(text), Segment::Reset, Segment::Text(newline)] => {
+                spec == &display.styles.source_border
+                    && text
+                        == display
+                            .chars
+                            .source_border_left_break
+                            .to_string()
+                            .as_bytes()
+                    && newline == b"\n"
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns true if `line` starts the snippet of a file, i.e. it shows its location
+    fn is_snippet_start(line: &[Segment], display: &crate::term::Config) -> bool {
+        match Self::trim_start(line) {
+            [Segment::Color(spec), Segment::Text(text), ..] => {
+                spec == &display.styles.source_border
+                    && text == display.chars.snippet_start.as_bytes()
+            }
+            _ => false,
+        }
+    }
+
+    /// Skips the padding at the start of `line`
+    fn trim_start(line: &[Segment]) -> &[Segment] {
+        let padding = line
+            .iter()
+            .take_while(
+                |segment| matches!(segment, Segment::Text(text) if text.iter().all(|b| *b == b' ')),
+            )
+            .count();
+        &line[padding..]
+    }
+}
+impl std::io::Write for GapRecorder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for chunk in buf.split_inclusive(|b| *b == b'\n') {
+            self.push(Segment::Text(chunk.to_vec()));
+            if chunk.ends_with(b"\n") {
+                self.lines.push(vec![]);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+impl WriteColor for GapRecorder {
+    #[inline]
+    fn supports_color(&self) -> bool {
+        self.color
+    }
+
+    fn set_color(&mut self, spec: &ColorSpec) -> std::io::Result<()> {
+        self.push(Segment::Color(spec.clone()));
+        Ok(())
+    }
+
+    fn reset(&mut self) -> std::io::Result<()> {
+        self.push(Segment::Reset);
+        Ok(())
+    }
+}
+
+/// Tracks the number of diagnostics with a given code, for throttling
+struct Throttle {
+    limit: usize,
+    emitted: usize,
+}
+
+/// A diagnostic recorded by a [DiagnosticsHandler], see [DiagnosticsHandler::take_recorded]
+#[derive(Debug, Clone)]
+pub struct RecordedDiagnostic {
+    /// The position of this diagnostic in the order in which diagnostics were emitted,
+    /// starting from zero. Sequence numbers are unique for the lifetime of the handler.
+    pub sequence: u64,
+    /// The thread from which this diagnostic was emitted
+    pub thread: ThreadId,
+    /// The diagnostic, as it was rendered
+    pub diagnostic: Diagnostic,
+    /// The error from which the diagnostic was produced, if emitted via
+    /// [DiagnosticsHandler::emit_error]
+    pub source: Option<Arc<dyn std::error::Error + Send + Sync>>,
+}
+impl RecordedDiagnostic {
+    /// Returns the error from which the diagnostic was produced, if it is of type `T`
+    pub fn downcast_ref<T: std::error::Error + 'static>(&self) -> Option<&T> {
+        self.source.as_deref()?.downcast_ref::<T>()
+    }
+}
+
+/// The diagnostics recorded by a [DiagnosticsHandler] with `record_diagnostics` set
+#[derive(Default)]
+struct Recorded {
+    next_sequence: u64,
+    diagnostics: Vec<RecordedDiagnostic>,
+}
+impl Recorded {
+    fn record(
+        &mut self,
+        diagnostic: &Diagnostic,
+        source: Option<Arc<dyn std::error::Error + Send + Sync>>,
+    ) {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.diagnostics.push(RecordedDiagnostic {
+            sequence,
+            thread: std::thread::current().id(),
+            diagnostic: diagnostic.clone(),
+            source,
+        });
+    }
+}
+
+/// A snapshot of the diagnostic counts of a [DiagnosticsHandler], see [DiagnosticsHandler::checkpoint]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    errors: usize,
+}
+impl Checkpoint {
+    /// Returns the number of error diagnostics emitted by `handler` since this checkpoint
+    pub fn errors_since(&self, handler: &DiagnosticsHandler) -> usize {
+        handler
+            .err_count
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.errors)
+    }
+
+    /// Returns true if `handler` has emitted any error diagnostics since this checkpoint
+    pub fn had_new_errors(&self, handler: &DiagnosticsHandler) -> bool {
+        self.errors_since(handler) > 0
+    }
+}
+
+/// A group of diagnostics which are either emitted together, or not at all,
+/// see [DiagnosticsHandler::begin_transaction]
+///
+/// Buffered diagnostics have no effect on the [DiagnosticsHandler], e.g. they are not
+/// reflected in its error count, until the transaction is committed. If the transaction
+/// is dropped without being committed, they are discarded.
+#[must_use]
+pub struct DiagnosticTransaction<'h> {
+    handler: &'h DiagnosticsHandler,
+    pending: Vec<Diagnostic>,
+}
+impl<'h> DiagnosticTransaction<'h> {
+    /// Buffers the given diagnostic, to be emitted when this transaction is committed
+    pub fn emit(&mut self, diagnostic: impl ToDiagnostic) {
+        self.pending.push(diagnostic.to_diagnostic());
+    }
+
+    /// Returns the number of diagnostics buffered in this transaction
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns true if no diagnostics have been buffered in this transaction
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Returns true if any of the buffered diagnostics would be counted as errors when this
+    /// transaction is committed, i.e. taking into account the lint levels, `warnings_as_errors`,
+    /// and filtering configured for the [DiagnosticsHandler].
+    pub fn has_errors(&self) -> bool {
+        self.pending.iter().any(|diagnostic| {
+            self.handler
+                .effective_severity(diagnostic)
+                .map_or(false, |severity| severity >= Severity::Error)
+        })
+    }
+
+    /// Emits all of the buffered diagnostics via the [DiagnosticsHandler], in the order
+    /// in which they were buffered
+    pub fn commit(self) {
+        for diagnostic in self.pending {
+            self.handler
+                .emit_diagnostic(diagnostic, Attachments::default());
+        }
+    }
+
+    /// Discards all of the buffered diagnostics
+    pub fn rollback(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler(
+        config: DiagnosticsConfig,
+        source: &str,
+    ) -> (DiagnosticsHandler, Arc<CaptureEmitter>, SourceId) {
+        let codemap = Arc::new(CodeMap::new());
+        let id = codemap.add("test.masm", source.to_string());
+        let emitter = Arc::new(CaptureEmitter::new());
+        let handler = DiagnosticsHandler::new(config, codemap, emitter.clone());
+        (handler, emitter, id)
+    }
+
+    fn span(id: SourceId, start: u32, end: u32) -> SourceSpan {
+        SourceSpan::new(
+            SourceIndex::new(id, ByteIndex(start)),
+            SourceIndex::new(id, ByteIndex(end)),
+        )
+    }
+
+    #[test]
+    fn suppressed_codes_are_dropped_within_region() {
+        let (handler, emitter, id) =
+            handler(DiagnosticsConfig::default(), "fn a() {}\nfn b() {}\n");
+        handler.suppress(span(id, 0, 9), "W001");
+        let unused = |start, end| {
+            Diagnostic::warning()
+                .with_code("W001")
+                .with_message(format!("unused at {}", start))
+                .with_labels(vec![Label::primary(id, span(id, start, end))])
+        };
+        handler.emit(unused(3, 4));
+        handler.emit(unused(13, 14));
+        handler.emit(
+            Diagnostic::warning()
+                .with_code("W002")
+                .with_message("other lint")
+                .with_labels(vec![Label::primary(id, span(id, 3, 4))]),
+        );
+
+        let captured = emitter.captured();
+        assert!(!captured.contains("unused at 3"), "{}", captured);
+        assert!(captured.contains("unused at 13"), "{}", captured);
+        assert!(captured.contains("other lint"), "{}", captured);
+    }
+
+    #[test]
+    fn min_severity_suppresses_lower_severities() {
+        let config = DiagnosticsConfig {
+            min_severity: Some(Severity::Error),
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, _) = self::handler(config, "");
+        handler.warn("a warning");
+        handler.note("a note");
+        handler.error("an error");
+
+        let captured = emitter.captured();
+        assert!(!captured.contains("a warning"), "{}", captured);
+        assert!(!captured.contains("a note"), "{}", captured);
+        assert!(captured.contains("error: an error"), "{}", captured);
+
+        // Warnings promoted to errors are not suppressed
+        let config = DiagnosticsConfig {
+            min_severity: Some(Severity::Error),
+            warnings_as_errors: true,
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, _) = self::handler(config, "");
+        handler.warn("a warning");
+        let captured = emitter.captured();
+        assert!(captured.contains("error: a warning"), "{}", captured);
+    }
+
+    #[test]
+    fn display_style_override() {
+        let (handler, emitter, id) = handler(DiagnosticsConfig::default(), "let x = foo\n");
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("short")
+            .with_primary_label(span(id, 8, 11), "here")
+            .with_display_style(DisplayStyle::Short)
+            .emit();
+        let short = emitter.captured();
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("rich")
+            .with_primary_label(span(id, 8, 11), "here")
+            .emit();
+        let rich = emitter.captured()[short.len()..].to_string();
+
+        assert_eq!(short, "<test.masm>:1:9: error: short\n");
+        assert!(rich.starts_with("error: rich\n"), "{}", rich);
+        assert!(rich.contains("let x = foo"), "{}", rich);
+    }
+
+    #[test]
+    fn whole_file_label() {
+        let (handler, emitter, id) = handler(DiagnosticsConfig::default(), "begin\nend\n");
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("missing entry point")
+            .with_file_label(id, "in this file")
+            .emit();
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("missing entry point")
+            .with_file_label(id, "in this file")
+            .with_display_style(DisplayStyle::Short)
+            .emit();
+        let captured = emitter.captured();
+        assert!(
+            captured.starts_with("error: missing entry point\n  ┌─ <test.masm>:1:1\n"),
+            "{}",
+            captured
+        );
+        assert!(captured.contains("1 │ ╭ begin\n"), "{}", captured);
+        assert!(captured.contains("in this file\n"), "{}", captured);
+        assert!(
+            captured.ends_with("<test.masm>:1:1: error: missing entry point\n"),
+            "{}",
+            captured
+        );
+    }
+
+    #[test]
+    fn byte_offsets_are_shown_when_enabled() {
+        let emit = |show_byte_offsets| {
+            let config = DiagnosticsConfig {
+                show_byte_offsets,
+                ..DiagnosticsConfig::default()
+            };
+            let (handler, emitter, id) = self::handler(config, "let x = foo\n");
+            handler
+                .diagnostic(Severity::Error)
+                .with_message("unknown function")
+                .with_primary_label(span(id, 8, 11), "here")
+                .with_secondary_label(span(id, 4, 5), "")
+                .emit();
+            emitter.captured()
+        };
+
+        let captured = emit(true);
+        assert!(captured.contains("here [8..11@1]"), "{}", captured);
+        assert!(captured.contains("[4..5@1]"), "{}", captured);
+        let captured = emit(false);
+        assert!(!captured.contains("@1]"), "{}", captured);
+    }
+
+    #[test]
+    fn fatal_diagnostic_is_emitted_before_aborting() {
+        let (handler, emitter, id) = handler(DiagnosticsConfig::default(), "let x = foo\n");
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            handler.fatal_diagnostic(
+                Diagnostic::error()
+                    .with_message("unknown function")
+                    .with_labels(vec![Label::primary(id, span(id, 8, 11))]),
+            )
+        }));
+        assert!(result.is_err());
+        assert!(handler.has_errors());
+        let captured = emitter.captured();
+        assert!(
+            captured.starts_with("error: unknown function\n"),
+            "{}",
+            captured
+        );
+        assert!(captured.contains("let x = foo"), "{}", captured);
+    }
+
+    #[test]
+    fn primary_pair_underlines_both() {
+        let (handler, emitter, id) = handler(DiagnosticsConfig::default(), "foo(bar]\n");
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("mismatched delimiters")
+            .with_primary_pair(span(id, 3, 4), span(id, 7, 8), "these do not match")
+            .emit();
+        let captured = emitter.captured();
+        assert!(captured.contains("1 │ foo(bar]\n"), "{}", captured);
+        assert!(captured.contains("  │    ^   ^\n"), "{}", captured);
+        assert!(captured.contains("these do not match"), "{}", captured);
+    }
+
+    #[test]
+    fn child_is_rendered_indented_beneath_parent() {
+        let (handler, emitter, id) =
+            handler(DiagnosticsConfig::default(), "fn a() {}\nfn a() {}\n");
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("duplicate definition")
+            .with_primary_label(span(id, 13, 14), "redefined here")
+            .with_child(
+                Severity::Note,
+                "previous definition",
+                vec![Label::primary(id, span(id, 3, 4)).with_message("first defined here")],
+            )
+            .emit();
+        assert_eq!(
+            emitter.captured(),
+            "error: duplicate definition\n  ┌─ <test.masm>:2:4\n  │\n2 │ fn a() {}\n  │    ^ \
+             redefined here\n\n    note: previous definition\n      ┌─ <test.masm>:1:4\n      │\n    \
+             1 │ fn a() {}\n      │    ^ first defined here\n\n"
+        );
+    }
+
+    #[test]
+    fn errors_since_checkpoint() {
+        let (handler, _, _) = handler(DiagnosticsConfig::default(), "");
+        handler.error("before");
+        let checkpoint = handler.checkpoint();
+        assert_eq!(checkpoint.errors_since(&handler), 0);
+        assert!(!checkpoint.had_new_errors(&handler));
+
+        handler.warn("not an error");
+        assert!(!checkpoint.had_new_errors(&handler));
+        handler.error("after");
+        assert_eq!(checkpoint.errors_since(&handler), 1);
+        assert!(checkpoint.had_new_errors(&handler));
+    }
+
+    #[test]
+    fn transaction_rollback_and_commit() {
+        let (handler, emitter, _) = handler(DiagnosticsConfig::default(), "");
+        let mut transaction = handler.begin_transaction();
+        transaction.emit(Diagnostic::error().with_message("first"));
+        transaction.emit(Diagnostic::error().with_message("second"));
+        assert_eq!(transaction.len(), 2);
+        assert!(transaction.has_errors());
+        assert!(!handler.has_errors());
+        transaction.rollback();
+        assert!(!handler.has_errors());
+        assert!(emitter.captured().is_empty());
+
+        let mut transaction = handler.begin_transaction();
+        transaction.emit(Diagnostic::error().with_message("first"));
+        transaction.emit(Diagnostic::warning().with_message("second"));
+        transaction.commit();
+        assert!(handler.has_errors());
+        assert_eq!(emitter.captured(), "error: first\n\nwarning: second\n\n");
+    }
+
+    #[test]
+    fn transaction_has_errors_uses_effective_severity() {
+        let config = DiagnosticsConfig {
+            warnings_as_errors: true,
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, _, _) = handler(config, "");
+        let mut transaction = handler.begin_transaction();
+        transaction.emit(Diagnostic::warning().with_message("promoted"));
+        assert!(transaction.has_errors());
+        transaction.rollback();
+
+        let config = DiagnosticsConfig {
+            lint_levels: [("shadowed-proc".to_string(), LintLevel::Allow)]
+                .into_iter()
+                .collect(),
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, _, _) = self::handler(config, "");
+        handler.register_lint("shadowed-proc", LintLevel::Deny, "a procedure is shadowed");
+        handler.register_lint("unused-local", LintLevel::Deny, "a local is never read");
+        let mut transaction = handler.begin_transaction();
+        transaction.emit(Diagnostic::error().with_code("shadowed-proc"));
+        assert!(!transaction.has_errors());
+        transaction.emit(Diagnostic::warning().with_code("unused-local"));
+        assert!(transaction.has_errors());
+        transaction.commit();
+        assert_eq!(handler.user_error_count(), 1);
+    }
+
+    #[test]
+    fn error_footer_is_appended_to_errors() {
+        const FOOTER: &str = "For more information, run `miden explain`.";
+        let config = || DiagnosticsConfig {
+            error_footer: Some(FOOTER.to_string()),
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, _) = self::handler(config(), "");
+        handler.error("an error");
+        handler.emit(
+            Diagnostic::error()
+                .with_message("noted")
+                .with_notes(vec![FOOTER.to_string()]),
+        );
+        handler.warn("a warning");
+        let captured = emitter.captured();
+        assert_eq!(captured.matches(FOOTER).count(), 2, "{}", captured);
+        assert!(captured.ends_with("warning: a warning\n\n"), "{}", captured);
+
+        let (handler, emitter, _) = self::handler(
+            DiagnosticsConfig {
+                footer_min_severity: Severity::Warning,
+                ..config()
+            },
+            "",
+        );
+        handler.warn("a warning");
+        assert!(emitter.captured().contains(FOOTER));
+    }
+
+    #[test]
+    fn with_primary_renders_title_and_label() {
+        let (handler, emitter, id) = handler(DiagnosticsConfig::default(), "let x: i32 = true;\n");
+        handler
+            .diagnostic(Severity::Error)
+            .with_primary(
+                span(id, 13, 17),
+                "type mismatch",
+                "expected i32, found bool",
+            )
+            .emit();
+        let captured = emitter.captured();
+        assert!(
+            captured.starts_with("error: type mismatch\n"),
+            "{}",
+            captured
+        );
+        assert!(
+            captured.contains("^^^^ expected i32, found bool\n"),
+            "{}",
+            captured
+        );
+    }
+
+    #[test]
+    fn diagnostics_are_tagged_with_pass() {
+        let config = DiagnosticsConfig {
+            verbosity: Verbosity::Debug,
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, _) = self::handler(config, "");
+        handler.in_pass("typeck", || {
+            assert_eq!(handler.current_pass().as_deref(), Some("typeck"));
+            handler.error("type mismatch");
+            handler.in_pass("infer", || handler.error("cannot infer type"));
+            handler.error("another mismatch");
+        });
+        assert_eq!(handler.current_pass(), None);
+        handler.error("outside");
+
+        assert_eq!(
+            emitter.captured(),
+            "error: [typeck] type mismatch\n\nerror: [infer] cannot infer type\n\n\
+             error: [typeck] another mismatch\n\nerror: outside\n\n"
+        );
+
+        // The pass is only rendered at debug verbosity
+        let (handler, emitter, _) = self::handler(DiagnosticsConfig::default(), "");
+        handler.in_pass("typeck", || handler.error("type mismatch"));
+        assert_eq!(emitter.captured(), "error: type mismatch\n\n");
+    }
+
+    #[test]
+    fn reset_counts() {
+        let (handler, emitter, _) = handler(DiagnosticsConfig::default(), "");
+        handler.error("an error");
+        handler.emit(Diagnostic::bug().with_message("a bug"));
+        assert!(handler.has_errors());
+
+        handler.reset_counts();
+        assert!(!handler.has_errors());
+        assert_eq!(handler.exit_code(), 0);
+        // Output which has already been emitted is unaffected
+        assert!(emitter.captured().contains("an error"));
+
+        handler.error("another error");
+        assert!(handler.has_errors());
+    }
+
+    #[test]
+    fn emit_raw_honors_silent() {
+        let (handler, emitter, _) = handler(DiagnosticsConfig::default(), "");
+        handler.error("before");
+        handler.emit_raw(b"+---+\n| a |\n+---+\n");
+        handler.emit_raw_str("after\n");
+        assert_eq!(
+            emitter.captured(),
+            "error: before\n\n+---+\n| a |\n+---+\nafter\n"
+        );
+
+        let config = DiagnosticsConfig {
+            verbosity: Verbosity::Silent,
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, _) = self::handler(config, "");
+        handler.emit_raw(b"table\n");
+        handler.emit_raw_str("text\n");
+        assert!(emitter.captured().is_empty());
+    }
+
+    #[test]
+    fn line_number_offset() {
+        let source = (1..=120)
+            .map(|i| format!("line {:03}\n", i))
+            .collect::<String>();
+        let config = DiagnosticsConfig {
+            line_number_offset: Some(-99),
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, id) = handler(config, &source);
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("in fragment")
+            .with_primary_label(span(id, 99 * 9, 99 * 9 + 4), "here")
+            .emit();
+        assert_eq!(
+            emitter.captured(),
+            "error: in fragment\n  ┌─ <test.masm>:1:1\n  │\n1 │ line 100\n  │ ^^^^ here\n\n"
+        );
+        assert_eq!(
+            handler
+                .display_location(&span(id, 99 * 9, 99 * 9 + 4))
+                .unwrap(),
+            (1, 1)
+        );
+    }
+
+    #[test]
+    fn check_errors() {
+        let (handler, _, _) = handler(DiagnosticsConfig::default(), "");
+        handler.warn("a warning");
+        assert!(handler.check_errors().is_ok());
+        handler.error("an error");
+        assert!(handler.check_errors().is_err());
+    }
+
+    #[test]
+    fn emit_sorted_is_deterministic() {
+        let (handler, emitter, id) = handler(DiagnosticsConfig::default(), "a b\n");
+        let diagnostic = |severity, message: &str, span: Option<SourceSpan>| {
+            let diagnostic = Diagnostic::new(severity).with_message(message);
+            match span {
+                Some(span) => diagnostic.with_labels(vec![Label::primary(id, span)]),
+                None => diagnostic,
+            }
+        };
+        handler.emit_sorted(vec![
+            diagnostic(Severity::Error, "no span", None),
+            diagnostic(Severity::Warning, "b warning", Some(span(id, 0, 1))),
+            diagnostic(Severity::Error, "second token", Some(span(id, 2, 3))),
+            diagnostic(Severity::Error, "b error", Some(span(id, 0, 1))),
+            diagnostic(Severity::Error, "a error", Some(span(id, 0, 1))),
+        ]);
+        let captured = emitter.captured();
+        let order = captured
+            .lines()
+            .filter(|line| line.starts_with("error") || line.starts_with("warning"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            [
+                "error: a error",
+                "error: b error",
+                "warning: b warning",
+                "error: second token",
+                "error: no span",
+            ]
+        );
+    }
+
+    #[test]
+    fn label_annotations_reach_structured_emitters() {
+        #[derive(Default)]
+        struct AnnotationEmitter {
+            annotations: Mutex<Vec<(usize, Option<f32>)>>,
+        }
+        impl Emitter for AnnotationEmitter {
+            fn buffer(&self) -> Buffer {
+                Buffer::no_color()
+            }
+            fn print(&self, _buffer: Buffer) -> std::io::Result<()> {
+                Ok(())
+            }
+            fn emit_structured(
+                &self,
+                _codemap: &CodeMap,
+                record: &DiagnosticRecord<'_>,
+            ) -> std::io::Result<bool> {
+                self.annotations.lock().extend(
+                    record
+                        .annotations
+                        .iter()
+                        .map(|a| (a.label, a.downcast_ref::<f32>().copied())),
+                );
+                Ok(true)
+            }
+        }
+
+        let source = "push.1 push.2\n";
+        let (plain, plain_emitter, id) = self::handler(DiagnosticsConfig::default(), source);
+        plain
+            .diagnostic(Severity::Warning)
+            .with_message("suspicious push")
+            .with_primary_label(span(id, 0, 6), "here")
+            .with_secondary_label(span(id, 7, 13), "and here")
+            .emit();
+        let (annotated, annotated_emitter, id) =
+            self::handler(DiagnosticsConfig::default(), source);
+        annotated
+            .diagnostic(Severity::Warning)
+            .with_message("suspicious push")
+            .with_primary_label(span(id, 0, 6), "here")
+            .with_secondary_label(span(id, 7, 13), "and here")
+            .with_label_annotation(0.25f32)
+            .emit();
+        assert_eq!(annotated_emitter.captured(), plain_emitter.captured());
+
+        let codemap = Arc::new(CodeMap::new());
+        let id = codemap.add("test.masm", source.to_string());
+        let emitter = Arc::new(AnnotationEmitter::default());
+        let handler =
+            DiagnosticsHandler::new(DiagnosticsConfig::default(), codemap, emitter.clone());
+        handler
+            .diagnostic(Severity::Warning)
+            .with_label_annotation("discarded")
+            .with_primary_label(span(id, 0, 6), "here")
+            .with_label_annotation(0.75f32)
+            .with_secondary_label(span(id, 7, 13), "and here")
+            .with_label_annotation("not a float")
+            .emit();
+        assert_eq!(
+            emitter.annotations.lock().as_slice(),
+            &[(0, Some(0.75)), (1, None)]
+        );
+    }
+
+    #[test]
+    fn conflict_renders_primaries_in_both_files() {
+        let (handler, emitter, a) = self::handler(DiagnosticsConfig::default(), "proc.foo\nend\n");
+        let b = handler
+            .codemap
+            .add("other.masm", "proc.foo\nend\n".to_string());
+        handler
+            .diagnostic(Severity::Error)
+            .with_conflict(span(a, 5, 8), span(b, 5, 8), "duplicate procedure `foo`")
+            .emit();
+        assert_eq!(
+            emitter.captured(),
+            "error: duplicate procedure `foo`\n  ┌─ <test.masm>:1:6\n  │\n1 │ proc.foo\n  │      ^^^ first defined here\n  │\n  ┌─ <other.masm>:1:6\n  │\n1 │ proc.foo\n  │      ^^^ redefined here\n\n"
+        );
+    }
+
+    #[test]
+    fn lint_registry_resolves_levels() {
+        let config = DiagnosticsConfig {
+            lint_levels: [("unused-local".to_string(), LintLevel::Allow)]
+                .into_iter()
+                .collect(),
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, id) = handler(config, "push.1\n");
+        handler.register_lint("shadowed-proc", LintLevel::Deny, "a procedure is shadowed");
+        handler.register_lint("unused-local", LintLevel::Warn, "a local is never read");
+        assert_eq!(handler.lint_level("shadowed-proc"), Some(LintLevel::Deny));
+        assert_eq!(handler.lint_level("unused-local"), Some(LintLevel::Allow));
+        assert_eq!(handler.lint_level("unknown"), None);
+        assert_eq!(handler.lint_registry().len(), 2);
+
+        handler.emit(
+            Diagnostic::warning()
+                .with_code("shadowed-proc")
+                .with_message("procedure `foo` shadows an import")
+                .with_labels(vec![Label::primary(id, span(id, 0, 6))]),
+        );
+        handler.emit(
+            Diagnostic::warning()
+                .with_code("unused-local")
+                .with_message("local 0 is never read"),
+        );
+        assert!(handler.has_errors());
+        let captured = emitter.captured();
+        assert!(captured.starts_with("error[shadowed-proc]: procedure `foo` shadows an import"));
+        assert!(!captured.contains("never read"));
+    }
+
+    #[test]
+    fn status_messages_go_to_status_emitter() {
+        let status = Arc::new(CaptureEmitter::new());
+        let config = DiagnosticsConfig {
+            status_emitter: Some(status.clone()),
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, id) = handler(config, "push.1\n");
+        handler.info("compiling test.masm");
+        handler.emit(
+            Diagnostic::error()
+                .with_message("invalid instruction")
+                .with_labels(vec![Label::primary(id, span(id, 0, 6))]),
+        );
+        assert!(status.captured().contains("compiling test.masm"));
+        assert!(!status.captured().contains("invalid instruction"));
+        assert!(emitter.captured().contains("error: invalid instruction"));
+        assert!(!emitter.captured().contains("compiling"));
+    }
+
+    #[test]
+    fn recorded_diagnostics_carry_sequence_and_thread() {
+        let config = DiagnosticsConfig {
+            record_diagnostics: true,
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, _emitter, _id) = handler(config, "push.1\n");
+        let main = std::thread::current().id();
+        handler.emit(Diagnostic::warning().with_message("from main"));
+        let worker = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    handler.emit(Diagnostic::warning().with_message("from worker"));
+                    std::thread::current().id()
+                })
+                .join()
+                .unwrap()
+        });
+
+        let recorded = handler.take_recorded();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[0].sequence, 0);
+        assert_eq!(recorded[0].thread, main);
+        assert_eq!(recorded[0].diagnostic.message, "from main");
+        assert_eq!(recorded[1].sequence, 1);
+        assert_eq!(recorded[1].thread, worker);
+        assert_eq!(recorded[1].diagnostic.message, "from worker");
+        assert_ne!(main, worker);
+        assert!(handler.take_recorded().is_empty());
+    }
+
+    #[test]
+    fn conditional_diagnostics() {
+        let (handler, emitter, _id) = handler(DiagnosticsConfig::default(), "");
+        let called = std::cell::Cell::new(false);
+        assert!(!handler.error_if(false, || {
+            called.set(true);
+            "unreachable"
+        }));
+        assert!(!handler.warn_if(false, || {
+            called.set(true);
+            "unreachable"
+        }));
+        assert!(!called.get());
+        assert!(emitter.captured().is_empty());
+        assert!(!handler.has_errors());
+
+        assert!(handler.warn_if(true, || "stack may underflow"));
+        assert!(!handler.has_errors());
+        assert!(handler.error_if(true, || "stack underflow"));
+        assert!(handler.has_errors());
+        let captured = emitter.captured();
+        assert!(captured.contains("warning: stack may underflow"));
+        assert!(captured.contains("error: stack underflow"));
+    }
+
+    #[test]
+    fn suggestion_title_is_retained_and_rendered() {
+        let source = "push.1 add\n";
+        let (handler, emitter, id) = self::handler(DiagnosticsConfig::default(), source);
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("missing operand")
+            .with_primary_label(span(id, 7, 10), "expects two operands")
+            .with_suggestion(span(id, 6, 6), " push.2", "push the second operand")
+            .emit();
+        assert!(emitter
+            .captured()
+            .contains("help: push the second operand\n"));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let codemap = Arc::new(CodeMap::new());
+        let id = codemap.add("test.masm", source.to_string());
+        let handler = DiagnosticsHandler::new(
+            DiagnosticsConfig::default(),
+            codemap,
+            Arc::new(crate::ChannelEmitter::new(sender)),
+        );
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("missing operand")
+            .with_suggestion(span(id, 6, 6), " push.2", "push the second operand")
+            .emit();
+        let emitted = receiver.recv().unwrap();
+        assert_eq!(
+            emitted.suggestions,
+            vec![Suggestion::new(
+                span(id, 6, 6),
+                " push.2",
+                "push the second operand"
+            )]
+        );
+    }
+
+    #[test]
+    fn program_name_prefixes_output() {
+        let config = DiagnosticsConfig {
+            program_name: Some("miden".to_string()),
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, id) = handler(config, "push.1\n");
+        handler.emit(
+            Diagnostic::error()
+                .with_message("invalid instruction")
+                .with_labels(vec![Label::primary(id, span(id, 0, 6))]),
+        );
+        handler.info("done");
+        let captured = emitter.captured();
+        assert!(captured.starts_with("miden: error: invalid instruction\n"));
+        assert!(captured.ends_with("\nmiden: info: done\n"));
+    }
+
+    #[test]
+    fn adjacent_labels_are_merged() {
+        let config = DiagnosticsConfig {
+            merge_adjacent_labels: true,
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, id) = handler(config, "push.abc\n");
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("invalid immediate")
+            .with_primary_label(span(id, 5, 6), "not a digit")
+            .with_primary_label(span(id, 6, 7), "not a digit")
+            .with_primary_label(span(id, 7, 8), "not a digit")
+            .with_secondary_label(span(id, 0, 4), "in this instruction")
+            .emit();
+        assert_eq!(
+            emitter.captured(),
+            "error: invalid immediate\n  ┌─ <test.masm>:1:6\n  │\n1 │ push.abc\n  │ ---- ^^^ not a digit\n  │ │     \n  │ in this instruction\n\n"
+        );
+
+        let mut diagnostic = Diagnostic::error().with_labels(vec![
+            Label::primary(id, span(id, 5, 6)).with_message("not a digit"),
+            Label::primary(id, span(id, 6, 7)).with_message("not a hex digit"),
+        ]);
+        merge_adjacent_labels(&mut diagnostic);
+        assert_eq!(diagnostic.labels.len(), 2);
+    }
+
+    #[test]
+    fn from_config_silent_emits_nothing() {
+        let config = DiagnosticsConfig {
+            verbosity: Verbosity::Silent,
+            color: ColorChoice::Always,
+            ..DiagnosticsConfig::default()
+        };
+        let codemap = Arc::new(CodeMap::new());
+        let id = codemap.add("test.masm", "push.1\n".to_string());
+        let handler = DiagnosticsHandler::from_config(config, codemap);
+        assert!(handler.silent);
+        handler.emit(
+            Diagnostic::error()
+                .with_message("invalid instruction")
+                .with_labels(vec![Label::primary(id, span(id, 0, 6))]),
+        );
+        handler.info("not shown");
+        handler.emit_raw(b"not shown either");
+        handler.finish();
+    }
+
+    #[test]
+    fn info_once_prints_once_per_key() {
+        let (handler, emitter, _id) = handler(DiagnosticsConfig::default(), "");
+        for _ in 0..3 {
+            handler.info_once("version", "miden v0.1.0");
+        }
+        handler.info_once("deprecation", "`exp.u` is deprecated");
+        let captured = emitter.captured();
+        assert_eq!(captured.matches("miden v0.1.0").count(), 1);
+        assert_eq!(captured.matches("is deprecated").count(), 1);
+
+        let config = DiagnosticsConfig {
+            verbosity: Verbosity::Warning,
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, _id) = self::handler(config, "");
+        handler.info_once("version", "miden v0.1.0");
+        assert!(emitter.captured().is_empty());
+    }
+
+    #[test]
+    fn recorded_source_error_can_be_downcast() {
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        struct UndefinedProc(String);
+        impl std::fmt::Display for UndefinedProc {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "undefined procedure `{}`", self.0)
+            }
+        }
+        impl std::error::Error for UndefinedProc {}
+        impl ToDiagnostic for UndefinedProc {
+            fn to_diagnostic(self) -> Diagnostic {
+                Diagnostic::error().with_message(self.to_string())
+            }
+        }
+
+        let config = DiagnosticsConfig {
+            record_diagnostics: true,
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, _id) = handler(config, "");
+        handler.emit_error(UndefinedProc("foo".to_string()));
+        handler.emit(Diagnostic::warning().with_message("not from an error"));
+        assert!(emitter
+            .captured()
+            .contains("error: undefined procedure `foo`"));
+
+        let recorded = handler.take_recorded();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(
+            recorded[0].downcast_ref::<UndefinedProc>(),
+            Some(&UndefinedProc("foo".to_string()))
+        );
+        assert!(recorded[0].downcast_ref::<std::fmt::Error>().is_none());
+        assert!(recorded[1].source.is_none());
+    }
+
+    #[test]
+    fn expanded_span_renders_both_sites() {
+        let (handler, emitter, id) = handler(DiagnosticsConfig::default(), "exec.swap_twice\n");
+        let expanded =
+            handler
+                .codemap
+                .add_child("swap_twice", "swap swap.x\n".to_string(), span(id, 5, 15));
+        let expansion = handler.codemap.expansion_of(span(expanded, 5, 11));
+        assert_eq!(expansion.call_site, span(id, 5, 15));
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("invalid instruction")
+            .with_expanded_span(expansion)
+            .emit();
+        assert_eq!(
+            emitter.captured(),
+            "error: invalid instruction\n  ┌─ <test.masm>:1:6\n  │\n1 │ exec.swap_twice\n  │      ^^^^^^^^^^ expanded from the macro here\n  │\n  ┌─ <swap_twice>:1:6\n  │\n1 │ swap swap.x\n  │      ------ in this macro definition\n\n"
+        );
+    }
+
+    #[test]
+    fn tree_is_rendered_with_nested_indentation() {
+        let (handler, emitter, id) = handler(DiagnosticsConfig::default(), "exec.foo\n");
+        let tree = DiagnosticTree::new(Severity::Error, "type mismatch")
+            .with_primary_label(span(id, 5, 8), "expected felt")
+            .with_child(
+                DiagnosticTree::new(Severity::Note, "`foo` returns u32").with_child(
+                    DiagnosticTree::new(Severity::Help, "convert with `u32cast`"),
+                ),
+            );
+        handler.emit_tree(tree);
+        assert_eq!(
+            emitter.captured(),
+            "error: type mismatch\n  ┌─ <test.masm>:1:6\n  │\n1 │ exec.foo\n  │      ^^^ expected felt\n\n    note: `foo` returns u32\n\n        help: convert with `u32cast`\n\n"
+        );
+    }
+
+    #[test]
+    fn fail_fast_raises_after_first_error() {
+        let config = DiagnosticsConfig {
+            fail_fast: true,
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, id) = handler(config, "push.1\nadd.x\n");
+        let reached = std::cell::Cell::new(false);
+        let result = catch_fatal(std::panic::AssertUnwindSafe(|| {
+            handler.emit(Diagnostic::warning().with_message("unused value"));
+            handler.emit(
+                Diagnostic::error()
+                    .with_message("invalid immediate")
+                    .with_labels(vec![Label::primary(id, span(id, 11, 12))]),
+            );
+            reached.set(true);
+            handler.emit(Diagnostic::error().with_message("never emitted"));
+        }));
+        assert!(result.is_err());
+        assert!(!reached.get());
+        let captured = emitter.captured();
+        assert!(captured.contains("warning: unused value"), "{}", captured);
+        assert!(
+            captured.contains("error: invalid immediate"),
+            "{}",
+            captured
+        );
+        assert!(captured.contains("add.x"), "{}", captured);
+        assert!(!captured.contains("never emitted"), "{}", captured);
+    }
+
+    #[test]
+    fn user_and_tool_errors_are_counted_separately() {
+        let (handler, _emitter, id) = handler(DiagnosticsConfig::default(), "push.1\n");
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("invalid instruction")
+            .with_primary_span(span(id, 0, 6))
+            .emit();
+        assert_eq!(handler.user_error_count(), 1);
+        assert_eq!(handler.tool_error_count(), 0);
+        assert_eq!(handler.exit_code(), 1);
+
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("could not read `lib.masm`")
+            .with_origin(DiagnosticOrigin::Tool)
+            .emit();
+        assert_eq!(handler.user_error_count(), 1);
+        assert_eq!(handler.tool_error_count(), 1);
+        assert_eq!(handler.exit_code(), 2);
+    }
+
+    #[test]
+    fn long_line_is_truncated_around_label() {
+        let config = DiagnosticsConfig {
+            max_label_width: Some(40),
+            ..DiagnosticsConfig::default()
+        };
+        let source = format!("{}add.x{}\n", "push.1 ".repeat(30), " drop".repeat(30));
+        let (handler, emitter, id) = handler(config, &source);
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("invalid immediate")
+            .with_primary_label(span(id, 214, 215), "expected a number")
+            .emit();
+        // The caret stays beneath the `x` of `add.x`, and the column is that of the original
+        assert_eq!(
+            emitter.captured(),
+            "error: invalid immediate\n  ┌─ <test.masm>:1:215\n  │\n1 │ … push.1 push.1 add.x drop drop drop drop…\n  │                     ^ expected a number\n\n"
+        );
+    }
+
+    #[test]
+    fn merge_counts_from_children() {
+        let (parent, _, _) = handler(DiagnosticsConfig::default(), "");
+        let (first, _, _) = self::handler(DiagnosticsConfig::default(), "");
+        let (second, _, _) = self::handler(DiagnosticsConfig::default(), "");
+        parent.error("in parent");
+        first.error("in first");
+        first.error("also in first");
+        second.emit(Diagnostic::bug().with_message("in second"));
+
+        parent.merge_counts_from(&first);
+        parent.merge_counts_from(&second);
+        assert_eq!(parent.user_error_count(), 3);
+        assert_eq!(parent.tool_error_count(), 1);
+        assert_eq!(parent.exit_code(), 2);
+        assert_eq!(first.user_error_count(), 2);
+        assert_eq!(second.tool_error_count(), 1);
+    }
+
+    #[test]
+    fn custom_severity_labels() {
+        let config = DiagnosticsConfig {
+            severity_labels: SeverityLabels {
+                error: "Fehler".to_string(),
+                help: "Hilfe".to_string(),
+                ..SeverityLabels::default()
+            },
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, id) = handler(config, "let x = foo\n");
+        handler.emit(
+            Diagnostic::error()
+                .with_code("E001")
+                .with_message("unknown function")
+                .with_labels(vec![Label::primary(id, span(id, 8, 11))]),
+        );
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("missing semicolon")
+            .with_primary_label(span(id, 8, 11), "here")
+            .with_suggestion(span(id, 11, 11), ";", "add `;`")
+            .emit();
+
+        let captured = emitter.captured();
+        assert!(
+            captured.starts_with("Fehler[E001]: unknown function\n"),
+            "{}",
+            captured
+        );
+        assert!(
+            captured.contains("Fehler: missing semicolon\n"),
+            "{}",
+            captured
+        );
+        assert!(captured.contains("Hilfe: add `;`\n"), "{}", captured);
+        assert!(!captured.contains("error"), "{}", captured);
+        assert!(!captured.contains("help"), "{}", captured);
+    }
+
+    #[test]
+    fn lines_between_distant_labels_are_collapsed() {
+        let source = (1..=60)
+            .map(|i| format!("line {:02}\n", i))
+            .collect::<String>();
+        let (handler, emitter, id) = handler(DiagnosticsConfig::default(), &source);
+        let line = |n: u32| span(id, (n - 1) * 8, (n - 1) * 8 + 4);
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("mismatch")
+            .with_primary_label(line(5), "first")
+            .with_secondary_label(line(55), "second")
+            .emit();
+
+        let captured = emitter.captured();
+        assert!(captured.contains(" 5 │ line 05\n"), "{}", captured);
+        assert!(captured.contains("55 │ line 55\n"), "{}", captured);
+        assert!(captured.contains("   ·\n"), "{}", captured);
+        assert!(!captured.contains("line 30"), "{}", captured);
+    }
+
+    #[test]
+    fn exit_code() {
+        let (handler, _, _) = handler(DiagnosticsConfig::default(), "");
+        handler.emit(Diagnostic::warning().with_message("unused"));
+        assert_eq!(handler.exit_code(), 0);
+        handler.emit(Diagnostic::error().with_message("mismatch"));
+        assert_eq!(handler.exit_code(), 1);
+        handler.emit(Diagnostic::bug().with_message("oops"));
+        assert_eq!(handler.exit_code(), 2);
+
+        let (bugged, _, _) = self::handler(DiagnosticsConfig::default(), "");
+        bugged.emit(Diagnostic::bug().with_message("oops"));
+        assert!(bugged.has_errors());
+        assert_eq!(bugged.user_error_count(), 0);
+        assert_eq!(bugged.tool_error_count(), 1);
+        assert_eq!(bugged.exit_code(), 2);
+    }
+
+    #[test]
+    fn plain_config_renders_ascii_within_width() {
+        let config = DiagnosticsConfig::plain(40);
+        assert_eq!(config.color, ColorChoice::Never);
+        assert!(
+            !DiagnosticsHandler::from_config(config.clone(), Arc::new(CodeMap::new()))
+                .supports_color()
+        );
+
+        let (handler, emitter, id) = handler(config, "let x = foo(1);\n");
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("the function `foo` is not defined in this scope, or any parent scope")
+            .with_primary_label(span(id, 8, 11), "not found")
+            .with_note("functions must be declared before they are referenced in a procedure")
+            .emit();
+
+        let captured = emitter.captured();
+        assert!(captured.is_ascii(), "{}", captured);
+        assert!(captured.contains("-->"), "{}", captured);
+        for line in captured.lines() {
+            assert!(line.len() <= 40, "line exceeds width: {:?}", line);
+        }
+    }
+
+    #[test]
+    fn min_code_width_truncates_gutter() {
+        let config = DiagnosticsConfig {
+            wrap_width: Some(24),
+            min_code_width: Some(16),
+            line_number_offset: Some(1_234_560),
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, id) = handler(config, "let value = foo;\n");
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("bad")
+            .with_primary_label(span(id, 12, 15), "here")
+            .with_note("aaaa bbbb cccc dddd eeee ffff")
+            .emit();
+
+        let captured = emitter.captured();
+        // The header keeps the full line number, while the gutter keeps its last 5 digits
+        assert!(captured.contains(":1234561:13\n"), "{}", captured);
+        assert!(
+            captured.contains("34561 │ let value = foo;\n"),
+            "{}",
+            captured
+        );
+        for line in captured.lines().filter(|line| line.contains('│')) {
+            // The gutter is followed by `│ `
+            let gutter_width = line[..line.find('│').unwrap()].chars().count() + 2;
+            assert!(24 - gutter_width >= 16, "code area too narrow: {:?}", line);
+        }
+        assert!(captured.contains("= aaaa bbbb cccc\n"), "{}", captured);
+    }
+
+    #[test]
+    fn location_base_is_per_handler() {
+        let codemap = Arc::new(CodeMap::new());
+        let id = codemap.add("test.masm", "let x = 1;\n".to_string());
+        let span = span(id, 4, 5);
+        let handler = |one_based| {
+            let config = DiagnosticsConfig {
+                one_based_lines: one_based,
+                one_based_columns: one_based,
+                ..DiagnosticsConfig::default()
+            };
+            DiagnosticsHandler::new(config, codemap.clone(), Arc::new(CaptureEmitter::new()))
+        };
+        let one_based = handler(true);
+        let zero_based = handler(false);
+
+        assert_eq!(one_based.display_location(&span).unwrap(), (1, 5));
+        assert_eq!(zero_based.display_location(&span).unwrap(), (0, 4));
+        // The codemap shared by both handlers is unaffected by either of them
+        let location = codemap.location(&span).unwrap();
+        assert_eq!(
+            (location.line, location.column),
+            (LineIndex(0), ColumnIndex(4))
+        );
+    }
+
+    #[test]
+    fn max_gap_lines_expands_short_gaps() {
+        let source = (1..=60)
+            .map(|i| format!("line {:02}\n", i))
+            .collect::<String>();
+        let render = |max_gap_lines, second: u32| {
+            let config = DiagnosticsConfig {
+                max_gap_lines,
+                ..DiagnosticsConfig::default()
+            };
+            let (handler, emitter, id) = handler(config, &source);
+            let line = |n: u32| span(id, (n - 1) * 8, (n - 1) * 8 + 4);
+            handler
+                .diagnostic(Severity::Error)
+                .with_message("mismatch")
+                .with_primary_label(line(5), "first")
+                .with_secondary_label(line(second), "second")
+                .emit();
+            emitter.captured()
+        };
+
+        // A single line between labels is always shown, which is how every line of a short
+        // enough gap is expected to be shown
+        let captured = render(None, 7);
+        assert!(captured.contains("\n6 │ line 06\n"), "{}", captured);
+
+        let captured = render(Some(3), 9);
+        for line in [
+            "5 │ line 05\n",
+            "6 │ line 06\n",
+            "7 │ line 07\n",
+            "8 │ line 08\n",
+        ] {
+            assert!(captured.contains(line), "{}", captured);
+        }
+        assert!(!captured.contains('·'), "{}", captured);
+        assert!(render(None, 9).contains("  ·\n"));
+
+        // Longer gaps are still collapsed
+        let captured = render(Some(3), 10);
+        assert!(captured.contains("  ·\n"), "{}", captured);
+        assert!(!captured.contains("line 07"), "{}", captured);
+    }
+
+    #[test]
+    fn throttled_diagnostics_are_summarized() {
+        let config = DiagnosticsConfig {
+            verbosity: Verbosity::Warning,
+            ..DiagnosticsConfig::default()
+        };
+        let (handler, emitter, _) = handler(config, "");
+        handler.throttle("W001", 3);
+        for i in 0..10 {
+            handler.emit(
+                Diagnostic::warning()
+                    .with_code("W001")
+                    .with_message(format!("warning {}", i)),
+            );
+        }
+        handler.finish();
+
+        let captured = emitter.captured();
+        assert_eq!(captured.matches("warning[W001]").count(), 3);
+        assert!(captured.contains("warning[W001]: warning 2\n"));
+        assert!(!captured.contains("warning 3"));
+        assert!(
+            captured.ends_with("note: ... and 7 more diagnostics with code W001\n\n"),
+            "{}",
+            captured
+        );
     }
 }
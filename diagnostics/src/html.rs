@@ -0,0 +1,266 @@
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::*;
+
+/// Renders `diagnostic` as a fragment of semantic HTML.
+///
+/// The output does not carry any inline styles, instead every element is tagged with a CSS
+/// class so that the embedding page is free to theme it. The structure is as follows:
+///
+/// * A `div.diagnostic` container, additionally classed by severity, e.g. `diagnostic-error`
+/// * A `div.diagnostic-header` containing the severity, optional code, and message
+/// * For each file referenced by a label, a `div.diagnostic-snippet` containing the file
+///   location, followed by a `pre.diagnostic-source` with one `span.diagnostic-line` per line.
+///   The labeled regions of each line are wrapped in `span.diagnostic-label` elements, classed
+///   by label style, e.g. `diagnostic-label-primary`. Label messages follow the source in a
+///   `ul.diagnostic-label-messages`.
+/// * A `ul.diagnostic-notes` containing the notes, if any
+///
+/// All source content and messages are escaped, so the result is safe to embed in a page.
+///
+/// The severity is displayed using the default English labels, see [render_html_with_labels]
+/// to display it using custom [SeverityLabels].
+pub fn render_html(codemap: &CodeMap, diagnostic: &Diagnostic) -> String {
+    render_html_with_labels(codemap, diagnostic, &SeverityLabels::default())
+}
+
+/// Renders `diagnostic` as a fragment of semantic HTML, like [render_html], displaying its
+/// severity using `labels`.
+///
+/// The class names always use the default English labels, so that stylesheets need not be
+/// localized.
+pub fn render_html_with_labels(
+    codemap: &CodeMap,
+    diagnostic: &Diagnostic,
+    labels: &SeverityLabels,
+) -> String {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "<div class=\"diagnostic diagnostic-{}\">",
+        severity_name(diagnostic.severity)
+    )
+    .unwrap();
+    out.push_str("<div class=\"diagnostic-header\">");
+    out.push_str("<span class=\"diagnostic-severity\">");
+    escape_into(&mut out, labels.get(diagnostic.severity));
+    out.push_str("</span>");
+    if let Some(code) = diagnostic.code.as_deref() {
+        out.push_str("<span class=\"diagnostic-code\">[");
+        escape_into(&mut out, code);
+        out.push_str("]</span>");
+    }
+    out.push_str(": <span class=\"diagnostic-message\">");
+    escape_into(&mut out, &diagnostic.message);
+    out.push_str("</span></div>\n");
+
+    // Render one snippet per file, in the order in which the files are first referenced
+    let mut file_ids = Vec::<SourceId>::new();
+    for label in diagnostic.labels.iter() {
+        if !file_ids.contains(&label.file_id) {
+            file_ids.push(label.file_id);
+        }
+    }
+    for file_id in file_ids {
+        if let Ok(file) = codemap.get(file_id) {
+            let labels = diagnostic
+                .labels
+                .iter()
+                .filter(|l| l.file_id == file_id)
+                .collect::<Vec<_>>();
+            render_snippet(&mut out, &file, labels.as_slice());
+        }
+    }
+
+    if !diagnostic.notes.is_empty() {
+        out.push_str("<ul class=\"diagnostic-notes\">\n");
+        for note in diagnostic.notes.iter() {
+            out.push_str("<li class=\"diagnostic-note\">");
+            escape_into(&mut out, note);
+            out.push_str("</li>\n");
+        }
+        out.push_str("</ul>\n");
+    }
+    out.push_str("</div>\n");
+    out
+}
+
+fn render_snippet(out: &mut String, file: &SourceFile, labels: &[&Label]) {
+    let source = file.source();
+    let clamp = |i: usize| i.min(source.len());
+    let first_line = labels
+        .iter()
+        .map(|l| file.line_index(ByteIndex(clamp(l.range.start) as u32)))
+        .min()
+        .unwrap();
+    let last_line = labels
+        .iter()
+        .map(|l| file.line_index(ByteIndex(clamp(l.range.end) as u32)))
+        .max()
+        .unwrap();
+
+    out.push_str("<div class=\"diagnostic-snippet\">\n");
+    out.push_str("<div class=\"diagnostic-location\">");
+    escape_into(out, &file.name().to_string());
+    if let Ok(loc) = file.location(ByteIndex(clamp(labels[0].range.start) as u32)) {
+        write!(out, ":{}:{}", loc.line.number(), loc.column.number()).unwrap();
+    }
+    out.push_str("</div>\n");
+
+    out.push_str("<pre class=\"diagnostic-source\">");
+    let mut line_index = first_line;
+    while line_index <= last_line {
+        let line_span = match file.line_span(line_index) {
+            Ok(span) => span,
+            Err(_) => break,
+        };
+        let start = line_span.start().to_usize();
+        let text = source[start..line_span.end().to_usize()].trim_end_matches(['\n', '\r']);
+        let end = start + text.len();
+        write!(
+            out,
+            "<span class=\"diagnostic-line\"><span class=\"diagnostic-line-number\">{}</span>",
+            line_index.number()
+        )
+        .unwrap();
+        out.push_str("<span class=\"diagnostic-line-content\">");
+        render_line(out, source, start..end, labels);
+        out.push_str("</span></span>\n");
+        line_index += LineOffset::from(1);
+    }
+    out.push_str("</pre>\n");
+
+    let mut messages = labels.iter().filter(|l| !l.message.is_empty()).peekable();
+    if messages.peek().is_some() {
+        out.push_str("<ul class=\"diagnostic-label-messages\">\n");
+        for label in messages {
+            write!(
+                out,
+                "<li class=\"diagnostic-label-message diagnostic-label-{}\">",
+                style_name(label.style)
+            )
+            .unwrap();
+            if let Ok(loc) = file.location(ByteIndex(clamp(label.range.start) as u32)) {
+                write!(out, "{}:{}: ", loc.line.number(), loc.column.number()).unwrap();
+            }
+            escape_into(out, &label.message);
+            out.push_str("</li>\n");
+        }
+        out.push_str("</ul>\n");
+    }
+    out.push_str("</div>\n");
+}
+
+/// Renders the content of a single line, wrapping each region covered by a label in a span.
+///
+/// Where labels overlap, primary labels take precedence over secondary ones.
+fn render_line(out: &mut String, source: &str, line: Range<usize>, labels: &[&Label]) {
+    let mut boundaries = vec![line.start, line.end];
+    for label in labels.iter() {
+        for i in [label.range.start, label.range.end] {
+            if i > line.start && i < line.end && source.is_char_boundary(i) {
+                boundaries.push(i);
+            }
+        }
+    }
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    for segment in boundaries.windows(2) {
+        let (start, end) = (segment[0], segment[1]);
+        let style = labels
+            .iter()
+            .filter(|l| l.range.start <= start && l.range.end >= end)
+            .map(|l| l.style)
+            .min_by_key(|style| matches!(style, LabelStyle::Secondary));
+        let text = &source[start..end];
+        match style {
+            Some(style) => {
+                write!(
+                    out,
+                    "<span class=\"diagnostic-label diagnostic-label-{}\">",
+                    style_name(style)
+                )
+                .unwrap();
+                escape_into(out, text);
+                out.push_str("</span>");
+            }
+            None => escape_into(out, text),
+        }
+    }
+}
+
+fn severity_name(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Bug => "bug",
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+        Severity::Note => "note",
+        Severity::Help => "help",
+    }
+}
+
+fn style_name(style: LabelStyle) -> &'static str {
+    match style {
+        LabelStyle::Primary => "primary",
+        LabelStyle::Secondary => "secondary",
+    }
+}
+
+fn escape_into(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_is_wrapped_and_source_is_escaped() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "if a < b && c {\n".to_string());
+        let span = SourceSpan::new(
+            SourceIndex::new(id, ByteIndex(3)),
+            SourceIndex::new(id, ByteIndex(8)),
+        );
+        let diagnostic = Diagnostic::error()
+            .with_message("expected <bool>")
+            .with_labels(vec![Label::primary(id, span).with_message("here")]);
+        let html = render_html(&codemap, &diagnostic);
+
+        assert!(html.starts_with("<div class=\"diagnostic diagnostic-error\">"));
+        assert!(html.contains("<span class=\"diagnostic-message\">expected &lt;bool&gt;</span>"));
+        assert!(html.contains(
+            "<span class=\"diagnostic-line-content\">if \
+             <span class=\"diagnostic-label diagnostic-label-primary\">a &lt; b</span> \
+             &amp;&amp; c {</span>"
+        ));
+        assert!(html.contains(
+            "<li class=\"diagnostic-label-message diagnostic-label-primary\">1:4: here</li>"
+        ));
+    }
+
+    #[test]
+    fn custom_labels_keep_default_classes() {
+        let codemap = CodeMap::new();
+        let diagnostic = Diagnostic::error().with_message("boom");
+        let labels = SeverityLabels {
+            error: "erreur".to_string(),
+            ..SeverityLabels::default()
+        };
+        let html = render_html_with_labels(&codemap, &diagnostic, &labels);
+
+        assert!(html.starts_with("<div class=\"diagnostic diagnostic-error\">"));
+        assert!(html.contains("<span class=\"diagnostic-severity\">erreur</span>"));
+    }
+}
@@ -1,13 +1,16 @@
-use std::num::NonZeroUsize;
-use std::ops::{Add, AddAssign, Sub, SubAssign};
+use core::num::NonZeroUsize;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
 
-use codespan::{ByteIndex, ByteOffset, RawIndex, RawOffset};
-
-use super::SourceId;
+use super::{ByteIndex, ByteOffset, RawIndex, RawOffset, SourceId};
 
 /// [SourceIndex] is a compact representation of a byte index in a specific source file.
 ///
 /// It has a canonical representation for "unknown" indices, similar to that of [crate::span::SourceSpan]
+///
+/// Indices in the same file are ordered by their byte index. Indices in different files are
+/// ordered by [SourceId] first, which gives a total order suitable for sorting and use as a
+/// map key, but has no meaning with respect to the content of the files. Use `distance_to`
+/// when the relative position of two indices matters.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct SourceIndex(NonZeroUsize);
 impl SourceIndex {
@@ -43,6 +46,16 @@ impl SourceIndex {
         ByteIndex((self.0.get() & Self::INDEX_MASK) as u32)
     }
 
+    /// Returns the signed distance in bytes from this index to `other`, i.e. `other - self`
+    ///
+    /// Returns `None` if the indices are in different files, or either is unknown.
+    pub fn distance_to(&self, other: SourceIndex) -> Option<ByteOffset> {
+        if self.source_id() != other.source_id() || self.source_id() == SourceId::UNKNOWN {
+            return None;
+        }
+        Some(other.index() - self.index())
+    }
+
     #[doc(hidden)]
     pub fn to_usize(&self) -> usize {
         self.0.get()
@@ -119,3 +132,32 @@ impl SubAssign<usize> for SourceIndex {
         *self = *self - rhs;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This module must not depend on `std`, as it is run with `--no-default-features`
+    #[test]
+    fn distance_to() {
+        let a = SourceId::new(1);
+        let b = SourceId::new(2);
+        let start = SourceIndex::new(a, ByteIndex(4));
+        let end = SourceIndex::new(a, ByteIndex(10));
+
+        assert_eq!(start.distance_to(end), Some(ByteOffset(6)));
+        assert_eq!(end.distance_to(start), Some(ByteOffset(-6)));
+        assert_eq!(start.distance_to(start), Some(ByteOffset(0)));
+        assert_eq!(start.distance_to(SourceIndex::new(b, ByteIndex(10))), None);
+        assert_eq!(SourceIndex::UNKNOWN.distance_to(SourceIndex::UNKNOWN), None);
+    }
+
+    #[test]
+    fn ordering() {
+        let a = SourceId::new(1);
+        let b = SourceId::new(2);
+
+        assert!(SourceIndex::new(a, ByteIndex(4)) < SourceIndex::new(a, ByteIndex(10)));
+        assert!(SourceIndex::new(a, ByteIndex(10)) < SourceIndex::new(b, ByteIndex(4)));
+    }
+}
@@ -0,0 +1,384 @@
+use std::fmt::Write as _;
+use std::io::Write;
+
+use parking_lot::Mutex;
+
+use crate::term::termcolor::Buffer;
+use crate::*;
+
+/// [RustcJsonEmitter] emits diagnostics as JSON Lines, using the same schema as `rustc`'s
+/// `--error-format=json` output, so that existing tooling which consumes that format can be
+/// used unchanged.
+///
+/// Each diagnostic is written as a single JSON object on its own line. Notes and child
+/// diagnostics are represented as `children`, nested as deeply as the [DiagnosticTree]
+/// they form, and the `rendered` field contains the diagnostic as it would be rendered
+/// without color, using the display configuration of the [DiagnosticsHandler]. Spans are resolved to file names,
+/// line, and column numbers (1-based, as in `rustc`) via the [CodeMap].
+///
+/// Output which is not a diagnostic, e.g. from [DiagnosticsHandler::info], is written as-is.
+pub struct RustcJsonEmitter {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+impl RustcJsonEmitter {
+    /// Construct a new [RustcJsonEmitter] which writes to `writer`
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    /// Construct a new [RustcJsonEmitter] which writes to stderr, as `rustc` does
+    pub fn stderr() -> Self {
+        Self::new(std::io::stderr())
+    }
+}
+impl Emitter for RustcJsonEmitter {
+    #[inline]
+    fn buffer(&self) -> Buffer {
+        Buffer::no_color()
+    }
+
+    fn print(&self, buffer: Buffer) -> std::io::Result<()> {
+        self.writer.lock().write_all(buffer.as_slice())
+    }
+
+    fn emit_structured(
+        &self,
+        codemap: &CodeMap,
+        record: &DiagnosticRecord<'_>,
+    ) -> std::io::Result<bool> {
+        let rendered = record.render();
+
+        let mut json = String::new();
+        write_diagnostic(
+            &mut json,
+            codemap,
+            record.diagnostic,
+            record.children,
+            record.suggestions,
+            record.annotations,
+            Some(&rendered),
+        );
+        json.push('\n');
+        self.writer.lock().write_all(json.as_bytes())?;
+        Ok(true)
+    }
+}
+
+fn write_diagnostic(
+    out: &mut String,
+    codemap: &CodeMap,
+    diagnostic: &Diagnostic,
+    children: &[DiagnosticTree],
+    suggestions: &[Suggestion],
+    annotations: &[LabelAnnotation],
+    rendered: Option<&str>,
+) {
+    if rendered.is_some() {
+        out.push_str("{\"$message_type\":\"diagnostic\",\"message\":");
+    } else {
+        out.push_str("{\"message\":");
+    }
+    write_str(out, &diagnostic.message);
+    out.push_str(",\"code\":");
+    match diagnostic.code.as_deref() {
+        Some(code) => {
+            out.push_str("{\"code\":");
+            write_str(out, code);
+            out.push_str(",\"explanation\":null}");
+        }
+        None => out.push_str("null"),
+    }
+    out.push_str(",\"level\":");
+    write_str(out, level(diagnostic.severity));
+
+    out.push_str(",\"spans\":[");
+    let mut first = true;
+    for (index, label) in diagnostic.labels.iter().enumerate() {
+        if let Ok(file) = codemap.get(label.file_id) {
+            if !first {
+                out.push(',');
+            }
+            first = false;
+            let annotations = annotations.iter().filter(|a| a.label == index);
+            write_span(out, &file, label, None, annotations);
+        }
+    }
+
+    out.push_str("],\"children\":[");
+    let notes = diagnostic
+        .notes
+        .iter()
+        .map(|note| Diagnostic::note().with_message(note.clone()));
+    for (i, note) in notes.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_diagnostic(out, codemap, &note, &[], &[], &[], None);
+    }
+    for (i, child) in children.iter().enumerate() {
+        if i > 0 || !diagnostic.notes.is_empty() {
+            out.push(',');
+        }
+        write_diagnostic(
+            out,
+            codemap,
+            &child.diagnostic,
+            &child.children,
+            &[],
+            &[],
+            None,
+        );
+    }
+    for (i, suggestion) in suggestions.iter().enumerate() {
+        if i > 0 || !diagnostic.notes.is_empty() || !children.is_empty() {
+            out.push(',');
+        }
+        write_suggestion(out, codemap, suggestion);
+    }
+
+    out.push_str("],\"rendered\":");
+    match rendered {
+        Some(rendered) => write_str(out, rendered),
+        None => out.push_str("null"),
+    }
+    out.push('}');
+}
+
+/// Writes `suggestion` as a child diagnostic, whose span carries the suggested replacement
+fn write_suggestion(out: &mut String, codemap: &CodeMap, suggestion: &Suggestion) {
+    out.push_str("{\"message\":");
+    write_str(out, &suggestion.title);
+    out.push_str(",\"code\":null,\"level\":\"help\",\"spans\":[");
+    if let Ok(file) = codemap.get(suggestion.span.source_id()) {
+        let label = suggestion.span.into_primary_label();
+        write_span(out, &file, &label, Some(&suggestion.replacement), [].iter());
+    }
+    out.push_str("],\"children\":[],\"rendered\":null}");
+}
+
+/// Writes the span of `label`, along with the first of its `annotations` which is JSON, if the
+/// `serde` feature is enabled
+fn write_span<'a>(
+    out: &mut String,
+    file: &SourceFile,
+    label: &Label,
+    replacement: Option<&str>,
+    annotations: impl Iterator<Item = &'a LabelAnnotation>,
+) {
+    let len = file.source().len();
+    let start = ByteIndex(label.range.start.min(len) as u32);
+    let end = ByteIndex(label.range.end.min(len).max(start.to_usize()) as u32);
+    let start_loc = file.location(start).unwrap_or(Location {
+        line: LineIndex(0),
+        column: ColumnIndex(0),
+    });
+    let end_loc = file.location(end).unwrap_or(start_loc);
+    let name = file.name();
+
+    out.push_str("{\"file_name\":");
+    match name.as_str() {
+        Some(name) => write_str(out, name),
+        None => write_str(out, &name.to_string()),
+    }
+    write!(
+        out,
+        ",\"byte_start\":{},\"byte_end\":{},\"line_start\":{},\"line_end\":{},\"column_start\":{},\"column_end\":{},\"is_primary\":{}",
+        start.to_usize(),
+        end.to_usize(),
+        start_loc.line.number(),
+        end_loc.line.number(),
+        start_loc.column.number(),
+        end_loc.column.number(),
+        label.style == LabelStyle::Primary,
+    )
+    .unwrap();
+
+    out.push_str(",\"text\":[");
+    let mut line = start_loc.line;
+    while line <= end_loc.line {
+        let text = match file.line_span(line) {
+            Ok(span) => file.source()[span.start().to_usize()..span.end().to_usize()]
+                .trim_end_matches(['\n', '\r']),
+            Err(_) => break,
+        };
+        if line > start_loc.line {
+            out.push(',');
+        }
+        let highlight_start = if line == start_loc.line {
+            start_loc.column.number()
+        } else {
+            ColumnIndex(0).number()
+        };
+        let highlight_end = if line == end_loc.line {
+            end_loc.column.number()
+        } else {
+            ColumnIndex(text.chars().count() as u32).number()
+        };
+        out.push_str("{\"text\":");
+        write_str(out, text);
+        write!(
+            out,
+            ",\"highlight_start\":{},\"highlight_end\":{}}}",
+            highlight_start, highlight_end
+        )
+        .unwrap();
+        line += LineOffset::from(1);
+    }
+
+    out.push_str("],\"label\":");
+    if label.message.is_empty() {
+        out.push_str("null");
+    } else {
+        write_str(out, &label.message);
+    }
+    out.push_str(",\"suggested_replacement\":");
+    match replacement {
+        Some(replacement) => {
+            write_str(out, replacement);
+            out.push_str(",\"suggestion_applicability\":\"Unspecified\"");
+        }
+        None => out.push_str("null,\"suggestion_applicability\":null"),
+    }
+    out.push_str(",\"expansion\":null");
+    #[cfg(feature = "serde")]
+    if let Some(value) = annotations.filter_map(LabelAnnotation::as_json).next() {
+        write!(out, ",\"annotation\":{}", value).unwrap();
+    }
+    #[cfg(not(feature = "serde"))]
+    let _ = annotations;
+    out.push('}');
+}
+
+fn level(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Bug => "error: internal compiler error",
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+        Severity::Note => "note",
+        Severity::Help => "help",
+    }
+}
+
+/// Writes `s` as a JSON string literal
+pub(crate) fn write_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::term::DisplayStyle;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rustc_json_golden() {
+        let codemap = Arc::new(CodeMap::new());
+        let id = codemap.add("test.rs", "let x: i32 = \"a\";\n".to_string());
+        let output = SharedBuffer::default();
+        let mut config = DiagnosticsConfig::default();
+        config.display.display_style = DisplayStyle::Short;
+        let handler = DiagnosticsHandler::new(
+            config,
+            codemap,
+            Arc::new(RustcJsonEmitter::new(output.clone())),
+        );
+        handler.emit(
+            Diagnostic::error()
+                .with_code("E0308")
+                .with_message("mismatched types")
+                .with_labels(vec![
+                    Label::primary(id, 13..16).with_message("expected `i32`, found `&str`")
+                ])
+                .with_notes(vec!["expected due to this type".to_string()]),
+        );
+
+        let output = String::from_utf8(output.0.lock().clone()).unwrap();
+        assert_eq!(
+            output,
+            concat!(
+                r#"{"$message_type":"diagnostic","message":"mismatched types","#,
+                r#""code":{"code":"E0308","explanation":null},"level":"error","#,
+                r#""spans":[{"file_name":"test.rs","byte_start":13,"byte_end":16,"#,
+                r#""line_start":1,"line_end":1,"column_start":14,"column_end":17,"#,
+                r#""is_primary":true,"text":[{"text":"let x: i32 = \"a\";","#,
+                r#""highlight_start":14,"highlight_end":17}],"#,
+                r#""label":"expected `i32`, found `&str`","suggested_replacement":null,"#,
+                r#""suggestion_applicability":null,"expansion":null}],"#,
+                r#""children":[{"message":"expected due to this type","code":null,"#,
+                r#""level":"note","spans":[],"children":[],"rendered":null}],"#,
+                r#""rendered":"<test.rs>:1:14: error[E0308]: mismatched types\n"}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn json_label_annotations_are_emitted() {
+        let codemap = Arc::new(CodeMap::new());
+        let id = codemap.add("test.rs", "let x: i32 = \"a\";\n".to_string());
+        let span = |start, end| {
+            SourceSpan::new(
+                SourceIndex::new(id, ByteIndex(start)),
+                SourceIndex::new(id, ByteIndex(end)),
+            )
+        };
+        let output = SharedBuffer::default();
+        let mut config = DiagnosticsConfig::default();
+        config.display.display_style = DisplayStyle::Short;
+        let handler = DiagnosticsHandler::new(
+            config,
+            codemap,
+            Arc::new(RustcJsonEmitter::new(output.clone())),
+        );
+        handler
+            .diagnostic(Severity::Error)
+            .with_message("mismatched types")
+            .with_primary_label(span(13, 16), "found `&str`")
+            .with_label_annotation(serde_json::json!({ "confidence": 0.25 }))
+            .with_secondary_label(span(7, 10), "expected `i32`")
+            .with_label_annotation(0.5f32)
+            .emit();
+
+        let output = String::from_utf8(output.0.lock().clone()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        let spans = json["spans"].as_array().unwrap();
+        assert_eq!(
+            spans[0]["annotation"],
+            serde_json::json!({ "confidence": 0.25 })
+        );
+        // Annotations which are not JSON are not emitted
+        assert!(spans[1].get("annotation").is_none(), "{}", output);
+        assert_eq!(
+            json["rendered"],
+            "<test.rs>:1:14: error: mismatched types\n",
+        );
+    }
+}
@@ -1,46 +1,147 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))]
+extern crate alloc;
+// Allows the derives to be tested within this crate, as they refer to it by name
+#[cfg(all(test, feature = "std"))]
+extern crate self as miden_diagnostics;
+
+#[cfg(feature = "std")]
+mod anchor;
+#[cfg(feature = "std")]
+mod annotated;
+mod byte;
+#[cfg(feature = "std")]
+mod channel;
+#[cfg(feature = "std")]
 mod codemap;
+#[cfg(feature = "std")]
+mod concat;
+#[cfg(feature = "std")]
 mod config;
+#[cfg(feature = "std")]
+mod detached;
+#[cfg(feature = "std")]
 mod diagnostic;
+#[cfg(feature = "std")]
 mod emitter;
+#[cfg(feature = "std")]
 mod filename;
+#[cfg(feature = "std")]
+mod grouped;
+#[cfg(feature = "std")]
 mod handler;
+#[cfg(feature = "std")]
+mod html;
 mod index;
+#[cfg(feature = "std")]
+mod json;
+#[cfg(feature = "std")]
+mod lint;
+#[cfg(feature = "std")]
 mod source;
+mod source_id;
 mod span;
+#[cfg(feature = "std")]
+mod suggestion;
+#[cfg(feature = "test-util")]
+mod testing;
+#[cfg(feature = "std")]
+mod tree;
+#[cfg(feature = "std")]
+mod truncate;
+#[cfg(feature = "std")]
+mod wrap;
 
+#[cfg(feature = "std")]
 pub use codespan::Location;
-pub use codespan::{ByteIndex, ByteOffset};
+#[cfg(feature = "std")]
 pub use codespan::{ColumnIndex, ColumnNumber, ColumnOffset};
+#[cfg(feature = "std")]
 pub use codespan::{Index, Offset};
+#[cfg(feature = "std")]
 pub use codespan::{LineIndex, LineNumber, LineOffset};
-pub use codespan::{RawIndex, RawOffset};
 
+pub use self::byte::{ByteIndex, ByteOffset, RawIndex, RawOffset};
+
+#[cfg(feature = "std")]
 pub use codespan_reporting::diagnostic::{LabelStyle, Severity};
+#[cfg(feature = "std")]
 pub use codespan_reporting::files::{Error, Files};
+#[cfg(feature = "std")]
 pub use codespan_reporting::term;
 
+#[cfg(feature = "std")]
 pub use miden_diagnostics_macros::*;
 
-pub use self::codemap::CodeMap;
-pub use self::config::{DiagnosticsConfig, Verbosity};
-pub use self::diagnostic::InFlightDiagnostic;
-pub use self::emitter::{CaptureEmitter, DefaultEmitter, Emitter, NullEmitter};
+#[cfg(feature = "std")]
+pub use self::anchor::{Anchor, SourceEdit};
+#[cfg(feature = "std")]
+pub use self::annotated::render_annotated_file;
+#[cfg(feature = "std")]
+pub use self::channel::{ChannelEmitter, EmittedDiagnostic, LabelLocation};
+#[cfg(feature = "sealed")]
+pub use self::codemap::SealedCodeMap;
+#[cfg(feature = "std")]
+pub use self::codemap::{CodeMap, ResolvedSpan, SourceLookup};
+#[cfg(feature = "std")]
+pub use self::concat::ConcatView;
+#[cfg(feature = "std")]
+pub use self::config::{DiagnosticsConfig, SeverityLabels, Verbosity};
+#[cfg(feature = "std")]
+pub use self::detached::{render_detached, DetachedDiagnostic};
+#[cfg(feature = "std")]
+pub use self::diagnostic::{
+    fingerprint, merge_adjacent_labels, primary_label, primary_span, DiagnosticOrigin,
+    InFlightDiagnostic, LabelAnnotation,
+};
+#[cfg(feature = "tracing")]
+pub use self::emitter::TracingEmitter;
+#[cfg(feature = "std")]
+pub use self::emitter::{
+    strip_ansi, CallbackEmitter, CaptureEmitter, DefaultEmitter, DiagnosticRecord, Emitter,
+    EmitterStats, MeteringEmitter, NullEmitter, RingBufferEmitter,
+};
+#[cfg(feature = "std")]
 pub use self::filename::FileName;
-pub use self::handler::DiagnosticsHandler;
+#[cfg(feature = "std")]
+pub use self::grouped::render_grouped;
+#[cfg(feature = "std")]
+pub use self::handler::{
+    Checkpoint, DiagnosticTransaction, DiagnosticsHandler, RecordedDiagnostic,
+};
+#[cfg(feature = "std")]
+pub use self::html::{render_html, render_html_with_labels};
 pub use self::index::SourceIndex;
-pub use self::source::{SourceFile, SourceId};
-pub use self::span::{SourceSpan, Span, Spanned};
+#[cfg(feature = "std")]
+pub use self::json::RustcJsonEmitter;
+#[cfg(feature = "std")]
+pub use self::lint::{Lint, LintLevel, LintRegistry};
+#[cfg(feature = "std")]
+pub use self::source::{SourceFile, SourceText};
+pub use self::source_id::SourceId;
+pub use self::span::{map_spanned, ExpandedSpan, SourceSpan, Span, Spanned};
+#[cfg(feature = "std")]
+pub use self::suggestion::Suggestion;
+#[cfg(feature = "test-util")]
+pub use self::testing::CapturedDiagnostics;
+#[cfg(feature = "std")]
+pub use self::tree::DiagnosticTree;
 
+#[cfg(feature = "std")]
 pub type Diagnostic = codespan_reporting::diagnostic::Diagnostic<SourceId>;
+#[cfg(feature = "std")]
 pub type Label = codespan_reporting::diagnostic::Label<SourceId>;
 
 /// [ToDiagnostic] should be implemented on types which can be converted to a [Diagnostic].
 ///
 /// This is largely intended for implementations of [std::error::Error], but may be implemented
 /// for any type that has a canonical [Diagnostic] representation.
+#[cfg(feature = "std")]
 pub trait ToDiagnostic {
     fn to_diagnostic(self) -> Diagnostic;
 }
+#[cfg(feature = "std")]
 impl ToDiagnostic for Diagnostic {
     #[inline(always)]
     fn to_diagnostic(self) -> Diagnostic {
@@ -48,20 +149,134 @@ impl ToDiagnostic for Diagnostic {
     }
 }
 
+#[cfg(feature = "std")]
 #[doc(hidden)]
 pub struct FatalErrorMarker;
 
 /// Used as a return value to signify a fatal error occurred
+#[cfg(feature = "std")]
 #[derive(Copy, Clone, Debug)]
 #[must_use]
 pub struct FatalError;
+#[cfg(feature = "std")]
 impl FatalError {
     pub fn raise(self) -> ! {
         std::panic::resume_unwind(Box::new(FatalErrorMarker))
     }
 }
+#[cfg(feature = "std")]
 impl core::fmt::Display for FatalError {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "The compiler has encountered a fatal error")
     }
 }
+#[cfg(feature = "std")]
+impl std::error::Error for FatalError {}
+
+/// Runs `f`, catching a [FatalError] raised during it, and returning it as `Err`
+///
+/// Any other panic is propagated as usual. Since raising a [FatalError] unwinds the stack,
+/// `f` must be [std::panic::UnwindSafe]. Shared state which is captured by reference, such as
+/// a [DiagnosticsHandler], generally is not, in which case `f` can be wrapped in
+/// [std::panic::AssertUnwindSafe], so long as that state is not relied upon to be consistent
+/// after a fatal error, beyond what was emitted before it was raised.
+#[cfg(feature = "std")]
+pub fn catch_fatal<F, T>(f: F) -> Result<T, FatalError>
+where
+    F: FnOnce() -> T + std::panic::UnwindSafe,
+{
+    std::panic::catch_unwind(f).map_err(|payload| {
+        if payload.is::<FatalErrorMarker>() {
+            FatalError
+        } else {
+            std::panic::resume_unwind(payload)
+        }
+    })
+}
+
+/// Runs `f` as a compilation, translating a [FatalError] raised during it into the exit code
+/// which should be returned from the process, i.e. `Err(1)`.
+///
+/// This is the glue code needed at the top level of a typical compiler driver, see
+/// [catch_fatal] for details on the requirements of `f`. Use [DiagnosticsHandler::exit_code]
+/// for the exit code when `f` completes normally.
+#[cfg(feature = "std")]
+pub fn run_compilation<F, T>(f: F) -> Result<T, i32>
+where
+    F: FnOnce() -> T + std::panic::UnwindSafe,
+{
+    catch_fatal(f).map_err(|_| 1)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[derive(ToDiagnostic)]
+    enum ParseError {
+        #[diag(error, "unexpected token {token}")]
+        UnexpectedToken {
+            #[label(primary, "expected an expression")]
+            span: SourceSpan,
+            token: String,
+        },
+        #[diag(warning, "unused import")]
+        UnusedImport(#[label(primary)] SourceSpan),
+    }
+
+    #[test]
+    fn derived_to_diagnostic() {
+        let codemap = Arc::new(CodeMap::new());
+        let id = codemap.add("test.masm", "use foo\nlet x = ;\n".to_string());
+        let span = |start, end| {
+            SourceSpan::new(
+                SourceIndex::new(id, ByteIndex(start)),
+                SourceIndex::new(id, ByteIndex(end)),
+            )
+        };
+
+        let diagnostic = ParseError::UnexpectedToken {
+            span: span(16, 17),
+            token: ";".to_string(),
+        }
+        .to_diagnostic();
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.message, "unexpected token ;");
+        assert_eq!(diagnostic.labels.len(), 1);
+        assert_eq!(diagnostic.labels[0].style, LabelStyle::Primary);
+        assert_eq!(diagnostic.labels[0].range, 16..17);
+        assert_eq!(diagnostic.labels[0].message, "expected an expression");
+
+        let diagnostic = ParseError::UnusedImport(span(0, 7)).to_diagnostic();
+        assert_eq!(diagnostic.severity, Severity::Warning);
+        assert_eq!(diagnostic.message, "unused import");
+        assert_eq!(diagnostic.labels[0].range, 0..7);
+        assert!(diagnostic.labels[0].message.is_empty());
+
+        let emitter = Arc::new(CaptureEmitter::new());
+        let handler =
+            DiagnosticsHandler::new(DiagnosticsConfig::default(), codemap, emitter.clone());
+        handler.emit(ParseError::UnusedImport(span(0, 7)));
+        let captured = emitter.captured();
+        assert!(
+            captured.starts_with("warning: unused import\n"),
+            "{}",
+            captured
+        );
+    }
+
+    #[test]
+    fn run_compilation_translates_fatal_errors() {
+        let result: Result<(), i32> = run_compilation(|| FatalError.raise());
+        assert_eq!(result, Err(1));
+        assert_eq!(run_compilation(|| 42), Ok(42));
+    }
+
+    #[test]
+    #[should_panic(expected = "not fatal")]
+    fn catch_fatal_propagates_other_panics() {
+        let _ = catch_fatal(|| panic!("not fatal"));
+    }
+}
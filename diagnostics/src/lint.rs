@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+
+use crate::Severity;
+
+/// The level at which a lint is reported
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LintLevel {
+    /// Diagnostics for the lint are not emitted
+    Allow,
+    /// Diagnostics for the lint are emitted as warnings
+    Warn,
+    /// Diagnostics for the lint are emitted as errors
+    Deny,
+}
+impl LintLevel {
+    /// Returns the severity at which diagnostics are emitted for this level, if at all
+    pub fn severity(self) -> Option<Severity> {
+        match self {
+            Self::Allow => None,
+            Self::Warn => Some(Severity::Warning),
+            Self::Deny => Some(Severity::Error),
+        }
+    }
+}
+
+/// Describes a known lint, see [LintRegistry]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    /// The name of the lint, which is also the code of diagnostics raised for it
+    pub name: String,
+    /// The level of the lint, unless overridden via [crate::DiagnosticsConfig]
+    pub default_level: LintLevel,
+    /// A short description of what the lint checks for
+    pub description: String,
+}
+
+/// A [LintRegistry] is the set of lints known to a compiler.
+///
+/// When registered with a [crate::DiagnosticsHandler], any diagnostic whose code is the name
+/// of a registered lint is emitted at the effective level of that lint, i.e. the level given
+/// in `lint_levels` of [crate::DiagnosticsConfig], or if absent, the lint's default level.
+/// Diagnostics with other codes are unaffected.
+#[derive(Debug, Default, Clone)]
+pub struct LintRegistry {
+    lints: BTreeMap<String, Lint>,
+}
+impl LintRegistry {
+    /// Registers a lint named `name`, replacing any existing lint of the same name
+    pub fn register_lint(
+        &mut self,
+        name: impl Into<String>,
+        default_level: LintLevel,
+        description: impl ToString,
+    ) {
+        let name = name.into();
+        self.lints.insert(
+            name.clone(),
+            Lint {
+                name,
+                default_level,
+                description: description.to_string(),
+            },
+        );
+    }
+
+    /// Returns the lint named `name`, if registered
+    pub fn get(&self, name: &str) -> Option<&Lint> {
+        self.lints.get(name)
+    }
+
+    /// Returns true if a lint named `name` is registered
+    ///
+    /// This can be used to reject unknown lint names given on the command line.
+    pub fn contains(&self, name: &str) -> bool {
+        self.lints.contains_key(name)
+    }
+
+    /// Returns the number of registered lints
+    pub fn len(&self) -> usize {
+        self.lints.len()
+    }
+
+    /// Returns true if no lints are registered
+    pub fn is_empty(&self) -> bool {
+        self.lints.is_empty()
+    }
+
+    /// Returns an iterator over the registered lints, ordered by name
+    pub fn iter(&self) -> impl Iterator<Item = &Lint> + '_ {
+        self.lints.values()
+    }
+}
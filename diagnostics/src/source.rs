@@ -1,37 +1,26 @@
 use std::convert::Into;
-use std::num::NonZeroU32;
+use std::fmt;
 use std::ops::Range;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
 
-use super::*;
-
-/// A handle that points to a file in the codemap.
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct SourceId(pub(crate) NonZeroU32);
-impl SourceId {
-    pub(crate) const UNKNOWN_SOURCE_ID: u32 = u32::MAX;
-
-    pub const UNKNOWN: Self = Self(unsafe { NonZeroU32::new_unchecked(Self::UNKNOWN_SOURCE_ID) });
-
-    pub(crate) fn new(index: u32) -> Self {
-        assert!(index > 0);
-        assert!(index < Self::UNKNOWN_SOURCE_ID);
-        Self(NonZeroU32::new(index).unwrap())
-    }
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
 
-    #[inline]
-    pub(crate) fn get(self) -> u32 {
-        self.0.get()
-    }
-}
+use super::*;
 
 /// The representation of a source file in the database.
 #[derive(Debug, Clone)]
 pub struct SourceFile {
     id: SourceId,
     name: FileName,
-    source: String,
-    line_starts: Vec<ByteIndex>,
+    content: LazyContent,
     parent: Option<SourceSpan>,
+    /// The modification time of the file on disk when it was read, if known
+    modified: Option<SystemTime>,
+    /// The index of the line most recently found by `line_index`, as a hint for the next lookup
+    last_line: LineHint,
 }
 impl SourceFile {
     pub(crate) fn new(
@@ -40,19 +29,52 @@ impl SourceFile {
         source: String,
         parent: Option<SourceSpan>,
     ) -> Self {
-        let line_starts = codespan_reporting::files::line_starts(source.as_str())
-            .map(|i| ByteIndex::from(i as u32))
-            .collect();
-
         Self {
             id,
             name,
-            source,
-            line_starts,
+            content: LazyContent::loaded(Content::new(source)),
             parent,
+            modified: None,
+            last_line: LineHint::default(),
         }
     }
 
+    /// Creates a [SourceFile] whose content is produced by `loader` when first accessed
+    pub(crate) fn lazy(id: SourceId, name: FileName, loader: Loader) -> Self {
+        Self {
+            id,
+            name,
+            content: LazyContent::lazy(loader),
+            parent: None,
+            modified: None,
+            last_line: LineHint::default(),
+        }
+    }
+
+    /// Loads the content of this file, if it was added lazily and has not been loaded yet
+    ///
+    /// Returns `Err` if loading fails. A [CodeMap] never hands out a file which failed to load,
+    /// so the content of a file is only ever accessed once it has been loaded successfully.
+    pub(crate) fn load(&self) -> Result<(), Error> {
+        self.content.get().map(|_| ())
+    }
+
+    /// Returns true if the content of this file has been loaded, see [CodeMap::add_lazy]
+    pub(crate) fn is_loaded(&self) -> bool {
+        self.content.is_loaded()
+    }
+
+    fn content(&self) -> &Content {
+        static EMPTY: Lazy<Content> = Lazy::new(|| Content::new(String::new()));
+
+        self.content.get().unwrap_or_else(|_| &EMPTY)
+    }
+
+    #[inline(always)]
+    fn line_starts(&self) -> &[ByteIndex] {
+        &self.content().line_starts
+    }
+
     /// Returns the [FileName] associated with this [SourceFile]
     pub fn name(&self) -> &FileName {
         &self.name
@@ -63,17 +85,42 @@ impl SourceFile {
         self.id
     }
 
+    pub(crate) fn set_id(&mut self, id: SourceId) {
+        self.id = id;
+    }
+
+    pub(crate) fn set_name(&mut self, name: FileName) {
+        self.name = name;
+    }
+
+    /// Returns the modification time of this file on disk at the time it was read
+    ///
+    /// This is only known for files read from disk via [CodeMap::add_file], and only if the
+    /// platform supports it, so it is `None` for virtual files. Tools which watch for changes
+    /// can compare it against the current modification time of the file to decide whether
+    /// to reload it.
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    pub(crate) fn set_modified(&mut self, modified: Option<SystemTime>) {
+        self.modified = modified;
+    }
+
     /// Returns the parent [SourceSpan] for this [SourceFile]
     pub fn parent(&self) -> Option<SourceSpan> {
         self.parent
     }
 
     /// Computes the [ByteIndex] at which the line corresponding to `line_index` begins
+    ///
+    /// For convenience, the line following the last line is considered to begin at the end
+    /// of the file.
     pub fn line_start(&self, line_index: LineIndex) -> Result<ByteIndex, Error> {
         use std::cmp::Ordering;
 
-        match line_index.cmp(&self.last_line_index()) {
-            Ordering::Less => Ok(self.line_starts[line_index.to_usize()]),
+        match line_index.to_usize().cmp(&self.line_starts().len()) {
+            Ordering::Less => Ok(self.line_starts()[line_index.to_usize()]),
             Ordering::Equal => Ok(self.source_span().end_index()),
             Ordering::Greater => Err(Error::LineTooLarge {
                 given: line_index.to_usize(),
@@ -82,24 +129,80 @@ impl SourceFile {
         }
     }
 
-    /// Returns the [LineIndex] of the last line in this file
+    /// Returns the number of lines in this file
+    ///
+    /// Each line consists of its content followed by its line terminator, if any. A line
+    /// terminator at the end of the file does not start a new line, so `"abc"` and `"abc\n"`
+    /// both have one line, while `"abc\n\n"` has two, the second of which is empty. An empty
+    /// file has a single empty line.
+    pub fn line_count(&self) -> usize {
+        self.line_starts().len()
+    }
+
+    /// Returns true if `index` is the first byte of a line, see `line_count`
+    pub fn is_line_start(&self, index: ByteIndex) -> bool {
+        self.line_starts().binary_search(&index).is_ok()
+    }
+
+    /// Returns true if `index` is at the end of a line, i.e. it is followed by a line
+    /// terminator (`\n` or `\r\n`), or is the end of the file.
+    pub fn is_line_end(&self, index: ByteIndex) -> bool {
+        let rest = match self.source().get(index.to_usize()..) {
+            Some(rest) => rest,
+            None => return false,
+        };
+        rest.is_empty() || rest.starts_with('\n') || rest.starts_with("\r\n")
+    }
+
+    /// Returns the [LineIndex] of the last line in this file, see `line_count`
     pub fn last_line_index(&self) -> LineIndex {
-        LineIndex::from(self.line_starts.len() as RawIndex)
+        LineIndex::from((self.line_starts().len() - 1) as RawIndex)
     }
 
+    /// Returns the span of the line at `line_index`, including its line terminator, if any
     pub(crate) fn line_span(&self, line_index: LineIndex) -> Result<codespan::Span, Error> {
+        if line_index > self.last_line_index() {
+            return Err(Error::LineTooLarge {
+                given: line_index.to_usize(),
+                max: self.last_line_index().to_usize(),
+            });
+        }
         let line_start = self.line_start(line_index)?;
         let next_line_start = self.line_start(line_index + LineOffset::from(1))?;
 
         Ok(codespan::Span::new(line_start, next_line_start))
     }
 
+    /// Returns the [LineIndex] of the line containing `byte_index`
+    ///
+    /// The end of the file is considered to be part of the last line.
     pub(crate) fn line_index(&self, byte_index: ByteIndex) -> LineIndex {
-        match self.line_starts.binary_search(&byte_index) {
-            // Found the start of a line
-            Ok(line) => LineIndex::from(line as u32),
-            Err(next_line) => LineIndex::from(next_line as u32 - 1),
-        }
+        // Lookups tend to be sequential, so check the most recently found line, and the one
+        // after it, before falling back to a search of the whole file
+        let hint = self.last_line.0.load(Ordering::Relaxed) as usize;
+        let contains = |line: usize| match self.line_starts().get(line) {
+            Some(start) => {
+                *start <= byte_index
+                    && self
+                        .line_starts()
+                        .get(line + 1)
+                        .map_or(true, |next| byte_index < *next)
+            }
+            None => false,
+        };
+        let line = if contains(hint) {
+            hint
+        } else if contains(hint + 1) {
+            hint + 1
+        } else {
+            match self.line_starts().binary_search(&byte_index) {
+                // Found the start of a line
+                Ok(line) => line,
+                Err(next_line) => next_line - 1,
+            }
+        };
+        self.last_line.0.store(line as u32, Ordering::Relaxed);
+        LineIndex::from(line as u32)
     }
 
     pub(crate) fn line_column_to_span(
@@ -110,8 +213,7 @@ impl SourceFile {
         let column_index = column_index.to_usize();
         let line_span = self.line_span(line_index)?;
         let line_src = self
-            .source
-            .as_str()
+            .source()
             .get(line_span.start().to_usize()..line_span.end().to_usize())
             .unwrap();
         if line_src.len() < column_index {
@@ -122,9 +224,8 @@ impl SourceFile {
             });
         }
         let (pre, _) = line_src.split_at(column_index);
-        let start = line_span.start();
-        let offset = ByteOffset::from_str_len(pre);
-        Ok(codespan::Span::new(start + offset, start + offset))
+        let start = ByteIndex::from(line_span.start()) + ByteOffset::from_str_len(pre);
+        Ok(codespan::Span::new(start, start))
     }
 
     /// Returns a [Location] corresponding to the given byte index in this file.
@@ -138,8 +239,7 @@ impl SourceFile {
                 max: self.source().len() - 1,
             })?;
         let line_src = self
-            .source
-            .as_str()
+            .source()
             .get(line_start_index.to_usize()..byte_index.to_usize())
             .ok_or_else(|| {
                 let given = byte_index.to_usize();
@@ -158,9 +258,47 @@ impl SourceFile {
     }
 
     /// Returns the underlying content of this file as a string slice
+    ///
+    /// The content of a file added via [CodeMap::add_lazy] is loaded on first access, and is
+    /// empty if loading fails.
     #[inline(always)]
     pub fn source(&self) -> &str {
-        self.source.as_str()
+        self.content().source.as_str()
+    }
+
+    /// Returns the underlying content of this file as a byte slice
+    #[inline(always)]
+    pub fn as_bytes(&self) -> &[u8] {
+        self.source().as_bytes()
+    }
+
+    /// Returns an iterator over the lines of this file, yielding the [LineIndex], [SourceSpan],
+    /// and content of each line.
+    ///
+    /// The span and content of each line exclude the line terminator (i.e. `\n` or `\r\n`).
+    /// One item is yielded per line, as defined by `line_count`, so if the file ends with a
+    /// line terminator, no empty line is yielded after it.
+    pub fn lines(&self) -> impl Iterator<Item = (LineIndex, SourceSpan, &str)> + '_ {
+        let source = self.source();
+        self.line_starts()
+            .iter()
+            .enumerate()
+            .map(move |(i, start)| {
+                let start = start.to_usize();
+                let end = self
+                    .line_starts()
+                    .get(i + 1)
+                    .map(|next| next.to_usize())
+                    .unwrap_or(source.len());
+                let line = source[start..end].trim_end_matches('\n');
+                let line = line.strip_suffix('\r').unwrap_or(line);
+                let span = SourceSpan {
+                    source_id: self.id,
+                    start: ByteIndex(start as u32),
+                    end: ByteIndex((start + line.len()) as u32),
+                };
+                (LineIndex(i as u32), span, line)
+            })
     }
 
     /// Returns a [SourceSpan] covering all of the content in this file
@@ -168,7 +306,20 @@ impl SourceFile {
         SourceSpan {
             source_id: self.id,
             start: ByteIndex(0),
-            end: ByteIndex(self.source.len() as u32),
+            end: ByteIndex(self.source().len() as u32),
+        }
+    }
+
+    /// Returns a zero-width [SourceSpan] at the end of this file, i.e. just past the last
+    /// character of its content.
+    ///
+    /// This is the conventional location for diagnostics about unexpected end of input.
+    pub fn eof_span(&self) -> SourceSpan {
+        let end = ByteIndex(self.source().len() as u32);
+        SourceSpan {
+            source_id: self.id,
+            start: end,
+            end,
         }
     }
 
@@ -189,3 +340,260 @@ impl SourceFile {
         })
     }
 }
+
+/// A function which produces the content of a file added via [CodeMap::add_lazy]
+pub(crate) type Loader = Box<dyn FnOnce() -> std::io::Result<String> + Send>;
+
+/// The content of a [SourceFile], and the start of each line in it
+struct Content {
+    source: String,
+    line_starts: Vec<ByteIndex>,
+}
+impl Content {
+    fn new(source: String) -> Self {
+        let mut line_starts = codespan_reporting::files::line_starts(source.as_str())
+            .map(|i| ByteIndex::from(i as u32))
+            .collect::<Vec<_>>();
+        // A line terminator at the end of the file does not start a new line, see `line_count`
+        if line_starts.len() > 1 && line_starts.last().unwrap().to_usize() == source.len() {
+            line_starts.pop();
+        }
+        Self {
+            source,
+            line_starts,
+        }
+    }
+}
+
+/// The [Content] of a [SourceFile], which may be loaded on first access
+///
+/// Clones share the same content, so that a lazily-added file is loaded at most once, even
+/// if it is renamed before it is loaded.
+#[derive(Clone)]
+struct LazyContent(Arc<(OnceCell<Content>, Mutex<Option<Loader>>)>);
+impl LazyContent {
+    fn loaded(content: Content) -> Self {
+        Self(Arc::new((OnceCell::with_value(content), Mutex::new(None))))
+    }
+
+    fn lazy(loader: Loader) -> Self {
+        Self(Arc::new((OnceCell::new(), Mutex::new(Some(loader)))))
+    }
+
+    fn is_loaded(&self) -> bool {
+        self.0 .0.get().is_some()
+    }
+
+    /// Returns the content, loading it first if necessary
+    ///
+    /// Concurrent callers block until the content has been loaded by one of them. If loading
+    /// fails, the error is returned to that caller, and `Error::FileMissing` to all others.
+    fn get(&self) -> Result<&Content, Error> {
+        let (content, loader) = &*self.0;
+        content.get_or_try_init(|| {
+            let loader = loader.lock().take().ok_or(Error::FileMissing)?;
+            Ok(Content::new(loader().map_err(Error::Io)?))
+        })
+    }
+}
+impl fmt::Debug for LazyContent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 .0.get() {
+            Some(content) => f.debug_tuple("Loaded").field(&content.source).finish(),
+            None => f.write_str("Unloaded"),
+        }
+    }
+}
+
+/// A thread-safe hint used to speed up sequential line lookups
+#[derive(Debug, Default)]
+struct LineHint(AtomicU32);
+impl Clone for LineHint {
+    fn clone(&self) -> Self {
+        Self(AtomicU32::new(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+/// [SourceText] is a handle to the content of a [SourceFile] obtained from a [CodeMap].
+///
+/// Since it owns a reference to the underlying [SourceFile], any content borrowed from it
+/// remains valid for as long as the [SourceText] itself, regardless of what happens to
+/// other references to the file.
+#[derive(Debug, Clone)]
+pub struct SourceText(Arc<SourceFile>);
+impl SourceText {
+    pub(crate) fn new(file: Arc<SourceFile>) -> Self {
+        Self(file)
+    }
+
+    /// Returns the [SourceFile] this text belongs to
+    #[inline]
+    pub fn file(&self) -> &SourceFile {
+        &self.0
+    }
+
+    /// Returns the full content of the file as a string slice
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        self.0.source()
+    }
+
+    /// Returns the full content of the file as a byte slice
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+
+    /// Returns the content corresponding to `span` as a string slice
+    ///
+    /// Returns `None` if `span` belongs to a different file, or is out of bounds.
+    pub fn slice(&self, span: SourceSpan) -> Option<&str> {
+        if span.source_id() != self.0.id() {
+            return None;
+        }
+        self.0.source_slice(span).ok()
+    }
+}
+impl AsRef<str> for SourceText {
+    #[inline]
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+impl AsRef<[u8]> for SourceText {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(source: &str) -> SourceFile {
+        SourceFile::new(SourceId::new(1), "test".into(), source.to_string(), None)
+    }
+
+    fn line(file: &SourceFile, line: u32) -> Range<usize> {
+        let span = file.line_span(LineIndex(line)).unwrap();
+        span.start().to_usize()..span.end().to_usize()
+    }
+
+    #[test]
+    fn line_without_terminator() {
+        let file = file("abc");
+        assert_eq!(file.line_count(), 1);
+        assert_eq!(file.last_line_index(), LineIndex(0));
+        assert_eq!(line(&file, 0), 0..3);
+        assert_eq!(file.line_index(ByteIndex(3)), LineIndex(0));
+        assert!(file.line_span(LineIndex(1)).is_err());
+    }
+
+    #[test]
+    fn line_with_terminator() {
+        let file = file("abc\n");
+        assert_eq!(file.line_count(), 1);
+        assert_eq!(file.last_line_index(), LineIndex(0));
+        assert_eq!(line(&file, 0), 0..4);
+        assert_eq!(file.line_index(ByteIndex(4)), LineIndex(0));
+        assert!(file.line_span(LineIndex(1)).is_err());
+    }
+
+    #[test]
+    fn empty_line_after_terminator() {
+        let file = file("abc\n\n");
+        assert_eq!(file.line_count(), 2);
+        assert_eq!(file.last_line_index(), LineIndex(1));
+        assert_eq!(line(&file, 0), 0..4);
+        assert_eq!(line(&file, 1), 4..5);
+        assert_eq!(file.line_index(ByteIndex(4)), LineIndex(1));
+        assert_eq!(file.line_index(ByteIndex(5)), LineIndex(1));
+        assert!(file.line_span(LineIndex(2)).is_err());
+    }
+
+    #[test]
+    fn empty_file() {
+        let file = file("");
+        assert_eq!(file.line_count(), 1);
+        assert_eq!(line(&file, 0), 0..0);
+    }
+
+    #[test]
+    fn lines_with_spans() {
+        let file = file("a\r\nbb\nccc");
+        let lines = file.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        let expected = [(0, 1, "a"), (3, 5, "bb"), (6, 9, "ccc")];
+        for (i, (line, (start, end, text))) in lines.iter().zip(expected).enumerate() {
+            assert_eq!(line.0, LineIndex(i as u32));
+            assert_eq!(Range::<usize>::from(line.1), start..end);
+            assert_eq!(line.2, text);
+            assert_eq!(file.source_slice(line.1).unwrap(), text);
+        }
+
+        let file = self::file("a\nb\n");
+        assert_eq!(
+            file.lines().map(|(_, _, text)| text).collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+    }
+
+    #[test]
+    fn eof_span_is_zero_width_at_end() {
+        let file = file("let x = 1;\n");
+        let span = file.eof_span();
+        assert_eq!(span.source_id(), file.id());
+        assert_eq!(span.start_index(), ByteIndex(11));
+        assert_eq!(span.end_index(), ByteIndex(11));
+
+        let span = self::file("").eof_span();
+        assert_eq!(span.start_index(), ByteIndex(0));
+        assert_eq!(span.end_index(), ByteIndex(0));
+    }
+
+    #[test]
+    fn line_index_is_consistent_regardless_of_lookup_order() {
+        let file = file("a\nbc\n\ndef\nghij");
+        let expected = |offset: u32| match offset {
+            0..=1 => 0,
+            2..=4 => 1,
+            5 => 2,
+            6..=9 => 3,
+            _ => 4,
+        };
+        let len = file.source().len() as u32;
+        for offset in (0..=len).chain((0..=len).rev()) {
+            assert_eq!(
+                file.line_index(ByteIndex(offset)),
+                LineIndex(expected(offset)),
+                "offset {}",
+                offset
+            );
+        }
+        for offset in [12, 0, 7, 3, 5, 14, 1, 6] {
+            assert_eq!(
+                file.line_index(ByteIndex(offset)),
+                LineIndex(expected(offset))
+            );
+        }
+    }
+
+    #[test]
+    fn line_starts_and_ends() {
+        // Lines: "ab\r\n" (0..4), "\n" (4..5), "cd" (5..7)
+        let file = file("ab\r\n\ncd");
+        for index in [0, 4, 5] {
+            assert!(file.is_line_start(ByteIndex(index)), "{}", index);
+        }
+        for index in [1, 2, 6, 7, 8] {
+            assert!(!file.is_line_start(ByteIndex(index)), "{}", index);
+        }
+        for index in [2, 4, 7] {
+            assert!(file.is_line_end(ByteIndex(index)), "{}", index);
+        }
+        for index in [0, 1, 5, 6, 8] {
+            assert!(!file.is_line_end(ByteIndex(index)), "{}", index);
+        }
+    }
+}
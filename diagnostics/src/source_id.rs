@@ -0,0 +1,48 @@
+use core::num::NonZeroU32;
+
+/// A handle that points to a file in the codemap.
+///
+/// Internally this is represented as a [NonZeroU32], as valid ids are always
+/// assigned starting from 1. This means that `Option<SourceId>` is the same size
+/// as `SourceId`, so it is cheap to store optional ids in syntax trees and the like.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SourceId(pub(crate) NonZeroU32);
+impl SourceId {
+    pub(crate) const UNKNOWN_SOURCE_ID: u32 = u32::MAX;
+
+    pub const UNKNOWN: Self = Self(unsafe { NonZeroU32::new_unchecked(Self::UNKNOWN_SOURCE_ID) });
+
+    pub(crate) fn new(index: u32) -> Self {
+        assert!(index > 0);
+        assert!(index < Self::UNKNOWN_SOURCE_ID);
+        Self(NonZeroU32::new(index).unwrap())
+    }
+
+    #[inline]
+    pub(crate) fn get(self) -> u32 {
+        self.0.get()
+    }
+}
+
+// Ensure that `Option<SourceId>` benefits from the niche in `NonZeroU32`
+const _: () = assert!(core::mem::size_of::<Option<SourceId>>() == core::mem::size_of::<SourceId>());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn option_is_niche_optimized() {
+        assert_eq!(
+            core::mem::size_of::<Option<SourceId>>(),
+            core::mem::size_of::<SourceId>()
+        );
+        assert_eq!(core::mem::size_of::<SourceId>(), 4);
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_is_not_a_valid_id() {
+        SourceId::new(0);
+    }
+}
@@ -1,12 +1,15 @@
-use std::cmp;
-use std::convert::{AsMut, AsRef};
-use std::fmt;
-use std::hash::{Hash, Hasher};
-use std::ops::{Deref, DerefMut, Range};
+use core::cmp;
+use core::convert::{AsMut, AsRef};
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::{Deref, DerefMut, Range};
 
-use codespan::{ByteIndex, ByteOffset};
+#[cfg(not(feature = "std"))]
+use alloc::{boxed::Box, vec::Vec};
 
-use super::{SourceId, SourceIndex};
+#[cfg(feature = "std")]
+use super::Label;
+use super::{ByteIndex, ByteOffset, SourceId, SourceIndex};
 
 /// Represents a range of bytes in a specific source file
 ///
@@ -140,7 +143,55 @@ impl SourceSpan {
             SourceIndex::new(source_id, end),
         ))
     }
+
+    /// Splits this span in two at `offset` bytes from its start, i.e. into `[start, start + offset)`
+    /// and `[start + offset, end)`, both in the same file.
+    ///
+    /// This is the inverse of `merge` for adjacent spans, and is useful when a lexer needs to
+    /// divide a token, e.g. splitting `>>` into two `>`.
+    pub fn split_at(self, offset: usize) -> (SourceSpan, SourceSpan) {
+        debug_assert!(
+            offset <= (self.end.to_usize() - self.start.to_usize()),
+            "split offset is out of bounds"
+        );
+        let mid = self.start + ByteOffset(offset as i64);
+        let first = SourceSpan { end: mid, ..self };
+        let second = SourceSpan { start: mid, ..self };
+        (first, second)
+    }
+
+    /// Returns true if this span and `other` are directly next to each other, with no gap
+    /// between them, i.e. one ends exactly where the other starts.
+    ///
+    /// Spans in different source files, or which are unknown, are never adjacent. The
+    /// order of the spans is not important.
+    pub fn is_adjacent(&self, other: &SourceSpan) -> bool {
+        if self.is_unknown() || other.is_unknown() || self.source_id != other.source_id {
+            return false;
+        }
+        self.end == other.start || other.end == self.start
+    }
+
+    /// Converts this span into a primary [Label], with no label message
+    ///
+    /// This is a convenience for constructing a [crate::Diagnostic] directly, rather than via
+    /// an [crate::InFlightDiagnostic].
+    #[inline]
+    #[cfg(feature = "std")]
+    pub fn into_primary_label(self) -> Label {
+        Label::primary(self.source_id, self)
+    }
+
+    /// Converts this span into a secondary [Label], with no label message
+    ///
+    /// See `into_primary_label`.
+    #[inline]
+    #[cfg(feature = "std")]
+    pub fn into_secondary_label(self) -> Label {
+        Label::secondary(self.source_id, self)
+    }
 }
+#[cfg(feature = "std")]
 impl From<SourceSpan> for codespan::Span {
     #[inline]
     fn from(span: SourceSpan) -> Self {
@@ -169,6 +220,15 @@ impl From<SourceSpan> for Range<SourceIndex> {
 /// This trait is implemented by any type which has a canoncial [SourceSpan]
 pub trait Spanned {
     fn span(&self) -> SourceSpan;
+
+    /// Wraps `item` in a [Span] with the same span as `self`
+    #[inline]
+    fn spanned<T>(&self, item: T) -> Span<T>
+    where
+        Self: Sized,
+    {
+        Span::new(self.span(), item)
+    }
 }
 impl Spanned for SourceSpan {
     #[inline(always)]
@@ -176,6 +236,36 @@ impl Spanned for SourceSpan {
         *self
     }
 }
+/// An [ExpandedSpan] describes code produced by expansion, e.g. of a macro or an include, in
+/// terms of both the location where the expansion was requested, and the location of the
+/// code which was expanded there.
+///
+/// Use [crate::CodeMap::expansion_of] to derive one from the lineage of a file added with
+/// [crate::CodeMap::add_child], and [crate::InFlightDiagnostic::with_expanded_span] to
+/// render both sites in a diagnostic.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ExpandedSpan {
+    /// The location at which the expansion occurred, e.g. the macro invocation
+    pub call_site: SourceSpan,
+    /// The location of the expanded code, e.g. in the macro definition
+    pub def_site: SourceSpan,
+}
+impl ExpandedSpan {
+    /// Constructs a new [ExpandedSpan] from its call and definition sites
+    pub const fn new(call_site: SourceSpan, def_site: SourceSpan) -> Self {
+        Self {
+            call_site,
+            def_site,
+        }
+    }
+}
+impl Spanned for ExpandedSpan {
+    /// Returns the call site, as that is the location the user wrote
+    #[inline]
+    fn span(&self) -> SourceSpan {
+        self.call_site
+    }
+}
 impl<T: Spanned> Spanned for Box<T> {
     #[inline]
     fn span(&self) -> SourceSpan {
@@ -205,6 +295,24 @@ impl<T> Span<T> {
     pub const fn new(span: SourceSpan, item: T) -> Self {
         Self { span, item }
     }
+
+    /// Construct a new [Span] from the span of `spanned` and a generic item
+    #[inline]
+    pub fn from_spanned<S: Spanned>(spanned: &S, item: T) -> Self {
+        Self::new(spanned.span(), item)
+    }
+
+    /// Returns a view of this [Span] whose [fmt::Debug] implementation omits the span, and
+    /// formats only the inner item.
+    ///
+    /// This is useful for dumping syntax trees when only the structure is of interest.
+    #[inline]
+    pub fn debug_inner(&self) -> impl fmt::Debug + '_
+    where
+        T: fmt::Debug,
+    {
+        &self.item
+    }
 }
 impl<T: ?Sized> AsRef<T> for Span<T> {
     #[inline(always)]
@@ -258,12 +366,12 @@ where
     T: PartialOrd<U>,
     U: PartialOrd<T>,
 {
-    fn partial_cmp(&self, other: &Span<U>) -> Option<std::cmp::Ordering> {
+    fn partial_cmp(&self, other: &Span<U>) -> Option<core::cmp::Ordering> {
         self.item.partial_cmp(&other.item)
     }
 }
 impl<T: Ord> Ord for Span<T> {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
         self.item.cmp(&other.item)
     }
 }
@@ -282,3 +390,159 @@ impl<T: fmt::Display> fmt::Display for Span<T> {
         write!(f, "{}", &self.item)
     }
 }
+
+/// Maps each of `items` with `f`, collecting the results into a [Span] whose span covers the
+/// spans of all of `items`, e.g. to build a node from a sequence of child nodes.
+///
+/// Items with an unknown span, or whose span is in a different file than the first known span,
+/// do not contribute to the resulting span. If no item has a known span, the resulting span is
+/// [SourceSpan::UNKNOWN].
+pub fn map_spanned<T, U, F>(items: Vec<T>, mut f: F) -> Span<Vec<U>>
+where
+    T: Spanned,
+    F: FnMut(T) -> U,
+{
+    let mut span = SourceSpan::UNKNOWN;
+    let mut mapped = Vec::with_capacity(items.len());
+    for item in items {
+        let item_span = item.span();
+        if span.is_unknown() {
+            span = item_span;
+        } else if let Some(merged) = span.merge(item_span) {
+            span = merged;
+        }
+        mapped.push(f(item));
+    }
+    Span::new(span, mapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(source_id: SourceId, start: u32, end: u32) -> SourceSpan {
+        SourceSpan::new(
+            SourceIndex::new(source_id, ByteIndex(start)),
+            SourceIndex::new(source_id, ByteIndex(end)),
+        )
+    }
+
+    // This module must not depend on `std`, as it is run with `--no-default-features`
+    #[test]
+    fn merge() {
+        let a = SourceId::new(1);
+        let b = SourceId::new(2);
+
+        assert_eq!(span(a, 4, 8).merge(span(a, 0, 2)), Some(span(a, 0, 8)));
+        assert_eq!(span(a, 0, 2).merge(span(a, 4, 8)), Some(span(a, 0, 8)));
+        assert_eq!(span(a, 0, 8).merge(span(a, 2, 4)), Some(span(a, 0, 8)));
+        assert_eq!(span(a, 0, 2).merge(span(b, 4, 8)), None);
+        assert_eq!(span(a, 0, 2).merge(SourceSpan::UNKNOWN), None);
+    }
+
+    #[test]
+    fn map_spanned_covers_items() {
+        let a = SourceId::new(1);
+        let items = Vec::from([span(a, 6, 8), SourceSpan::UNKNOWN, span(a, 2, 4)]);
+        let mapped = map_spanned(items, |span| span.start_index().to_usize());
+        assert_eq!(mapped.span(), span(a, 2, 8));
+        assert_eq!(mapped.item, [6, 0, 2]);
+    }
+
+    #[test]
+    fn spanned_carries_span() {
+        let a = SourceId::new(1);
+        let node = Span::new(span(a, 2, 6), "node");
+        let derived = node.spanned(42);
+        assert_eq!(derived.span(), span(a, 2, 6));
+        assert_eq!(derived.item, 42);
+        assert_eq!(Span::from_spanned(&node, 'x').span(), span(a, 2, 6));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn into_codespan_span() {
+        let a = SourceId::new(1);
+        let span = codespan::Span::from(span(a, 2, 6));
+        assert_eq!(span.start(), codespan::ByteIndex(2));
+        assert_eq!(ByteIndex::from(span.end()), ByteIndex(6));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn into_labels() {
+        use crate::LabelStyle;
+
+        let a = SourceId::new(1);
+        let label = span(a, 2, 6).into_primary_label();
+        assert_eq!(label.style, LabelStyle::Primary);
+        assert_eq!(label.file_id, a);
+        assert_eq!(label.range, 2..6);
+        assert!(label.message.is_empty());
+
+        let label = span(a, 7, 9).into_secondary_label();
+        assert_eq!(label.style, LabelStyle::Secondary);
+        assert_eq!(label.file_id, a);
+        assert_eq!(label.range, 7..9);
+    }
+
+    #[test]
+    fn is_adjacent() {
+        let a = SourceId::new(1);
+        let b = SourceId::new(2);
+        assert!(span(a, 2, 5).is_adjacent(&span(a, 5, 7)));
+        assert!(span(a, 5, 7).is_adjacent(&span(a, 2, 5)));
+        assert!(!span(a, 2, 5).is_adjacent(&span(a, 6, 7)));
+        assert!(!span(a, 2, 5).is_adjacent(&span(b, 5, 7)));
+        assert!(!SourceSpan::UNKNOWN.is_adjacent(&SourceSpan::UNKNOWN));
+    }
+
+    #[test]
+    fn split_at() {
+        let a = SourceId::new(1);
+        assert_eq!(
+            span(a, 10, 14).split_at(2),
+            (span(a, 10, 12), span(a, 12, 14))
+        );
+        assert_eq!(
+            span(a, 10, 14).split_at(0),
+            (span(a, 10, 10), span(a, 10, 14))
+        );
+        assert_eq!(
+            span(a, 10, 14).split_at(4),
+            (span(a, 10, 14), span(a, 14, 14))
+        );
+        let (first, second) = span(a, 10, 14).split_at(1);
+        assert_eq!(first.merge(second), Some(span(a, 10, 14)));
+    }
+
+    #[test]
+    fn debug_inner_omits_span() {
+        extern crate alloc;
+        use alloc::format;
+
+        let item = Span::new(span(SourceId::new(1), 0, 4), Some("push"));
+        assert_eq!(
+            format!("{:?}", item.debug_inner()),
+            format!("{:?}", Some("push"))
+        );
+        assert_eq!(
+            format!("{:#?}", item.debug_inner()),
+            format!("{:#?}", Some("push"))
+        );
+        assert_ne!(format!("{:?}", item), format!("{:?}", Some("push")));
+    }
+
+    #[test]
+    fn map_spanned_tokens() {
+        let a = SourceId::new(1);
+        let tokens = Vec::from([
+            Span::new(span(a, 0, 4), "push"),
+            Span::new(span(a, 4, 5), "."),
+            Span::new(span(a, 5, 7), "42"),
+        ]);
+        let mapped = map_spanned(tokens, |token| token.item.len());
+        assert_eq!(mapped.span(), span(a, 0, 7));
+        assert_eq!(mapped.item, [4, 1, 2]);
+    }
+}
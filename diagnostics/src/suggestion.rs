@@ -0,0 +1,301 @@
+use std::io;
+
+use unicode_width::UnicodeWidthStr;
+
+use crate::term::termcolor::{Color, ColorSpec, WriteColor};
+use crate::*;
+
+/// A [Suggestion] is a proposed fix attached to a diagnostic, which replaces the source
+/// content covered by `span` with `replacement`.
+///
+/// Suggestions are rendered beneath the diagnostic as a diff of the affected lines, headed by
+/// their title. The title is also suitable for presenting a suggestion as a quick fix, e.g. a
+/// code action in a language server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suggestion {
+    /// The span of the content to be replaced
+    pub span: SourceSpan,
+    /// The content to replace `span` with, which may be empty, or span multiple lines
+    pub replacement: String,
+    /// A short, human-readable description of the fix, e.g. "add missing semicolon"
+    pub title: String,
+}
+impl Suggestion {
+    /// Constructs a new [Suggestion] to replace `span` with `replacement`
+    pub fn new(span: SourceSpan, replacement: impl Into<String>, title: impl ToString) -> Self {
+        Self {
+            span,
+            replacement: replacement.into(),
+            title: title.to_string(),
+        }
+    }
+}
+
+/// Renders `suggestion` as a diff of the lines it affects, with removed lines prefixed by `-`,
+/// and added lines prefixed by `+`. Unchanged lines are shown for context.
+///
+/// `line_number` maps a line index in the file to the line number to display for it.
+pub(crate) fn render_diff<W, F>(
+    out: &mut W,
+    codemap: &CodeMap,
+    display: &crate::term::Config,
+    labels: &SeverityLabels,
+    suggestion: &Suggestion,
+    line_number: F,
+) -> io::Result<()>
+where
+    W: WriteColor,
+    F: Fn(usize) -> usize,
+{
+    let file = match codemap.get(suggestion.span.source_id()) {
+        Ok(file) => file,
+        Err(_) => return Ok(()),
+    };
+    let source = file.source();
+    let start = suggestion.span.start_index().to_usize().min(source.len());
+    let end = suggestion
+        .span
+        .end_index()
+        .to_usize()
+        .clamp(start, source.len());
+    let start_line = file.line_index(ByteIndex(start as u32));
+    let end_line = file.line_index(ByteIndex(end as u32));
+    let lo = file.line_start(start_line).unwrap().to_usize();
+    let hi = file
+        .line_span(end_line)
+        .map(|span| span.end().to_usize())
+        .unwrap_or(source.len());
+
+    let before = &source[lo..hi];
+    let after = format!(
+        "{}{}{}",
+        &source[lo..start],
+        suggestion.replacement,
+        &source[end..hi]
+    );
+    let before = before.lines().collect::<Vec<_>>();
+    let after = after.lines().collect::<Vec<_>>();
+    let prefix = before
+        .iter()
+        .zip(after.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = before[prefix..]
+        .iter()
+        .rev()
+        .zip(after[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let first = start_line.to_usize();
+    let last = first + before.len().max(after.len());
+    let width = line_number(last).to_string().len();
+    let border = display.chars.source_border_left;
+    let styles = &display.styles;
+    let mut removed = ColorSpec::new();
+    removed.set_fg(Some(Color::Red));
+    let mut added = ColorSpec::new();
+    added.set_fg(Some(Color::Green));
+
+    out.set_color(styles.header(Severity::Help))?;
+    write!(out, "{}", labels.help)?;
+    out.set_color(&styles.header_message)?;
+    writeln!(out, ": {}", suggestion.title)?;
+    out.reset()?;
+
+    let write_line = |out: &mut W, line: usize, marker: char, color, text: &str| {
+        out.set_color(&styles.line_number)?;
+        write!(
+            out,
+            "{:>width$} {} ",
+            line_number(line),
+            border,
+            width = width
+        )?;
+        match color {
+            Some(color) => out.set_color(color)?,
+            None => out.reset()?,
+        }
+        writeln!(out, "{} {}", marker, text)?;
+        out.reset()
+    };
+    for (i, line) in before[..prefix].iter().enumerate() {
+        write_line(out, first + i, ' ', None, line)?;
+    }
+    for (i, line) in before[prefix..before.len() - suffix].iter().enumerate() {
+        write_line(out, first + prefix + i, '-', Some(&removed), line)?;
+    }
+    for (i, line) in after[prefix..after.len() - suffix].iter().enumerate() {
+        write_line(out, first + prefix + i, '+', Some(&added), line)?;
+    }
+    let suffix_start = before.len() - suffix;
+    for (i, line) in before[suffix_start..].iter().enumerate() {
+        write_line(out, first + suffix_start + i, ' ', None, line)?;
+    }
+    writeln!(out)
+}
+
+/// Renders `suggestion` inline, as the line it affects with the replacement spliced in, and
+/// the replacement underlined beneath it, e.g.:
+///
+/// ```text
+/// help: add missing semicolon
+/// 1 │ let x = 1;
+///   │          +
+/// ```
+///
+/// Insertions are underlined with `+`, and replacements with `~`. This is only possible when
+/// both the replaced content and the replacement are on a single line, so `false` is returned
+/// without rendering anything otherwise, in which case `render_diff` should be used instead.
+pub(crate) fn render_inline<W, F>(
+    out: &mut W,
+    codemap: &CodeMap,
+    display: &crate::term::Config,
+    labels: &SeverityLabels,
+    suggestion: &Suggestion,
+    line_number: F,
+) -> io::Result<bool>
+where
+    W: WriteColor,
+    F: Fn(usize) -> usize,
+{
+    let file = match codemap.get(suggestion.span.source_id()) {
+        Ok(file) => file,
+        Err(_) => return Ok(false),
+    };
+    let (start, end) = (suggestion.span.start_index(), suggestion.span.end_index());
+    let line_index = file.line_index(start);
+    if line_index != file.line_index(end) || suggestion.replacement.contains('\n') {
+        return Ok(false);
+    }
+    let line = match file.line_span(line_index) {
+        Ok(span) => file.source_slice(span).unwrap(),
+        Err(_) => return Ok(false),
+    };
+    let line = line.trim_end_matches(&['\n', '\r'][..]);
+    let line_start = file.line_start(line_index).unwrap().to_usize();
+    // The span may start past the content of its line, e.g. an insertion at the end of a
+    // file which ends with a line terminator
+    let before = &line[..(start.to_usize() - line_start).min(line.len())];
+    let after = &line[(end.to_usize() - line_start).min(line.len())..];
+
+    let number = line_number(line_index.to_usize()).to_string();
+    let border = display.chars.source_border_left;
+    let styles = &display.styles;
+    let mut added = ColorSpec::new();
+    added.set_fg(Some(Color::Green));
+
+    out.set_color(styles.header(Severity::Help))?;
+    write!(out, "{}", labels.help)?;
+    out.set_color(&styles.header_message)?;
+    writeln!(out, ": {}", suggestion.title)?;
+    out.reset()?;
+
+    out.set_color(&styles.line_number)?;
+    write!(out, "{} {} ", number, border)?;
+    out.reset()?;
+    write!(out, "{}", before)?;
+    out.set_color(&added)?;
+    write!(out, "{}", suggestion.replacement)?;
+    out.reset()?;
+    writeln!(out, "{}", after)?;
+
+    let marker = if start == end { "+" } else { "~" };
+    out.set_color(&styles.line_number)?;
+    write!(out, "{:width$} {} ", "", border, width = number.len())?;
+    out.set_color(&added)?;
+    write!(out, "{:width$}", "", width = before.width())?;
+    writeln!(
+        out,
+        "{}",
+        marker.repeat(suggestion.replacement.width().max(1))
+    )?;
+    out.reset()?;
+    writeln!(out)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::term::termcolor::Buffer;
+
+    use super::*;
+
+    #[test]
+    fn multiline_replacement_renders_as_diff() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "begin\n  push.1\n  add\nend\n".to_string());
+        let span = SourceSpan::new(
+            SourceIndex::new(id, ByteIndex(8)),
+            SourceIndex::new(id, ByteIndex(20)),
+        );
+        let suggestion = Suggestion::new(span, "push.2\n  mul", "use mul");
+        let mut buffer = Buffer::no_color();
+        let config = crate::term::Config::default();
+        render_diff(
+            &mut buffer,
+            &codemap,
+            &config,
+            &SeverityLabels::default(),
+            &suggestion,
+            |line| line + 1,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(buffer.into_inner()).unwrap(),
+            "help: use mul\n2 │ -   push.1\n3 │ -   add\n2 │ +   push.2\n3 │ +   mul\n\n"
+        );
+    }
+
+    fn render(codemap: &CodeMap, suggestion: &Suggestion) -> (bool, String) {
+        let mut buffer = Buffer::no_color();
+        let config = crate::term::Config::default();
+        let labels = SeverityLabels::default();
+        let inline = render_inline(&mut buffer, codemap, &config, &labels, suggestion, |line| {
+            line + 1
+        })
+        .unwrap();
+        (inline, String::from_utf8(buffer.into_inner()).unwrap())
+    }
+
+    #[test]
+    fn inline_replacement_of_token() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "let x = foo;\n".to_string());
+        let span = SourceSpan::new(
+            SourceIndex::new(id, ByteIndex(8)),
+            SourceIndex::new(id, ByteIndex(11)),
+        );
+        let suggestion = Suggestion::new(span, "bar", "use `bar`");
+        let (inline, rendered) = render(&codemap, &suggestion);
+        assert!(inline);
+        assert_eq!(
+            rendered,
+            "help: use `bar`\n1 │ let x = bar;\n  │         ~~~\n\n"
+        );
+    }
+
+    #[test]
+    fn inline_insertion_at_eof() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "abc\n".to_string());
+        let suggestion = Suggestion::new(codemap.eof_span(id).unwrap(), ";", "add `;`");
+        let (inline, rendered) = render(&codemap, &suggestion);
+        assert!(inline);
+        assert_eq!(rendered, "help: add `;`\n1 │ abc;\n  │    +\n\n");
+    }
+
+    #[test]
+    fn multiline_replacement_is_not_inline() {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "abc\n".to_string());
+        let span = SourceSpan::new(
+            SourceIndex::new(id, ByteIndex(0)),
+            SourceIndex::new(id, ByteIndex(3)),
+        );
+        let suggestion = Suggestion::new(span, "a\nb", "split");
+        let (inline, rendered) = render(&codemap, &suggestion);
+        assert!(!inline);
+        assert!(rendered.is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::*;
+
+/// [CapturedDiagnostics] records the structured diagnostics emitted by a [DiagnosticsHandler]
+/// constructed with [DiagnosticsHandler::in_memory], so that tests can write assertions about
+/// them without scraping rendered output.
+///
+/// Diagnostics are recorded after filtering and severity promotion have been applied, i.e.
+/// exactly as they would have been rendered.
+#[derive(Default)]
+pub struct CapturedDiagnostics {
+    diagnostics: Mutex<Vec<Diagnostic>>,
+}
+impl CapturedDiagnostics {
+    pub(crate) fn record(&self, diagnostic: &Diagnostic) {
+        self.diagnostics.lock().push(diagnostic.clone());
+    }
+
+    /// Returns all of the diagnostics captured so far, in the order they were emitted
+    pub fn all(&self) -> Vec<Diagnostic> {
+        self.diagnostics.lock().clone()
+    }
+
+    /// Returns all of the error diagnostics captured so far
+    pub fn errors(&self) -> Vec<Diagnostic> {
+        self.filter(|d| d.severity >= Severity::Error)
+    }
+
+    /// Returns all of the warning diagnostics captured so far
+    pub fn warnings(&self) -> Vec<Diagnostic> {
+        self.filter(|d| d.severity == Severity::Warning)
+    }
+
+    /// Returns all of the captured diagnostics for which `predicate` returns true
+    pub fn filter<F>(&self, predicate: F) -> Vec<Diagnostic>
+    where
+        F: Fn(&Diagnostic) -> bool,
+    {
+        self.diagnostics
+            .lock()
+            .iter()
+            .filter(|d| predicate(d))
+            .cloned()
+            .collect()
+    }
+
+    /// Panics unless at least one captured diagnostic satisfies `predicate`
+    #[track_caller]
+    pub fn assert_emitted<F>(&self, predicate: F)
+    where
+        F: Fn(&Diagnostic) -> bool,
+    {
+        let diagnostics = self.diagnostics.lock();
+        if !diagnostics.iter().any(predicate) {
+            panic!(
+                "expected a matching diagnostic to have been emitted, but got: {:#?}",
+                diagnostics.as_slice()
+            );
+        }
+    }
+
+    /// Panics if any captured diagnostic satisfies `predicate`
+    #[track_caller]
+    pub fn assert_not_emitted<F>(&self, predicate: F)
+    where
+        F: Fn(&Diagnostic) -> bool,
+    {
+        let diagnostics = self.diagnostics.lock();
+        if let Some(found) = diagnostics.iter().find(|d| predicate(d)) {
+            panic!(
+                "expected no matching diagnostic to have been emitted, but got: {:#?}",
+                found
+            );
+        }
+    }
+}
+
+impl DiagnosticsHandler {
+    /// Constructs a [DiagnosticsHandler] for use in tests, which discards rendered output, and
+    /// instead captures emitted diagnostics in the returned [CapturedDiagnostics].
+    pub fn in_memory(codemap: Arc<CodeMap>) -> (Self, Arc<CapturedDiagnostics>) {
+        let captured = Arc::new(CapturedDiagnostics::default());
+        let emitter = Arc::new(NullEmitter::new(term::termcolor::ColorChoice::Never));
+        let mut handler = Self::new(DiagnosticsConfig::default(), codemap, emitter);
+        handler.captured = Some(captured.clone());
+        (handler, captured)
+    }
+}
+
+/// Asserts that the source text covered by the span of `node` is equal to `expected`
+///
+/// `node` may be any [Spanned] item, and its span is resolved using `codemap`. On failure,
+/// both the expected and actual text are shown, along with the span.
+///
+/// ```ignore
+/// assert_span_text!(codemap, expr, "a + b");
+/// ```
+#[macro_export]
+macro_rules! assert_span_text {
+    ($codemap:expr, $node:expr, $expected:expr $(,)?) => {{
+        let span = $crate::Spanned::span(&$node);
+        let expected = $expected;
+        let expected: &str = ::core::convert::AsRef::as_ref(&expected);
+        match $codemap.source_slice(&span) {
+            Ok(actual) => {
+                if actual != expected {
+                    panic!(
+                        "span {:?} does not cover the expected text\n  expected: {:?}\n    actual: {:?}",
+                        span, expected, actual
+                    );
+                }
+            }
+            Err(err) => panic!(
+                "span {:?} could not be resolved to source text: {}\n  expected: {:?}",
+                span, err, expected
+            ),
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assert_emitted_by_predicate() {
+        let (handler, captured) = DiagnosticsHandler::in_memory(Arc::new(CodeMap::new()));
+        handler.error("undefined variable `x`");
+        handler.warn("unused import");
+        handler.info("not a diagnostic");
+
+        assert_eq!(captured.all().len(), 2);
+        assert_eq!(captured.errors().len(), 1);
+        assert_eq!(captured.warnings()[0].message, "unused import");
+        captured
+            .assert_emitted(|d| d.severity == Severity::Error && d.message.contains("undefined"));
+        captured.assert_not_emitted(|d| d.message.contains("not a diagnostic"));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected a matching diagnostic to have been emitted")]
+    fn assert_emitted_fails_without_match() {
+        let (handler, captured) = DiagnosticsHandler::in_memory(Arc::new(CodeMap::new()));
+        handler.warn("unused import");
+        captured.assert_emitted(|d| d.severity == Severity::Error);
+    }
+
+    fn expr() -> (CodeMap, Span<&'static str>) {
+        let codemap = CodeMap::new();
+        let id = codemap.add("test", "let x = a + b;".to_string());
+        let span = SourceSpan::new(
+            SourceIndex::new(id, ByteIndex(8)),
+            SourceIndex::new(id, ByteIndex(13)),
+        );
+        (codemap, Span::new(span, "expr"))
+    }
+
+    #[test]
+    fn assert_span_text_passes() {
+        let (codemap, expr) = expr();
+        assert_span_text!(codemap, expr, "a + b");
+        assert_span_text!(codemap, expr, String::from("a + b"));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "span 8..13@1 does not cover the expected text\n  expected: \"a - b\"\n    actual: \"a + b\""
+    )]
+    fn assert_span_text_fails_on_mismatch() {
+        let (codemap, expr) = expr();
+        assert_span_text!(codemap, expr, "a - b");
+    }
+
+    #[test]
+    #[should_panic(expected = "could not be resolved to source text")]
+    fn assert_span_text_fails_on_unknown_span() {
+        let (codemap, _) = expr();
+        assert_span_text!(codemap, SourceSpan::UNKNOWN, "a + b");
+    }
+}
@@ -0,0 +1,69 @@
+use crate::*;
+
+/// A [DiagnosticTree] is a diagnostic with a tree of child diagnostics beneath it, for
+/// explaining complex errors in detail, e.g. a type error and the chain of constraints from
+/// which it arose:
+///
+/// ```ignore
+/// let tree = DiagnosticTree::new(Severity::Error, "mismatched types")
+///     .with_primary_label(span, "expected `u32`, found `felt`")
+///     .with_child(
+///         DiagnosticTree::new(Severity::Note, "`u32` is required by this call")
+///             .with_secondary_label(call_span, "")
+///             .with_child(DiagnosticTree::new(Severity::Note, "declared here")),
+///     );
+/// diagnostics.emit_tree(tree);
+/// ```
+///
+/// Each node has its own severity, message, and labels. When rendered to a terminal, the
+/// children of each node are indented beneath it. Structured emitters receive the full tree,
+/// see [DiagnosticRecord::children].
+#[derive(Debug, Clone)]
+pub struct DiagnosticTree {
+    /// The diagnostic at this node
+    pub diagnostic: Diagnostic,
+    /// The children of this node, in the order they were added
+    pub children: Vec<DiagnosticTree>,
+}
+impl DiagnosticTree {
+    /// Constructs a new [DiagnosticTree] without labels or children
+    pub fn new(severity: Severity, message: impl ToString) -> Self {
+        Self::from(Diagnostic::new(severity).with_message(message.to_string()))
+    }
+
+    /// Adds a primary label for `span` to the diagnostic at this node
+    pub fn with_primary_label(mut self, span: SourceSpan, message: impl ToString) -> Self {
+        self.diagnostic
+            .labels
+            .push(Label::primary(span.source_id(), span).with_message(message.to_string()));
+        self
+    }
+
+    /// Adds a secondary label for `span` to the diagnostic at this node
+    pub fn with_secondary_label(mut self, span: SourceSpan, message: impl ToString) -> Self {
+        self.diagnostic
+            .labels
+            .push(Label::secondary(span.source_id(), span).with_message(message.to_string()));
+        self
+    }
+
+    /// Adds a note to the diagnostic at this node
+    pub fn with_note(mut self, note: impl ToString) -> Self {
+        self.diagnostic.notes.push(note.to_string());
+        self
+    }
+
+    /// Appends `child` to the children of this node
+    pub fn with_child(mut self, child: DiagnosticTree) -> Self {
+        self.children.push(child);
+        self
+    }
+}
+impl From<Diagnostic> for DiagnosticTree {
+    fn from(diagnostic: Diagnostic) -> Self {
+        Self {
+            diagnostic,
+            children: vec![],
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+use crate::*;
+
+/// The source of a file in which long lines have been truncated around the labels on them,
+/// for rendering in place of the original, see `max_label_width` of [DiagnosticsConfig].
+///
+/// Each truncated line retains a window of its content, with an ellipsis in place of the
+/// content omitted on either side. Line terminators are never removed, so line indices are
+/// the same as in the original source, but byte indices on and after a truncated line are
+/// not, see `map_index` and `original_index`.
+pub(crate) struct TruncatedSource {
+    pub file_id: SourceId,
+    pub source: String,
+    line_starts: Vec<usize>,
+    edits: Vec<LineEdit>,
+}
+
+/// A line of the original source which has been truncated
+struct LineEdit {
+    /// The range of the content of the line, excluding its terminator, in the original source
+    original: Range<usize>,
+    /// The range of the content retained from the line, in the original source
+    window: Range<usize>,
+    /// The range of the content of the line, including ellipses, in the truncated source
+    truncated: Range<usize>,
+    /// The index at which the retained content starts, in the truncated source
+    start: usize,
+}
+
+impl TruncatedSource {
+    const ELLIPSIS: char = '…';
+
+    /// Truncates the lines of `file` on which labels of `diagnostic` are displayed, which are
+    /// longer than `max_width` characters, and remaps the labels in `file` to match.
+    ///
+    /// The window retained from each line is centered on the first label on it, primary
+    /// labels first, or starts at that label if it is too long to fit. Returns `None` if no
+    /// line needs to be truncated.
+    pub fn new(file: &SourceFile, diagnostic: &mut Diagnostic, max_width: usize) -> Option<Self> {
+        let file_id = file.id();
+        let len = file.source().len();
+        let mut labels = diagnostic
+            .labels
+            .iter()
+            .filter(|label| label.file_id == file_id)
+            .map(|label| {
+                let start = label.range.start.min(len);
+                (label.style, start..label.range.end.clamp(start, len))
+            })
+            .collect::<Vec<_>>();
+        labels.sort_by_key(|(style, _)| *style != LabelStyle::Primary);
+
+        // Labels are displayed on the lines on which they start and end, and lines between
+        // labels which are close together are displayed too
+        let mut lines = BTreeSet::new();
+        for (_, range) in labels.iter() {
+            let start = file.line_index(ByteIndex(range.start as u32)).to_usize();
+            let end = file.line_index(ByteIndex(range.end as u32)).to_usize();
+            lines.extend(start.saturating_sub(1)..=(end + 1));
+        }
+
+        let mut edits = vec![];
+        for (line_index, span, line) in file.lines() {
+            if !lines.contains(&line_index.to_usize()) {
+                continue;
+            }
+            let offsets = line
+                .char_indices()
+                .map(|(offset, _)| offset)
+                .collect::<Vec<_>>();
+            if offsets.len() <= max_width {
+                continue;
+            }
+            let line_start = span.start_index().to_usize();
+            let line_end = span.end_index().to_usize();
+            let char_at = |index: usize| {
+                offsets.partition_point(|offset| line_start + offset < index.min(line_end))
+            };
+            let focus = labels
+                .iter()
+                .find(|(_, range)| range.start <= line_end && line_start <= range.end)
+                .map_or(0..0, |(_, range)| char_at(range.start)..char_at(range.end));
+            let first = if focus.len() < max_width {
+                focus.start.saturating_sub((max_width - focus.len()) / 2)
+            } else {
+                focus.start
+            };
+            let first = first.min(offsets.len() - max_width);
+            let window = (line_start + offsets[first])
+                ..offsets
+                    .get(first + max_width)
+                    .map_or(line_end, |offset| line_start + offset);
+            edits.push(LineEdit {
+                original: line_start..line_end,
+                window,
+                truncated: 0..0,
+                start: 0,
+            });
+        }
+        if edits.is_empty() {
+            return None;
+        }
+
+        let original = file.source();
+        let mut source = String::with_capacity(len);
+        let mut copied = 0;
+        for edit in edits.iter_mut() {
+            source.push_str(&original[copied..edit.original.start]);
+            let line_start = source.len();
+            if edit.window.start > edit.original.start {
+                source.push(Self::ELLIPSIS);
+            }
+            edit.start = source.len();
+            source.push_str(&original[edit.window.clone()]);
+            if edit.window.end < edit.original.end {
+                source.push(Self::ELLIPSIS);
+            }
+            edit.truncated = line_start..source.len();
+            copied = edit.original.end;
+        }
+        source.push_str(&original[copied..]);
+
+        let mut line_starts = codespan_reporting::files::line_starts(&source).collect::<Vec<_>>();
+        // A line terminator at the end of the file does not start a new line, see `SourceFile`
+        if line_starts.len() > 1 && line_starts.last() == Some(&source.len()) {
+            line_starts.pop();
+        }
+
+        let truncated = Self {
+            file_id,
+            source,
+            line_starts,
+            edits,
+        };
+        for label in diagnostic
+            .labels
+            .iter_mut()
+            .filter(|label| label.file_id == file_id)
+        {
+            label.range =
+                truncated.map_index(label.range.start)..truncated.map_index(label.range.end);
+        }
+        Some(truncated)
+    }
+
+    /// Maps `index` in the original source to the truncated source
+    ///
+    /// Indices in the omitted content of a truncated line are clamped to the retained window.
+    pub fn map_index(&self, index: usize) -> usize {
+        let mut shift = 0isize;
+        for edit in self.edits.iter() {
+            if index < edit.original.start {
+                break;
+            }
+            if index <= edit.original.end {
+                let index = index.clamp(edit.window.start, edit.window.end);
+                return edit.start + (index - edit.window.start);
+            }
+            shift = edit.truncated.end as isize - edit.original.end as isize;
+        }
+        (index as isize + shift) as usize
+    }
+
+    /// Maps `index` in the truncated source back to the original source
+    pub fn original_index(&self, index: usize) -> usize {
+        let mut shift = 0isize;
+        for edit in self.edits.iter() {
+            if index < edit.truncated.start {
+                break;
+            }
+            if index <= edit.truncated.end {
+                let index = index.clamp(edit.start, edit.start + edit.window.len());
+                return edit.window.start + (index - edit.start);
+            }
+            shift = edit.original.end as isize - edit.truncated.end as isize;
+        }
+        (index as isize + shift) as usize
+    }
+
+    /// Returns the index of the line containing `index` in the truncated source
+    pub fn line_index(&self, index: usize) -> usize {
+        match self.line_starts.binary_search(&index) {
+            Ok(line) => line,
+            Err(next_line) => next_line - 1,
+        }
+    }
+
+    /// Returns the range of the line at `line_index` in the truncated source, including its
+    /// line terminator, if any
+    pub fn line_range(&self, line_index: usize) -> Result<Range<usize>, Error> {
+        let start = *self
+            .line_starts
+            .get(line_index)
+            .ok_or(Error::LineTooLarge {
+                given: line_index,
+                max: self.line_starts.len() - 1,
+            })?;
+        let end = self
+            .line_starts
+            .get(line_index + 1)
+            .copied()
+            .unwrap_or(self.source.len());
+        Ok(start..end)
+    }
+}
+
+/// Truncates the long lines on which the labels of `diagnostic` are displayed, in each file
+/// to which they refer, see [TruncatedSource::new]
+pub(crate) fn truncate_lines(
+    codemap: &CodeMap,
+    diagnostic: &mut Diagnostic,
+    max_width: usize,
+) -> Vec<TruncatedSource> {
+    let mut file_ids = diagnostic
+        .labels
+        .iter()
+        .map(|label| label.file_id)
+        .collect::<Vec<_>>();
+    file_ids.sort();
+    file_ids.dedup();
+    file_ids
+        .into_iter()
+        .filter_map(|file_id| {
+            let file = codemap.get(file_id).ok()?;
+            TruncatedSource::new(&file, diagnostic, max_width)
+        })
+        .collect()
+}
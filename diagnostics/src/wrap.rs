@@ -0,0 +1,34 @@
+use unicode_width::UnicodeWidthStr;
+
+/// Wraps `text` at whitespace so that its first line is at most `first_width` columns wide,
+/// and subsequent lines at most `width` columns wide.
+///
+/// Existing line breaks, and the indentation of each line, are preserved. Words wider than
+/// the available width are placed on a line of their own, rather than being broken up.
+pub(crate) fn wrap_text(text: &str, first_width: usize, width: usize) -> String {
+    let mut wrapped = String::with_capacity(text.len());
+    let mut available = first_width;
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            wrapped.push('\n');
+            available = width;
+        }
+        let indent = &line[..line.len() - line.trim_start().len()];
+        wrapped.push_str(indent);
+        let mut column = indent.width();
+        for (i, word) in line.split_whitespace().enumerate() {
+            let word_width = word.width();
+            if i > 0 && column + 1 + word_width > available {
+                wrapped.push('\n');
+                available = width;
+                column = 0;
+            } else if i > 0 {
+                wrapped.push(' ');
+                column += 1;
+            }
+            wrapped.push_str(word);
+            column += word_width;
+        }
+    }
+    wrapped
+}